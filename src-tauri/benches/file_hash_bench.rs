@@ -1,12 +1,41 @@
-use std::path::PathBuf;
+use std::io::Write;
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use mylib::commands::local_files::update_local_state;
+use mylib::db::hashing::{hash_file, HashMode, HashSettings};
 
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("fib 20", |b| b.iter(|| update_local_state(black_box(PathBuf::from(r#"C:\Users\jdc10\Documents\GrabCAD"#)))));
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Bigger than the hash buffer, so it gets streamed through it a few times
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    for _ in 0..64 {
+        file.write_all(&[7; 1024 * 1024]).unwrap();
+    }
+
+    c.bench_function("hash 64MiB buffered", |b| {
+        b.to_async(&runtime).iter(|| {
+            hash_file(
+                black_box(file.path().to_path_buf()),
+                HashSettings {
+                    mode: HashMode::Buffered,
+                    strong: None,
+                },
+            )
+        })
+    });
+    c.bench_function("hash 64MiB mmap", |b| {
+        b.to_async(&runtime).iter(|| {
+            hash_file(
+                black_box(file.path().to_path_buf()),
+                HashSettings {
+                    mode: HashMode::Mmap,
+                    strong: None,
+                },
+            )
+        })
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
-criterion_main!(benches);
\ No newline at end of file
+criterion_main!(benches);
@@ -0,0 +1,43 @@
+//! Chunk manifests, for sending only the parts of a file the remote doesn't have yet.
+
+use std::collections::HashSet;
+
+use tauri::State;
+
+use crate::{
+    db::{
+        chunking::{manifest_for, missing_chunks},
+        content_hash::ContentHash,
+        project_path::ProjectPath,
+        projects::get_project,
+        store::Store,
+        types::{Chunk, ChunkManifest, ProjectId},
+    },
+    error::Result,
+};
+
+/// How a local file splits into chunks, chunking it first if needed
+#[tauri::command]
+pub async fn get_chunk_manifest(
+    project_id: ProjectId,
+    path: ProjectPath,
+    db: State<'_, Store>,
+) -> Result<ChunkManifest> {
+    let project = get_project(db.as_ref(), project_id)?;
+    manifest_for(&project, &path, db.as_ref()).await
+}
+
+/// The chunks of a local file that need sending, given the chunk hashes the remote already has
+#[tauri::command]
+pub async fn get_missing_chunks(
+    project_id: ProjectId,
+    path: ProjectPath,
+    remote_chunks: Vec<ContentHash>,
+    db: State<'_, Store>,
+) -> Result<Vec<Chunk>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    let manifest = manifest_for(&project, &path, db.as_ref()).await?;
+    let remote_chunks: HashSet<ContentHash> = remote_chunks.into_iter().collect();
+
+    Ok(missing_chunks(&manifest, &remote_chunks))
+}
@@ -0,0 +1,105 @@
+//! Local commits, the history of HEAD, and getting old versions of files back.
+
+use tauri::State;
+
+use crate::{
+    db::{
+        commits, history,
+        objects::{self, ObjectCompression, ObjectStore},
+        project_path::ProjectPath,
+        projects::get_project,
+        restore::{self, Trash},
+        store::Store,
+        types::{Commit, CommitId, FileVersion, ProjectId, TrashEntry},
+    },
+    error::Result,
+};
+
+/// Commit everything the local files changed since HEAD, and move HEAD to the new commit
+#[tauri::command]
+pub async fn commit_project(
+    project_id: ProjectId,
+    author: String,
+    message: String,
+    objects: State<'_, ObjectStore>,
+    db: State<'_, Store>,
+) -> Result<Commit> {
+    let project = get_project(db.as_ref(), project_id)?;
+    commits::commit_project(&project, author, message, &objects, db.as_ref()).await
+}
+
+/// The project's commits, newest first
+#[tauri::command]
+pub async fn log(project_id: ProjectId, db: State<'_, Store>) -> Result<Vec<Commit>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    commits::log(&project, db.as_ref())
+}
+
+/// How newly stored objects get compressed. Objects already stored stay as they are.
+#[tauri::command]
+pub async fn set_object_compression(
+    compression: ObjectCompression,
+    db: State<'_, Store>,
+) -> Result<()> {
+    objects::set_object_compression(db.as_ref(), compression)
+}
+
+/// Every version the file at `path` has had, in commits or on the remote, newest first
+#[tauri::command]
+pub async fn file_history(
+    project_id: ProjectId,
+    path: ProjectPath,
+    objects: State<'_, ObjectStore>,
+    db: State<'_, Store>,
+) -> Result<Vec<FileVersion>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    history::file_history(&project, &path, &objects, db.as_ref())
+}
+
+/// Put `path`, a file or a folder, back how it was at commit `version`. What's there now
+/// goes to the trash. Returns every file that changed.
+#[tauri::command]
+pub async fn restore_path(
+    project_id: ProjectId,
+    path: ProjectPath,
+    version: CommitId,
+    objects: State<'_, ObjectStore>,
+    trash: State<'_, Trash>,
+    db: State<'_, Store>,
+) -> Result<Vec<ProjectPath>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    restore::restore_path(&project, &path, version, &objects, &trash, db.as_ref()).await
+}
+
+/// Throw away the local changes to `paths` since HEAD. They go to the trash.
+#[tauri::command]
+pub async fn revert_to_head(
+    project_id: ProjectId,
+    paths: Vec<ProjectPath>,
+    objects: State<'_, ObjectStore>,
+    trash: State<'_, Trash>,
+    db: State<'_, Store>,
+) -> Result<Vec<ProjectPath>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    restore::revert_to_head(&project, &paths, &objects, &trash, db.as_ref()).await
+}
+
+/// Files restores put aside, newest first
+#[tauri::command]
+pub async fn list_trash(project_id: ProjectId, db: State<'_, Store>) -> Result<Vec<TrashEntry>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    restore::list_trash(&project, db.as_ref())
+}
+
+/// Put a trashed file back where it came from
+#[tauri::command]
+pub async fn recover_trashed(
+    project_id: ProjectId,
+    trash_id: u64,
+    objects: State<'_, ObjectStore>,
+    trash: State<'_, Trash>,
+    db: State<'_, Store>,
+) -> Result<Vec<ProjectPath>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    restore::recover_trashed(&project, trash_id, &objects, &trash, db.as_ref()).await
+}
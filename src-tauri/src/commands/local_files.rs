@@ -23,207 +23,229 @@
 //! │   Remote State    │─────┘                     
 //! └───────────────────┘                           
 
-use std::path::PathBuf;
-
-use chrono::{Utc};
-use futures::prelude::*;
-use serde_cbor::{from_slice, to_vec};
-use tauri::State;
-use tokio::fs::read;
-use xxhash_rust::xxh3::xxh3_64;
+use tauri::{State, Window};
 
 use crate::{
-    db::{compare::compare_trees, types::{TreeNames, LocalFileData, FileDiff, LocalFileMetadata}, refresh_state::get_metadatas},
+    db::{
+        compare::{compare_project, compare_three_trees, stream_trees, TreeDiffBase},
+        content_hash::HashAlgorithm,
+        hashing::{self, HashMode},
+        ignore_rules::IgnoreRules,
+        project_path::ProjectPath,
+        projects::{get_project, last_scan_report},
+        remote_state::set_remote_state,
+        renames::RenameWindow,
+        scan::scan_project,
+        store::Store,
+        types::{
+            FileDiff, FileDiffBatch, IgnoreReason, LocalFileData, ProjectId, ScanReport,
+            ThreeWayDiff, TreeNames, VerifyOptions, VerifyReport,
+        },
+        verify,
+    },
     error::Result,
+    scans::{Scans, SCAN_PROGRESS_EVENT},
 };
 
 
-/// Find the difference between the local file state and the HEAD file state
+/// Find the difference between the local file state and the remote file state
 /// Local is LEFT, remote is RIGHT
+///
+/// Pass a `folder` to only compare what's inside it.
 #[tauri::command]
-pub async fn get_local_to_head_diff(
-    root: PathBuf,
-    db: State<'_, sled::Db>,
+pub async fn get_file_diff(
+    project_id: ProjectId,
+    folder: Option<ProjectPath>,
+    db: State<'_, Store>,
 ) -> Result<Vec<FileDiff>> {
-    let local_tree_name =
-        TreeNames::HASH_LOCAL_METDATA.to_owned() + root.to_string_lossy().as_ref();
+    let project = get_project(db.as_ref(), project_id)?;
+
+    compare_project(&project, folder.as_ref(), db.as_ref())
+}
+
+/// Same as `get_file_diff`, but sends the diffs to the window in `file-diff-batch` events
+/// as they're found, so the first results show up before the whole project is compared.
+/// Returns the total number of diffs sent.
+#[tauri::command]
+pub async fn stream_file_diff(
+    project_id: ProjectId,
+    folder: Option<ProjectPath>,
+    window: Window,
+    db: State<'_, Store>,
+) -> Result<usize> {
+    const BATCH_SIZE: usize = 500;
+    // Creates and deletes held back at once, waiting for the other half of a rename
+    const RENAME_WINDOW: usize = 5_000;
+
+    let project = get_project(db.as_ref(), project_id)?;
+
+    let local_tree_name = project.tree_name(TreeNames::HASH_LOCAL_METDATA);
+    let remote_tree_name = project.tree_name(TreeNames::HASH_REMOTE_METDATA);
+    let head_tree_name = project.tree_name(TreeNames::HASH_HEAD_METDATA);
+    let tombstone_tree_name = project.tree_name(TreeNames::LOCAL_TOMBSTONES);
+
+    let base = TreeDiffBase::open(head_tree_name, tombstone_tree_name, db.as_ref())?;
+
+    let mut total = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut renames = RenameWindow::new(RENAME_WINDOW);
+
+    let diffs = stream_trees(
+        local_tree_name,
+        remote_tree_name,
+        folder.as_ref(),
+        base,
+        db.as_ref(),
+    )?;
+
+    for diff in diffs {
+        batch.extend(renames.push(diff?));
+
+        if batch.len() >= BATCH_SIZE {
+            total += batch.len();
+            window.emit("file-diff-batch", FileDiffBatch::new(std::mem::take(&mut batch), false))?;
+        }
+    }
 
-    let remote_tree_name =
-        TreeNames::HASH_REMOTE_METDATA.to_owned() + root.to_string_lossy().as_ref();
+    batch.extend(renames.finish());
+    total += batch.len();
+    window.emit("file-diff-batch", FileDiffBatch::new(batch, true))?;
 
-    compare_trees(local_tree_name, remote_tree_name, db)
+    Ok(total)
+}
 
+/// Classify every path against HEAD, so local and remote changes can be told apart
+#[tauri::command]
+pub async fn get_three_way_diff(
+    project_id: ProjectId,
+    db: State<'_, Store>,
+) -> Result<Vec<ThreeWayDiff>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    let local_tree_name = project.tree_name(TreeNames::HASH_LOCAL_METDATA);
+    let head_tree_name = project.tree_name(TreeNames::HASH_HEAD_METDATA);
+    let remote_tree_name = project.tree_name(TreeNames::HASH_REMOTE_METDATA);
+
+    compare_three_trees(local_tree_name, head_tree_name, remote_tree_name, db.as_ref())
 }
 
 #[tauri::command]
 pub async fn update_remote_state(
-    root: PathBuf,
+    project_id: ProjectId,
     remote_state: Vec<LocalFileData>,
-    db: State<'_, sled::Db>,
+    db: State<'_, Store>,
 ) -> Result<()> {
-    let remote_tree_name =
-        TreeNames::HASH_REMOTE_METDATA.to_owned() + root.to_string_lossy().as_ref();
-    let remote_tree = db.open_tree(remote_tree_name)?;
-
-    remote_tree.clear()?;
+    let project = get_project(db.as_ref(), project_id)?;
 
-    for item in remote_state {
-        let key = to_vec(&item.metadata.path)?;
-        let value = to_vec(&item)?;
-
-        remote_tree.insert(key, value)?;
-    }
-
-    Ok(())
+    set_remote_state(&project, remote_state, db.as_ref())
 }
 
 
-/// Update local state from the state of the filesystem
+/// Update local state from the state of the filesystem, sending `scan-progress` events
+/// as it goes. `cancel_scan` stops it early.
 #[tauri::command]
-pub async fn update_local_state(root: PathBuf, db: State<'_, sled::Db>) -> Result<()> {
-    let update_start_time = Utc::now();
-
-    // Get metadata for all files
-    let metadata = get_metadatas(&root)?;
-
-    // Create the tree
-    let basic_metadata_tree_name =
-        TreeNames::BASIC_LOCAL_METADATA.to_owned() + root.to_string_lossy().as_ref();
-    let meta_hash_tree_name =
-        TreeNames::HASH_LOCAL_METDATA.to_owned() + root.to_string_lossy().as_ref();
-    // Metadata tree always has LocalFileMetadata in it
-    let metadata_tree = db.open_tree(basic_metadata_tree_name)?;
-    // Hash tree always has LocalFileData in it (the superset)
-    let meta_hash_tree = db.open_tree(meta_hash_tree_name)?;
-
-    // Check metadata for each file, seeing if it needs to be changed
-    let files_to_rehash = metadata
-        .into_iter()
-        .filter_map(|(path, metadatum)| {
-            let key = to_vec(&path).ok()?;
-            let value = to_vec(&metadatum).ok()?;
-
-            let prev_val = metadata_tree.insert(&key, value).ok()?;
-
-            match prev_val {
-                // If the key doesn't exist, we need to insert it then hash it
-                None => Some((path, metadatum)),
-                // If the key does exist, we need to check if the metadata is the same
-                Some(prev_val) => {
-                    let old_thing: LocalFileMetadata = match from_slice(&prev_val) {
-                        Ok(old_thing) => old_thing,
-                        Err(err) => {
-                            println!("Error: {:?}", err);
-                            if err.is_data() {
-                                // If it's a data error, override and mark for rehashing because something done fucked up
-                                return Some((path, metadatum));
-                            } else {
-                                // If it's not a data error, just ignore it I guess
-                                return None;
-                            }
-                        }
-                    };
-
-                    if old_thing == metadatum {
-                        None
-                    } else {
-                        Some((path, metadatum))
-                    }
-                }
+pub async fn update_local_state(
+    project_id: ProjectId,
+    window: Window,
+    scans: State<'_, Scans>,
+    db: State<'_, Store>,
+) -> Result<ScanReport> {
+    let project = get_project(db.as_ref(), project_id)?;
+    let scan = scans.start(project_id).await?;
+
+    scan_project(
+        &project,
+        db.as_ref(),
+        |progress| {
+            if let Err(err) = window.emit(SCAN_PROGRESS_EVENT, progress) {
+                println!("Failed to send scan progress: {}", err);
             }
-        })
-        .map(|(path, metadatum)| {
-            let key = to_vec(&path).unwrap();
-            metadata_tree
-                .insert(&key, to_vec(&metadatum).unwrap())
-                .unwrap();
-            (path, metadatum)
-        });
-
-    // If it does, it needs to be re-hashed
-    let mut fs_iter = futures::stream::iter(files_to_rehash)
-        .map(|(_, metadata)| tokio::spawn(hash_and_finalize(metadata)))
-        .buffer_unordered(200)
-        .enumerate();
-
-    while let Some((count, Ok(Ok(data)))) = fs_iter.next().await {
-        meta_hash_tree.insert(to_vec(&data.metadata.path)?, to_vec(&data)?)?;
-
-        if count % 200 == 0 {
-            println!("{} files re-hashed", count + 1);
-        }
-    }
+        },
+        scan.cancelled(),
+    )
+    .await
+}
 
-    // Remove old items from the trees
-    for item in metadata_tree.iter() {
-        let (key, metadatum) = item?;
+/// Stop the running scan of a project. Returns whether there was one.
+#[tauri::command]
+pub async fn cancel_scan(project_id: ProjectId, scans: State<'_, Scans>) -> Result<bool> {
+    scans.cancel(project_id)
+}
 
-        let key_path: PathBuf = from_slice(&key)?;
-        let metadatum: LocalFileMetadata = from_slice(&metadatum)?;
+/// What the project's last full scan did and skipped, `None` if it hasn't had one
+#[tauri::command]
+pub async fn get_scan_report(
+    project_id: ProjectId,
+    db: State<'_, Store>,
+) -> Result<Option<ScanReport>> {
+    last_scan_report(db.as_ref(), project_id)
+}
 
-        if metadatum.update_time < update_start_time {
-            metadata_tree.remove(&key)?;
-            meta_hash_tree.remove(&key)?;
-        }
-    }
 
-    metadata_tree.flush()?;
-    meta_hash_tree.flush()?;
+/// Choose between buffered and memory-mapped hashing
+#[tauri::command]
+pub async fn set_hash_mode(mode: HashMode, db: State<'_, Store>) -> Result<()> {
+    hashing::set_hash_mode(db.as_ref(), mode)
+}
+
+/// Turn on a cryptographic hash next to the fast one, or turn it off with `None`.
+/// Files get their new hashes on the next scan.
+#[tauri::command]
+pub async fn set_strong_hash(
+    algorithm: Option<HashAlgorithm>,
+    db: State<'_, Store>,
+) -> Result<()> {
+    hashing::set_strong_hash(db.as_ref(), algorithm)
+}
 
-    println!(
-        "Metadata: {}, Hashed: {}",
-        metadata_tree.len(),
-        meta_hash_tree.len()
-    );
-    Ok(())
+/// Why the scanner skips `path`, or `None` if it doesn't
+#[tauri::command]
+pub async fn explain_ignored(
+    project_id: ProjectId,
+    path: ProjectPath,
+    db: State<'_, Store>,
+) -> Result<Option<IgnoreReason>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    let mut rules = IgnoreRules::new(&project.root)?;
+
+    Ok(rules.explain(&path))
 }
 
-async fn hash_and_finalize(metadata: LocalFileMetadata) -> Result<LocalFileData> {
-    let bytes = read(&metadata.path).await?; // Vec<u8>
-    let hash = xxh3_64(&bytes);
-
-    let result = hash as u128;
-
-    Ok(LocalFileData {
-        name: metadata
-            .path
-            .file_name()
-            .ok_or_else(|| "No filename".to_string())?
-            .to_string_lossy()
-            .to_string(),
-        metadata,
-        hash: result,
-    })
+/// Check the local state against itself and the disk. Pass `sample` to only re-hash
+/// some of the files, and `repair` to fix what can be fixed.
+#[tauri::command]
+pub async fn verify_project(
+    project_id: ProjectId,
+    options: Option<VerifyOptions>,
+    db: State<'_, Store>,
+) -> Result<VerifyReport> {
+    let project = get_project(db.as_ref(), project_id)?;
+
+    verify::verify_project(&project, &options.unwrap_or_default(), db.as_ref()).await
 }
 
 #[cfg(test)]
 mod tests {
-    use chrono::TimeZone;
-    use sled::Db;
+    use std::{fs::write, sync::atomic::AtomicBool};
 
-    use super::*;
-    use std::path::PathBuf;
-
-    #[cfg(target_os = "macos")]
-    static GRABCAD_PATH: &str = "/Users/cobular/Documents/GrabCAD";
-    #[cfg(target_os = "linux")]
-    static GRABCAD_PATH: &str = "/Users/cobular/Documents/GrabCAD";
-    #[cfg(target_os = "windows")]
-    static GRABCAD_PATH: &str = r#"C:\Users\jdc10\Documents\GrabCAD"#;
+    use chrono::{TimeZone, Utc};
 
-    pub struct MyState<'r, T: Send + Sync + 'static>(&'r T);
+    use super::*;
+    use crate::db::{
+        memory_store::MemoryStore, projects::register_project, staging::LocalTrees,
+        types::{DiffTypes, LocalFileMetadata},
+    };
 
     #[test]
     fn test_compare_metadata() {
         let modified = Utc.ymd(2021, 1, 1).and_hms(0, 0, 0);
         let data1 = LocalFileMetadata {
-            path: PathBuf::from("test"),
+            path: ProjectPath::parse("test").unwrap(),
             size: 0,
             modified,
             update_time: Utc::now(),
         };
         let data2 = LocalFileMetadata {
-            path: PathBuf::from("test"),
+            path: ProjectPath::parse("test").unwrap(),
             size: 0,
             modified,
             update_time: Utc.ymd(2014, 11, 28).and_hms(12, 0, 9),
@@ -233,26 +255,30 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_get_local_files() {
-        println!("eeeee");
-        let root = PathBuf::from(GRABCAD_PATH);
-        // let db = sled::Config::default().temporary(true).open().unwrap();
-
-        let db = sled::open("test.db").unwrap();
-        let state = MyState(&db);
-        let state: State<Db> = unsafe { std::mem::transmute(state) };
-        update_local_state(root, state).await.unwrap();
+    async fn test_scan_and_diff_against_remote() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path().join("bolt.sldprt"), "bolt").unwrap();
+        write(root.path().join("nut.sldprt"), "nut").unwrap();
+
+        let db = MemoryStore::default();
+        let project = register_project(&db, root.path().to_path_buf(), None).unwrap();
+        scan_project(&project, &db, |_| {}, &AtomicBool::new(false))
+            .await
+            .unwrap();
+
+        // The remote has the same bolt, but has never seen the nut
+        let bolt = LocalTrees::open(&project, &db)
+            .unwrap()
+            .hashes
+            .get(&ProjectPath::parse("bolt.sldprt").unwrap())
+            .unwrap()
+            .unwrap();
+        set_remote_state(&project, vec![bolt], &db).unwrap();
+
+        let diffs = compare_project(&project, None, &db).unwrap();
+
+        assert_eq!(1, diffs.len());
+        assert_eq!(ProjectPath::parse("nut.sldprt").unwrap(), diffs[0].path);
+        assert_eq!(DiffTypes::LeftCreate, diffs[0].diff_type);
     }
-
-    #[tokio::test]
-    async fn test_merge_trees() {
-        println!("eeeee");
-        let root = PathBuf::from(GRABCAD_PATH);
-
-        let db = sled::open("test.db").unwrap();
-        let state = MyState(&db);
-        let state: State<Db> = unsafe { std::mem::transmute(state) };
-
-        get_file_diff(root, state).await.unwrap();
-    }
-}
\ No newline at end of file
+}
@@ -1 +1,5 @@
+pub mod chunks;
+pub mod history;
 pub mod local_files;
+pub mod projects;
+pub mod tags;
@@ -0,0 +1,64 @@
+//! Register and manage the local projects everything else is keyed by.
+
+use std::path::PathBuf;
+
+use tauri::{AppHandle, State};
+
+use crate::{
+    db::{
+        objects::ObjectStore,
+        projects,
+        store::Store,
+        types::{Project, ProjectId},
+    },
+    error::Result,
+    watcher::Watchers,
+};
+
+/// Start tracking the folder at `root`, and watching it for changes. Returns the new
+/// project, whose ID the other commands take.
+#[tauri::command]
+pub async fn register_project(
+    root: PathBuf,
+    cloud_project_id: Option<u64>,
+    app: AppHandle,
+    watchers: State<'_, Watchers>,
+    db: State<'_, Store>,
+) -> Result<Project> {
+    let project = projects::register_project(db.as_ref(), root, cloud_project_id)?;
+    watchers.watch(&project, &db, &app)?;
+
+    Ok(project)
+}
+
+/// The project folder moved, point it at the new `root` without losing any state
+#[tauri::command]
+pub async fn relink_project(
+    project_id: ProjectId,
+    root: PathBuf,
+    app: AppHandle,
+    watchers: State<'_, Watchers>,
+    db: State<'_, Store>,
+) -> Result<Project> {
+    let project = projects::relink_project(db.as_ref(), project_id, root)?;
+    watchers.watch(&project, &db, &app)?;
+
+    Ok(project)
+}
+
+#[tauri::command]
+pub async fn list_projects(db: State<'_, Store>) -> Result<Vec<Project>> {
+    projects::list_projects(db.as_ref())
+}
+
+/// Stop tracking a project, dropping all its local state and history. Doesn't touch the files.
+#[tauri::command]
+pub async fn remove_project(
+    project_id: ProjectId,
+    watchers: State<'_, Watchers>,
+    objects: State<'_, ObjectStore>,
+    db: State<'_, Store>,
+) -> Result<()> {
+    watchers.unwatch(project_id)?;
+    projects::remove_project(db.as_ref(), &objects, project_id)
+}
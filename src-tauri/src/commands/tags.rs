@@ -0,0 +1,59 @@
+//! Tags, named snapshots of HEAD for freezing what was sent out.
+
+use std::path::PathBuf;
+
+use tauri::State;
+
+use crate::{
+    db::{
+        projects::get_project,
+        store::Store,
+        tags,
+        types::{FileDiff, ProjectId, Tag, TagManifest},
+    },
+    error::Result,
+};
+
+/// Tag what HEAD is now. Fails if the name is taken, tags never move.
+#[tauri::command]
+pub async fn create_tag(
+    project_id: ProjectId,
+    name: String,
+    description: String,
+    creator: String,
+    db: State<'_, Store>,
+) -> Result<Tag> {
+    let project = get_project(db.as_ref(), project_id)?;
+    tags::create_tag(&project, name, description, creator, db.as_ref())
+}
+
+/// The project's tags, newest first
+#[tauri::command]
+pub async fn list_tags(project_id: ProjectId, db: State<'_, Store>) -> Result<Vec<Tag>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    tags::list_tags(&project, db.as_ref())
+}
+
+/// What changed between two tags. `from` is LEFT and `to` is RIGHT.
+#[tauri::command]
+pub async fn diff_tags(
+    project_id: ProjectId,
+    from: String,
+    to: String,
+    db: State<'_, Store>,
+) -> Result<Vec<FileDiff>> {
+    let project = get_project(db.as_ref(), project_id)?;
+    tags::diff_tags(&project, &from, &to, db.as_ref())
+}
+
+/// Save every file in the tag, with its hashes, as JSON at `destination`
+#[tauri::command]
+pub async fn export_tag_manifest(
+    project_id: ProjectId,
+    name: String,
+    destination: PathBuf,
+    db: State<'_, Store>,
+) -> Result<TagManifest> {
+    let project = get_project(db.as_ref(), project_id)?;
+    tags::export_tag_manifest(&project, &name, &destination, db.as_ref())
+}
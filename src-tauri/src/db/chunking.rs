@@ -0,0 +1,223 @@
+//! Content-defined chunking, so a small edit to a big file only means sending the
+//! chunks around the edit.
+//!
+//! Boundaries come from FastCDC, which picks them from the content itself. Inserting
+//! a few bytes near the start of a file changes the chunk they land in, instead of
+//! shifting every fixed-size block after it. Chunks are named by their BLAKE3 hash,
+//! since the server dedups on them and a collision there would corrupt files.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Read,
+    path::PathBuf,
+    slice,
+};
+
+use fastcdc::v2020::StreamCDC;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    db::{
+        content_hash::{ContentHash, HashAlgorithm},
+        hashing::reserve_memory,
+        project_path::ProjectPath,
+        refresh_state::refresh_paths,
+        staging::LocalTrees,
+        store::MetadataStore,
+        types::{Chunk, ChunkManifest, LocalFileData, Project},
+    },
+    error::Result,
+};
+
+pub const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+pub const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+pub const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Chunk a file, waiting for room in the hashing memory budget first
+pub async fn chunk_file(path: PathBuf) -> Result<ChunkManifest> {
+    // The chunker's read buffer, plus the chunk it hands back
+    let _permit = reserve_memory(2 * MAX_CHUNK_SIZE as usize).await?;
+
+    tokio::task::spawn_blocking(move || {
+        chunk_reader(File::open(path)?, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE)
+    })
+    .await
+    .map_err(|err| format!("Chunking task failed: {}", err))?
+}
+
+/// Split everything from `reader` into chunks, hashing the whole thing on the way
+pub fn chunk_reader(
+    reader: impl Read,
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+) -> Result<ChunkManifest> {
+    let mut file_hasher = Xxh3::new();
+    let mut chunks = Vec::new();
+    let mut size = 0;
+
+    for chunk in StreamCDC::new(reader, min_size, avg_size, max_size) {
+        let chunk = chunk.map_err(std::io::Error::from)?;
+
+        file_hasher.update(&chunk.data);
+        size += chunk.length as u64;
+        chunks.push(Chunk {
+            hash: ContentHash::new(
+                HashAlgorithm::Blake3,
+                blake3::hash(&chunk.data).as_bytes().to_vec(),
+            )?,
+            offset: chunk.offset,
+            length: chunk.length as u64,
+        });
+    }
+
+    Ok(ChunkManifest {
+        file_hash: ContentHash::xxh3_128(file_hasher.digest128()),
+        size,
+        chunks,
+    })
+}
+
+/// The chunk manifest of a tracked file, chunking it if there's no manifest
+/// for its current contents yet
+pub async fn manifest_for(
+    project: &Project,
+    path: &ProjectPath,
+    db: &dyn MetadataStore,
+) -> Result<ChunkManifest> {
+    let trees = LocalTrees::open(project, db)?;
+    let tracked = || -> Result<LocalFileData> {
+        match trees.hashes.get(path)? {
+            Some(data) => Ok(data),
+            None => Err(format!("{} isn't tracked", path).into()),
+        }
+    };
+
+    let mut data = tracked()?;
+    // A hash kept from before v4 can't be checked against, so hash it again like a scan would
+    if data.hash.algorithm() != HashAlgorithm::Xxh3_128 {
+        refresh_paths(project, slice::from_ref(path), db).await?;
+        data = tracked()?;
+    }
+
+    if let Some(manifest) = trees.manifests.get(path)? {
+        if manifest.file_hash == data.hash {
+            return Ok(manifest);
+        }
+    }
+
+    let manifest = chunk_file(path.to_absolute(&project.root)).await?;
+    // Sending these chunks would send something other than what the local state says
+    if manifest.file_hash != data.hash {
+        return Err(format!("{} changed since it was last scanned", path).into());
+    }
+
+    trees.manifests.insert(path, &manifest)?;
+    Ok(manifest)
+}
+
+/// The chunks the remote needs to rebuild the file, given the chunk hashes it has.
+/// A chunk that shows up more than once in the file is only listed once.
+pub fn missing_chunks(manifest: &ChunkManifest, remote_has: &HashSet<ContentHash>) -> Vec<Chunk> {
+    let mut seen = HashSet::new();
+
+    manifest
+        .chunks
+        .iter()
+        .filter(|chunk| !remote_has.contains(&chunk.hash) && seen.insert(&chunk.hash))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use xxhash_rust::xxh3::xxh3_128;
+
+    use super::{chunk_reader, manifest_for, missing_chunks};
+    use crate::db::{
+        content_hash::ContentHash,
+        memory_store::MemoryStore,
+        project_path::ProjectPath,
+        refresh_state::refresh_paths,
+        staging::LocalTrees,
+        test_support::{path, project_at},
+    };
+
+    // Deterministic junk, so chunk boundaries don't line up with any pattern
+    fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunks_cover_file() {
+        let data = noise(200_000, 1);
+
+        let manifest = chunk_reader(data.as_slice(), 1024, 4096, 16384).unwrap();
+
+        assert_eq!(ContentHash::xxh3_128(xxh3_128(&data)), manifest.file_hash);
+        assert_eq!(data.len() as u64, manifest.size);
+        let mut offset = 0;
+        for chunk in &manifest.chunks {
+            assert_eq!(offset, chunk.offset);
+            offset += chunk.length;
+        }
+        assert_eq!(data.len() as u64, offset);
+    }
+
+    #[test]
+    fn test_edit_only_misses_nearby_chunks() {
+        let original = noise(200_000, 2);
+        let mut edited = original.clone();
+        // Insert in the middle, which would shift every fixed-size block after it
+        edited.splice(100_000..100_000, noise(10, 3));
+
+        let original = chunk_reader(original.as_slice(), 1024, 4096, 16384).unwrap();
+        let edited = chunk_reader(edited.as_slice(), 1024, 4096, 16384).unwrap();
+
+        let remote_has: HashSet<ContentHash> =
+            original.chunks.iter().map(|chunk| chunk.hash.clone()).collect();
+        let missing = missing_chunks(&edited, &remote_has);
+
+        assert!(!missing.is_empty());
+        assert!(missing.len() <= 3, "{} chunks missing", missing.len());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_for_migrated_hash() {
+        let root = tempfile::tempdir().unwrap();
+        let contents = noise(10_000, 4);
+        std::fs::write(root.path().join("bolt.sldprt"), &contents).unwrap();
+
+        let db = MemoryStore::default();
+        let project = project_at(root.path());
+        refresh_paths(&project, &[ProjectPath::default()], &db)
+            .await
+            .unwrap();
+
+        // As the v4 migration leaves it until the next scan
+        let trees = LocalTrees::open(&project, &db).unwrap();
+        let mut data = trees.hashes.get(&path("bolt.sldprt")).unwrap().unwrap();
+        data.hash = ContentHash::xxh3_64(1);
+        trees.hashes.insert(&path("bolt.sldprt"), &data).unwrap();
+
+        let manifest = manifest_for(&project, &path("bolt.sldprt"), &db)
+            .await
+            .unwrap();
+        assert_eq!(
+            ContentHash::xxh3_128(xxh3_128(&contents)),
+            manifest.file_hash
+        );
+        let data = trees.hashes.get(&path("bolt.sldprt")).unwrap().unwrap();
+        assert_eq!(manifest.file_hash, data.hash);
+    }
+}
@@ -0,0 +1,386 @@
+//! Local commits, the history behind HEAD.
+//!
+//! A commit records what changed in the local hash tree since its parent, and moves
+//! HEAD to it. The HEAD tree always holds the files as of the HEAD commit, so only
+//! the changes are kept in the commit itself. The bytes of every committed version go
+//! into the `ObjectStore`.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+
+use crate::{
+    db::{
+        compare::{compare_trees, DiffBase},
+        objects::{add_refs, release_refs, store_commit_objects, ObjectStore},
+        project_path::ProjectPath,
+        store::MetadataStore,
+        typed_tree::{TypedBatch, TypedTree},
+        types::{
+            Commit, CommitChange, CommitChangeKind, CommitId, DiffTypes, FileDiff, FileDiffData,
+            LocalFileData, Project, ProjectId, TreeNames,
+        },
+    },
+    error::Result,
+};
+
+fn commits(project: &Project, db: &dyn MetadataStore) -> Result<TypedTree<CommitId, Commit>> {
+    TypedTree::open(db, project.tree_name(TreeNames::COMMITS))
+}
+
+fn heads(db: &dyn MetadataStore) -> Result<TypedTree<ProjectId, CommitId>> {
+    TypedTree::open(db, TreeNames::HEADS)
+}
+
+/// The commit HEAD is at, `None` until the project's first commit
+pub fn head_commit(project: &Project, db: &dyn MetadataStore) -> Result<Option<Commit>> {
+    match heads(db)?.get(&project.id)? {
+        Some(id) => Ok(Some(get_commit(project, id, db)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn get_commit(project: &Project, id: CommitId, db: &dyn MetadataStore) -> Result<Commit> {
+    match commits(project, db)?.get(&id)? {
+        Some(commit) => Ok(commit),
+        None => Err(format!("No commit {} in project {}", id, project.id).into()),
+    }
+}
+
+/// Everything the local hash tree changed since HEAD, renames included
+pub fn uncommitted_changes(project: &Project, db: &dyn MetadataStore) -> Result<Vec<CommitChange>> {
+    let head: TypedTree<ProjectPath, LocalFileData> =
+        TypedTree::open(db, project.tree_name(TreeNames::HASH_HEAD_METDATA))?;
+
+    // Local is LEFT and HEAD is RIGHT, so anything only HEAD has was deleted locally
+    let diffs = compare_trees(
+        project.tree_name(TreeNames::HASH_LOCAL_METDATA),
+        project.tree_name(TreeNames::HASH_HEAD_METDATA),
+        None,
+        &HeadBase(head),
+        db,
+    )?;
+
+    let mut changes: Vec<CommitChange> = diffs.into_iter().filter_map(to_change).collect();
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changes)
+}
+
+/// Snapshot the local hash tree into a new commit on top of HEAD, and move HEAD to it
+pub async fn commit_project(
+    project: &Project,
+    author: String,
+    message: String,
+    objects: &ObjectStore,
+    db: &dyn MetadataStore,
+) -> Result<Commit> {
+    let changes = uncommitted_changes(project, db)?;
+    if changes.is_empty() {
+        return Err(format!("Nothing to commit in project {}", project.id).into());
+    }
+
+    let commit = Commit {
+        id: db.generate_id()?,
+        project_id: project.id,
+        parent: heads(db)?.get(&project.id)?,
+        author,
+        message,
+        timestamp: Utc::now(),
+        changes,
+    };
+
+    // Before anything points at them
+    let stored = store_commit_objects(&commit, &project.root, objects, db).await?;
+
+    let mut head_batch: TypedBatch<ProjectPath, LocalFileData> = TypedBatch::default();
+    for change in &commit.changes {
+        if let CommitChangeKind::Renamed { from } = &change.kind {
+            head_batch.remove(from);
+        }
+        match &change.data {
+            Some(data) => head_batch.insert(&change.path, data)?,
+            None => head_batch.remove(&change.path),
+        }
+    }
+
+    let mut commit_batch: TypedBatch<CommitId, Commit> = TypedBatch::default();
+    commit_batch.insert(&commit.id, &commit)?;
+    let mut heads_batch: TypedBatch<ProjectId, CommitId> = TypedBatch::default();
+    heads_batch.insert(&project.id, &commit.id)?;
+
+    // Another commit could have moved HEAD while the objects were stored, and then this
+    // one's changes are against the wrong parent
+    let parent_is_head = || {
+        if heads(db)?.get(&project.id)? != commit.parent {
+            return Err(format!(
+                "Project {} was committed to meanwhile, commit again",
+                project.id
+            )
+            .into());
+        }
+        Ok(())
+    };
+
+    add_refs(
+        &stored,
+        &[
+            (
+                &project.tree_name(TreeNames::HASH_HEAD_METDATA),
+                head_batch.inner(),
+            ),
+            (&project.tree_name(TreeNames::COMMITS), commit_batch.inner()),
+            (TreeNames::HEADS, heads_batch.inner()),
+        ],
+        parent_is_head,
+        objects,
+        db,
+    )?;
+
+    Ok(commit)
+}
+
+/// The commits HEAD is built from, newest first
+pub fn log(project: &Project, db: &dyn MetadataStore) -> Result<Vec<Commit>> {
+    ancestry(project, heads(db)?.get(&project.id)?, db)
+}
+
+/// `from` and every commit before it, newest first
+fn ancestry(
+    project: &Project,
+    from: Option<CommitId>,
+    db: &dyn MetadataStore,
+) -> Result<Vec<Commit>> {
+    let commits = commits(project, db)?;
+    let mut ancestry = Vec::new();
+
+    let mut next = from;
+    while let Some(id) = next {
+        let commit = match commits.get(&id)? {
+            Some(commit) => commit,
+            None => {
+                return Err(format!("Commit {} of project {} is missing", id, project.id).into())
+            }
+        };
+        next = commit.parent;
+        ancestry.push(commit);
+    }
+
+    Ok(ancestry)
+}
+
+/// Every file as of commit `id`, put together from it and the commits before it
+pub fn files_at(
+    project: &Project,
+    id: CommitId,
+    db: &dyn MetadataStore,
+) -> Result<BTreeMap<ProjectPath, LocalFileData>> {
+    let mut files = BTreeMap::new();
+
+    for commit in ancestry(project, Some(id), db)?.into_iter().rev() {
+        for change in commit.changes {
+            if let CommitChangeKind::Renamed { from } = &change.kind {
+                files.remove(from);
+            }
+            match change.data {
+                Some(data) => files.insert(change.path, data),
+                None => files.remove(&change.path),
+            };
+        }
+    }
+
+    Ok(files)
+}
+
+/// Drop the whole history of a project, and the objects only it used
+pub fn remove_history(
+    project: &Project,
+    objects: &ObjectStore,
+    db: &dyn MetadataStore,
+) -> Result<()> {
+    let commits = commits(project, db)?
+        .iter()
+        .map(|item| Ok(item?.1))
+        .collect::<Result<Vec<_>>>()?;
+    release_refs(&commits, objects, db)?;
+
+    db.drop_tree(&project.tree_name(TreeNames::COMMITS))?;
+    heads(db)?.remove(&project.id)?;
+    Ok(())
+}
+
+// Only HEAD, so a path that's only in HEAD is a local delete rather than a create on HEAD's side
+struct HeadBase(TypedTree<ProjectPath, LocalFileData>);
+
+impl DiffBase for HeadBase {
+    fn head(&self, path: &ProjectPath) -> Result<Option<LocalFileData>> {
+        self.0.get(path)
+    }
+
+    fn left_tombstone(&self, _path: &ProjectPath) -> Result<Option<LocalFileData>> {
+        Ok(None)
+    }
+}
+
+fn to_change(diff: FileDiff) -> Option<CommitChange> {
+    let (kind, data) = match (diff.diff_type, diff.diff_metadata) {
+        (DiffTypes::LeftCreate, FileDiffData::Left(data)) => (CommitChangeKind::Added, Some(data)),
+        (DiffTypes::LeftDelete, _) => (CommitChangeKind::Deleted, None),
+        (
+            DiffTypes::LeftNewer | DiffTypes::RightNewer | DiffTypes::Conflict { .. },
+            FileDiffData::Both(data, _),
+        ) => (CommitChangeKind::Modified, Some(data)),
+        (DiffTypes::Renamed { from, .. }, FileDiffData::Left(data)) => {
+            (CommitChangeKind::Renamed { from }, Some(data))
+        }
+        // Nothing is created or deleted on HEAD's side
+        _ => return None,
+    };
+
+    Some(CommitChange {
+        path: diff.path,
+        kind,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use chrono::{TimeZone, Utc};
+    use xxhash_rust::xxh3::xxh3_128;
+
+    use super::{commit_project, log, remove_history, uncommitted_changes};
+    use crate::db::{
+        content_hash::ContentHash,
+        memory_store::MemoryStore,
+        objects::{object_record, ObjectStore},
+        project_path::ProjectPath,
+        test_support::{path, project_at},
+        typed_tree::TypedTree,
+        types::{CommitChangeKind, LocalFileData, LocalFileMetadata, TreeNames},
+    };
+
+    // Put the file on disk, and return what a scan would have found
+    fn write_file(root: &Path, file_path: &str, contents: &[u8], modified: i64) -> LocalFileData {
+        let absolute = path(file_path).to_absolute(root);
+        fs::create_dir_all(absolute.parent().unwrap()).unwrap();
+        fs::write(&absolute, contents).unwrap();
+
+        LocalFileData {
+            name: file_path.to_owned(),
+            hash: ContentHash::xxh3_128(xxh3_128(contents)),
+            strong_hash: None,
+            metadata: LocalFileMetadata {
+                path: path(file_path),
+                size: contents.len() as u64,
+                modified: Utc.timestamp_opt(modified, 0).unwrap(),
+                update_time: Utc.timestamp_opt(modified, 0).unwrap(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_commit_and_log() {
+        let db = MemoryStore::default();
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        let root = dir.path().join("robot");
+        let project = project_at(&root);
+        let local: TypedTree<ProjectPath, LocalFileData> =
+            TypedTree::open(&db, project.tree_name(TreeNames::HASH_LOCAL_METDATA)).unwrap();
+        let head: TypedTree<ProjectPath, LocalFileData> =
+            TypedTree::open(&db, project.tree_name(TreeNames::HASH_HEAD_METDATA)).unwrap();
+
+        assert!(
+            commit_project(&project, "ana".into(), "empty".into(), &objects, &db)
+                .await
+                .is_err()
+        );
+
+        let bolt = write_file(&root, "bolt.sldprt", b"bolt", 10);
+        let nut = write_file(&root, "nut.sldprt", b"nut", 10);
+        local.insert(&path("bolt.sldprt"), &bolt).unwrap();
+        local.insert(&path("nut.sldprt"), &nut).unwrap();
+        let first = commit_project(&project, "ana".into(), "first".into(), &objects, &db)
+            .await
+            .unwrap();
+        assert_eq!(None, first.parent);
+        assert_eq!(2, first.changes.len());
+        assert_eq!(2, head.len());
+        assert!(uncommitted_changes(&project, &db).unwrap().is_empty());
+
+        // Change the bolt, move the nut
+        let new_bolt = write_file(&root, "bolt.sldprt", b"bigger bolt", 20);
+        local.insert(&path("bolt.sldprt"), &new_bolt).unwrap();
+        fs::remove_file(root.join("nut.sldprt")).unwrap();
+        local.remove(&path("nut.sldprt")).unwrap();
+        let moved_nut = write_file(&root, "parts/nut.sldprt", b"nut", 10);
+        local.insert(&path("parts/nut.sldprt"), &moved_nut).unwrap();
+        let second = commit_project(&project, "ben".into(), "second".into(), &objects, &db)
+            .await
+            .unwrap();
+        assert_eq!(Some(first.id), second.parent);
+
+        let kinds: Vec<_> = second
+            .changes
+            .iter()
+            .map(|change| (change.path.clone(), change.kind.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (path("bolt.sldprt"), CommitChangeKind::Modified),
+                (
+                    path("parts/nut.sldprt"),
+                    CommitChangeKind::Renamed {
+                        from: path("nut.sldprt")
+                    }
+                ),
+            ],
+            kinds
+        );
+
+        let head_paths: Vec<_> = head.keys().map(|key| key.unwrap()).collect();
+        assert_eq!(
+            vec![path("bolt.sldprt"), path("parts/nut.sldprt")],
+            head_paths
+        );
+
+        // Both versions of the bolt can be had, and the nut is stored once
+        assert_eq!(b"bolt".to_vec(), objects.read(&bolt.hash).unwrap());
+        assert_eq!(
+            b"bigger bolt".to_vec(),
+            objects.read(&new_bolt.hash).unwrap()
+        );
+        assert_eq!(2, object_record(&nut.hash, &db).unwrap().unwrap().refs);
+
+        assert_eq!(vec![second, first], log(&project, &db).unwrap());
+
+        remove_history(&project, &objects, &db).unwrap();
+        assert!(log(&project, &db).unwrap().is_empty());
+        assert!(!objects.contains(&nut.hash));
+        assert_eq!(None, object_record(&bolt.hash, &db).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_commits_keep_their_parent() {
+        let db = MemoryStore::default();
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        let root = dir.path().join("robot");
+        let project = project_at(&root);
+        let local: TypedTree<ProjectPath, LocalFileData> =
+            TypedTree::open(&db, project.tree_name(TreeNames::HASH_LOCAL_METDATA)).unwrap();
+        let bolt = write_file(&root, "bolt.sldprt", b"bolt", 10);
+        local.insert(&path("bolt.sldprt"), &bolt).unwrap();
+
+        // Both read HEAD before either stores its objects
+        let (first, second) = tokio::join!(
+            commit_project(&project, "ana".into(), "first".into(), &objects, &db),
+            commit_project(&project, "ben".into(), "second".into(), &objects, &db),
+        );
+
+        assert!(first.is_ok() != second.is_ok());
+        assert_eq!(1, log(&project, &db).unwrap().len());
+    }
+}
@@ -1,172 +1,558 @@
-use futures::stream::Peekable;
-use serde::de::DeserializeOwned;
-use serde_cbor::from_slice;
-use sled::Tree;
-use tauri::State;
+use std::{cmp::Ordering, iter::Peekable};
 
-use crate::{error::Result, db::types::{FileDiff, TreeItem}};
+use crate::{
+    db::{
+        project_path::ProjectPath,
+        renames::detect_renames,
+        store::MetadataStore,
+        typed_tree::{TypedIter, TypedTree},
+        types::{
+            ConflictReason, FileDiff, LocalFileData, Project, ThreeWayDiff, ThreeWayDiffTypes,
+            Tombstone, TreeItem, TreeNames,
+        },
+    },
+    error::Result,
+};
 
-// There is room to later make this zero copy (Deserialize<'a> vs DeserializeOwned)
-pub fn sort_tree_keys<K, V>(tree: &Tree) -> Result<Vec<(K, V)>>
-where
-    K: DeserializeOwned + Ord + Clone,
-    V: DeserializeOwned,
-{
-    let mut decoded_keypair = tree
-        .iter()
-        .filter_map(|item| item.ok())
-        .map(|(key, value)| {
+/// The last agreed-upon state of the files, used to tell a delete on one side
+/// from a create on the other. A base that can't be read is an error, not a missing
+/// entry, since that would turn conflicts into plain creates and deletes.
+pub trait DiffBase {
+    /// The HEAD version of a path, if it was there at the last sync
+    fn head(&self, path: &ProjectPath) -> Result<Option<LocalFileData>>;
+    /// The last known local version of a path that has since been deleted locally
+    fn left_tombstone(&self, path: &ProjectPath) -> Result<Option<LocalFileData>>;
+}
 
-            let key_parsed: K = from_slice(&key)?;
-            let val_parsed: V = from_slice(&value)?;
+/// No base at all, every one-sided path is a create
+pub struct NoBase;
 
-            Ok((key_parsed, val_parsed)) as Result<(K, V)>
-        })
-        .filter_map(|item| match item {
-            Ok(item) => Some(item),
-            Err(err) => {
-                println!("{}", err);
-                None
-            }
+impl DiffBase for NoBase {
+    fn head(&self, _path: &ProjectPath) -> Result<Option<LocalFileData>> {
+        Ok(None)
+    }
+
+    fn left_tombstone(&self, _path: &ProjectPath) -> Result<Option<LocalFileData>> {
+        Ok(None)
+    }
+}
+
+impl<T: DiffBase> DiffBase for &T {
+    fn head(&self, path: &ProjectPath) -> Result<Option<LocalFileData>> {
+        (**self).head(path)
+    }
+
+    fn left_tombstone(&self, path: &ProjectPath) -> Result<Option<LocalFileData>> {
+        (**self).left_tombstone(path)
+    }
+}
+
+/// Base backed by the HEAD tree and the local tombstone tree
+pub struct TreeDiffBase {
+    pub head: TypedTree<ProjectPath, LocalFileData>,
+    pub tombstones: TypedTree<ProjectPath, Tombstone>,
+}
+
+impl TreeDiffBase {
+    pub fn open(
+        head_tree_name: impl AsRef<str>,
+        tombstone_tree_name: impl AsRef<str>,
+        db: &dyn MetadataStore,
+    ) -> Result<Self> {
+        Ok(Self {
+            head: TypedTree::open(db, head_tree_name)?,
+            tombstones: TypedTree::open(db, tombstone_tree_name)?,
         })
-        .collect::<Vec<_>>();
-    
-    decoded_keypair.sort_by_key(|key| key.0.clone());
+    }
+}
 
-    Ok(decoded_keypair)
+impl DiffBase for TreeDiffBase {
+    fn head(&self, path: &ProjectPath) -> Result<Option<LocalFileData>> {
+        self.head.get(path)
+    }
+
+    fn left_tombstone(&self, path: &ProjectPath) -> Result<Option<LocalFileData>> {
+        Ok(self.tombstones.get(path)?.map(|tombstone| tombstone.data))
+    }
 }
 
-/// Find the difference between the local file state and the HEAD file state
+/// A tree of `LocalFileData`, decoded lazily in key order.
+/// Because keys are encoded with `encode_path_key`, key order is path order.
+pub type TreeItems = TypedIter<ProjectPath, LocalFileData>;
+
+type DataTree = TypedTree<ProjectPath, LocalFileData>;
+
+/// Find the difference between the local file state and the remote file state
 /// Local is LEFT, remote is RIGHT
-pub fn compare_trees<KT, VT>(
-    left_tree_name: dyn AsRef<str>,
-    right_tree_name: dyn AsRef<str>,
-    db: State<'_, sled::Db>,
+///
+/// With a `folder`, only paths inside it are compared.
+pub fn compare_trees(
+    left_tree_name: impl AsRef<str>,
+    right_tree_name: impl AsRef<str>,
+    folder: Option<&ProjectPath>,
+    base: &impl DiffBase,
+    db: &dyn MetadataStore,
+) -> Result<Vec<FileDiff>> {
+    let file_diffs = stream_trees(left_tree_name, right_tree_name, folder, base, db)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(detect_renames(file_diffs))
+}
+
+/// The project's local state against the remote, LEFT and RIGHT, with HEAD and the local
+/// tombstones as the base. Only inside `folder`, if there is one.
+pub fn compare_project(
+    project: &Project,
+    folder: Option<&ProjectPath>,
+    db: &dyn MetadataStore,
+) -> Result<Vec<FileDiff>> {
+    let base = TreeDiffBase::open(
+        project.tree_name(TreeNames::HASH_HEAD_METDATA),
+        project.tree_name(TreeNames::LOCAL_TOMBSTONES),
+        db,
+    )?;
+
+    compare_trees(
+        project.tree_name(TreeNames::HASH_LOCAL_METDATA),
+        project.tree_name(TreeNames::HASH_REMOTE_METDATA),
+        folder,
+        &base,
+        db,
+    )
+}
+
+/// Like `compare_trees`, but yields diffs as the merge-join finds them instead of
+/// collecting them. Only one entry per tree is held in memory at a time.
+///
+/// Renames aren't detected here since they need every create and delete first.
+pub fn stream_trees<B: DiffBase>(
+    left_tree_name: impl AsRef<str>,
+    right_tree_name: impl AsRef<str>,
+    folder: Option<&ProjectPath>,
+    base: B,
+    db: &dyn MetadataStore,
+) -> Result<DiffIter<TreeItems, TreeItems, B>> {
+    let left_tree = DataTree::open(db, left_tree_name)?;
+    let right_tree = DataTree::open(db, right_tree_name)?;
+
+    Ok(DiffIter::new(
+        left_tree.maybe_under(folder),
+        right_tree.maybe_under(folder),
+        base,
+    ))
+}
+
+/// Compare only the given paths, for when just a few files are known to have changed
+pub fn compare_paths(
+    left_tree_name: impl AsRef<str>,
+    right_tree_name: impl AsRef<str>,
+    paths: &[ProjectPath],
+    base: &impl DiffBase,
+    db: &dyn MetadataStore,
+) -> Result<Vec<FileDiff>> {
+    let left_tree = DataTree::open(db, left_tree_name)?;
+    let right_tree = DataTree::open(db, right_tree_name)?;
+
+    let mut paths = paths.to_vec();
+    paths.sort();
+    paths.dedup();
+
+    let items = |tree: &DataTree| -> Result<Vec<TreeItem>> {
+        let mut items = Vec::new();
+        for path in &paths {
+            if let Some(data) = tree.get(path)? {
+                items.push((path.clone(), data));
+            }
+        }
+        Ok(items)
+    };
+
+    find_diffs_with_base(
+        items(&left_tree)?.into_iter().peekable(),
+        items(&right_tree)?.into_iter().peekable(),
+        base,
+    )
+}
+
+pub fn find_diffs<I>(left_iter: Peekable<I>, right_iter: Peekable<I>) -> Result<Vec<FileDiff>>
+where
+    I: Iterator<Item = TreeItem>,
+{
+    find_diffs_with_base(left_iter, right_iter, &NoBase)
+}
+
+/// Same as `find_diffs`, but paths that only exist on one side are checked
+/// against `base` to see if the other side deleted them
+pub fn find_diffs_with_base<I>(
+    left_iter: Peekable<I>,
+    right_iter: Peekable<I>,
+    base: &impl DiffBase,
 ) -> Result<Vec<FileDiff>>
 where
-    KT: DeserializeOwned + Ord + Clone,
-    VT: DeserializeOwned {
-    let left_tree = db.open_tree(left_tree_name)?;
-    let right_tree = db.open_tree(right_tree_name)?;
-
-    // Need to sort both then itterate over both, doing the <> thing
-    let sorted_local_iter = sort_tree_keys::<KT, VT>(&left_tree)?
-        .into_iter()   
-        .peekable();
-    let sorted_remote_iter = sort_tree_keys::<KT, VT>(&right_tree)?
-        .into_iter()
-        .peekable();
+    I: Iterator<Item = TreeItem>,
+{
+    let file_diffs = DiffIter::new(left_iter.map(Ok), right_iter.map(Ok), base)
+        .collect::<Result<Vec<_>>>()?;
 
-    find_diffs(sorted_local_iter, sorted_remote_iter)
+    Ok(detect_renames(file_diffs))
 }
 
-pub fn find_diffs<I>(
-    mut left_iter: Peekable<I>,
-    mut right_iter: Peekable<I>,
-) -> Result<Vec<FileDiff>>
+/// Merge-join over two iterators sorted by path, yielding one diff at a time
+pub struct DiffIter<L, R, B>
+where
+    L: Iterator<Item = Result<TreeItem>>,
+    R: Iterator<Item = Result<TreeItem>>,
+{
+    left_iter: Peekable<L>,
+    right_iter: Peekable<R>,
+    base: B,
+}
+
+impl<L, R, B> DiffIter<L, R, B>
 where
-    I: Iterator<Item = TreeItem> + ExactSizeIterator,
+    L: Iterator<Item = Result<TreeItem>>,
+    R: Iterator<Item = Result<TreeItem>>,
+    B: DiffBase,
 {
-    let mut file_diffs: Vec<FileDiff> = Vec::with_capacity(left_iter.len().max(right_iter.len()));
-
-    while let (Some(left), Some(right)) = (left_iter.peek(), right_iter.peek()) {
-        // Compare the keys
-        match (left, right) {
-            // Right is smaller, so that means this exists only there
-            ((left_path, _), (right_path, right_data)) if left_path > right_path => {
-                // Remote is smaller, so that means this exists only there
-                file_diffs.push(FileDiff::right_create(
-                    right_path.clone(),
-                    right_data.clone(),
-                ));
-                right_iter.next();
-                continue;
+    pub fn new(left_iter: L, right_iter: R, base: B) -> Self {
+        Self {
+            left_iter: left_iter.peekable(),
+            right_iter: right_iter.peekable(),
+            base,
+        }
+    }
+}
+
+impl<L, R, B> Iterator for DiffIter<L, R, B>
+where
+    L: Iterator<Item = Result<TreeItem>>,
+    R: Iterator<Item = Result<TreeItem>>,
+    B: DiffBase,
+{
+    type Item = Result<FileDiff>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Surface decode errors as soon as they're at the front
+            if let Some(Err(err)) = self.left_iter.next_if(|item| item.is_err()) {
+                return Some(Err(err));
             }
-            // Left is smallser, so this exists only there
-            ((left_path, left_data), (right_path, _)) if left_path < right_path => {
-                // Local is smaller
-                file_diffs.push(FileDiff::left_create(left_path.clone(), left_data.clone()));
-                left_iter.next();
-                continue;
+            if let Some(Err(err)) = self.right_iter.next_if(|item| item.is_err()) {
+                return Some(Err(err));
             }
-            // If their keys are the same & their hashes are different,
-            //  check their metadata to see who is newer
-            // Figure out which it is,  advance both iterators and continue
-            ((left_path, left_data), (right_path, right_data))
-                if left_path == right_path && left_data.hash != right_data.hash =>
-            {
-                // Figure out which is newer
-                match (&left_data.metadata, &right_data.metadata) {
-                    // Local time is greater (left is newer)
-                    (left_metadata, right_metadata)
-                        if left_metadata.modified > right_metadata.modified =>
-                    {
-                        file_diffs.push(FileDiff::left_newer(
-                            left_path.clone(),
-                            left_data.clone(),
-                            right_data.clone(),
-                        ));
-                    }
-                    // Remote time is greater (right is newer)
-                    (left_metadata, right_metadata)
-                        if left_metadata.modified < right_metadata.modified =>
-                    {
-                        file_diffs.push(FileDiff::right_newer(
-                            right_path.clone(),
-                            left_data.clone(),
-                            right_data.clone(),
-                        ));
-                    }
-                    // Both times are equal - merge conflict
-                    (_, _) => {
-                        return Err(
-                            "Found merge conflict - same key, different hash, same modified time"
-                                .to_owned()
-                                .into(),
-                        )
+
+            let ordering = match (self.left_iter.peek(), self.right_iter.peek()) {
+                (Some(Ok((left_path, _))), Some(Ok((right_path, _)))) => left_path.cmp(right_path),
+                // Only one side left, clean up residuals
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => return None,
+                // Errors were taken above
+                _ => unreachable!(),
+            };
+
+            match ordering {
+                // Left is smaller, so this exists only there
+                Ordering::Less => {
+                    let (left_path, left_data) = self.left_iter.next()?.ok()?;
+                    return Some(left_only(left_path, left_data, &self.base));
+                }
+                // Right is smaller, so that means this exists only there
+                Ordering::Greater => {
+                    let (right_path, right_data) = self.right_iter.next()?.ok()?;
+                    return Some(right_only(right_path, right_data, &self.base));
+                }
+                Ordering::Equal => {
+                    let (path, left_data) = self.left_iter.next()?.ok()?;
+                    let (_, right_data) = self.right_iter.next()?.ok()?;
+
+                    match left_data.same_contents(&right_data) {
+                        // Keys are the same and contents are the same, nothing happens.
+                        Some(true) => continue,
+                        Some(false) => return Some(Ok(both_sides(path, left_data, right_data))),
+                        None => {
+                            return Some(Ok(FileDiff::conflict(
+                                path,
+                                left_data,
+                                right_data,
+                                ConflictReason::HashesNotComparable,
+                            )))
+                        }
                     }
-                };
-                left_iter.next();
-                right_iter.next();
-                continue;
+                }
             }
-            // Keys are the same and hashes are the same, nothing happens.
-            (left, right) => {
-                assert_eq!(left.0, right.0);
-                assert_eq!(left.1.hash, right.1.hash);
-                println!("Same file name and same hash");
-                left_iter.next();
-                right_iter.next();
-                continue;
+        }
+    }
+}
+
+/// Both sides have the path but with different hashes, check their metadata to see who is newer
+fn both_sides(path: ProjectPath, left_data: LocalFileData, right_data: LocalFileData) -> FileDiff {
+    match (&left_data.metadata, &right_data.metadata) {
+        // Local time is greater (left is newer)
+        (left_metadata, right_metadata) if left_metadata.modified > right_metadata.modified => {
+            FileDiff::left_newer(path, left_data, right_data)
+        }
+        // Remote time is greater (right is newer)
+        (left_metadata, right_metadata) if left_metadata.modified < right_metadata.modified => {
+            FileDiff::right_newer(path, left_data, right_data)
+        }
+        // Both times are equal - merge conflict, record it and keep going
+        (_, _) => FileDiff::conflict(
+            path,
+            left_data,
+            right_data,
+            ConflictReason::SameModifiedTime,
+        ),
+    }
+}
+
+/// A path only the left has. If it was in HEAD, the right deleted it.
+fn left_only(
+    path: ProjectPath,
+    left_data: LocalFileData,
+    base: &impl DiffBase,
+) -> Result<FileDiff> {
+    let head_data = match base.head(&path)? {
+        Some(head_data) => head_data,
+        None => return Ok(FileDiff::left_create(path, left_data)),
+    };
+
+    Ok(match head_data.same_contents(&left_data) {
+        Some(true) => FileDiff::right_delete(path, left_data),
+        // Right deleted it, but left changed it since HEAD
+        Some(false) => FileDiff::conflict(
+            path,
+            left_data,
+            head_data,
+            ConflictReason::LeftModifiedRightDeleted,
+        ),
+        None => FileDiff::conflict(
+            path,
+            left_data,
+            head_data,
+            ConflictReason::HashesNotComparable,
+        ),
+    })
+}
+
+/// A path only the right has. If we have a tombstone for it or it was in HEAD, the left deleted it.
+fn right_only(
+    path: ProjectPath,
+    right_data: LocalFileData,
+    base: &impl DiffBase,
+) -> Result<FileDiff> {
+    let base_data = match base.left_tombstone(&path)? {
+        Some(tombstone) => Some(tombstone),
+        None => base.head(&path)?,
+    };
+
+    let base_data = match base_data {
+        Some(base_data) => base_data,
+        None => return Ok(FileDiff::right_create(path, right_data)),
+    };
+
+    Ok(match base_data.same_contents(&right_data) {
+        Some(true) => FileDiff::left_delete(path, right_data),
+        // Left deleted it, but right changed it since
+        Some(false) => FileDiff::conflict(
+            path,
+            base_data,
+            right_data,
+            ConflictReason::LeftDeletedRightModified,
+        ),
+        None => FileDiff::conflict(
+            path,
+            base_data,
+            right_data,
+            ConflictReason::HashesNotComparable,
+        ),
+    })
+}
+
+/// Three-way comparison of the local, HEAD and remote states.
+///
+/// HEAD is the merge base, so unlike `compare_trees` this can tell a local
+/// change from a remote one without looking at modified times.
+pub fn compare_three_trees(
+    local_tree_name: impl AsRef<str>,
+    head_tree_name: impl AsRef<str>,
+    remote_tree_name: impl AsRef<str>,
+    db: &dyn MetadataStore,
+) -> Result<Vec<ThreeWayDiff>> {
+    let local_tree = DataTree::open(db, local_tree_name)?;
+    let head_tree = DataTree::open(db, head_tree_name)?;
+    let remote_tree = DataTree::open(db, remote_tree_name)?;
+
+    ThreeWayDiffIter::new(local_tree.iter(), head_tree.iter(), remote_tree.iter()).collect()
+}
+
+/// Walk all three sorted iterators at once, classifying every path seen in any of them.
+pub fn find_three_way_diffs<I>(
+    local_iter: Peekable<I>,
+    head_iter: Peekable<I>,
+    remote_iter: Peekable<I>,
+) -> Vec<ThreeWayDiff>
+where
+    I: Iterator<Item = TreeItem>,
+{
+    ThreeWayDiffIter::new(local_iter.map(Ok), head_iter.map(Ok), remote_iter.map(Ok))
+        .filter_map(|diff| diff.ok())
+        .collect()
+}
+
+/// Three-way merge-join, yielding one classified path at a time
+pub struct ThreeWayDiffIter<I>
+where
+    I: Iterator<Item = Result<TreeItem>>,
+{
+    local_iter: Peekable<I>,
+    head_iter: Peekable<I>,
+    remote_iter: Peekable<I>,
+}
+
+impl<I> ThreeWayDiffIter<I>
+where
+    I: Iterator<Item = Result<TreeItem>>,
+{
+    pub fn new(local_iter: I, head_iter: I, remote_iter: I) -> Self {
+        Self {
+            local_iter: local_iter.peekable(),
+            head_iter: head_iter.peekable(),
+            remote_iter: remote_iter.peekable(),
+        }
+    }
+}
+
+impl<I> Iterator for ThreeWayDiffIter<I>
+where
+    I: Iterator<Item = Result<TreeItem>>,
+{
+    type Item = Result<ThreeWayDiff>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for iter in [&mut self.local_iter, &mut self.head_iter, &mut self.remote_iter] {
+            if let Some(Err(err)) = iter.next_if(|item| item.is_err()) {
+                return Some(Err(err));
             }
+        }
+
+        // The smallest key across all three is the next path to classify
+        let path = [
+            self.local_iter.peek(),
+            self.head_iter.peek(),
+            self.remote_iter.peek(),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.as_ref().ok())
+        .map(|(path, _)| path)
+        .min()?
+        .clone();
+
+        let take = |iter: &mut Peekable<I>| {
+            iter.next_if(|item| matches!(item, Ok((key, _)) if key == &path))
+                .and_then(|item| item.ok())
+                .map(|(_, data)| data)
         };
+        let local = take(&mut self.local_iter);
+        let head = take(&mut self.head_iter);
+        let remote = take(&mut self.remote_iter);
+
+        let diff_type = classify_three_way(local.as_ref(), head.as_ref(), remote.as_ref());
+        Some(Ok(ThreeWayDiff::new(path, local, head, remote, diff_type)))
     }
+}
 
-    // Clean up residuals
-    file_diffs.extend(left_iter.map(|(path, data)| FileDiff::left_create(path, data)));
-    file_diffs.extend(right_iter.map(|(path, data)| FileDiff::right_create(path, data)));
+/// Decide what happened to a single path, using HEAD as the merge base.
+/// Only hashes are compared. Ones from different algorithms count as a change, so the
+/// file is shown to someone rather than passed over.
+pub fn classify_three_way(
+    local: Option<&LocalFileData>,
+    head: Option<&LocalFileData>,
+    remote: Option<&LocalFileData>,
+) -> ThreeWayDiffTypes {
+    let same = |a: &LocalFileData, b: &LocalFileData| a.same_contents(b) == Some(true);
 
-    Ok(file_diffs)
+    match (local, head, remote) {
+        (Some(local), Some(head), Some(remote)) => {
+            match (same(local, head), same(remote, head)) {
+                (true, true) => ThreeWayDiffTypes::Unchanged,
+                (false, true) => ThreeWayDiffTypes::LocalModified,
+                (true, false) => ThreeWayDiffTypes::RemoteModified,
+                // Both sides made the exact same change, nothing to do
+                (false, false) if same(local, remote) => ThreeWayDiffTypes::Unchanged,
+                (false, false) => ThreeWayDiffTypes::BothModified,
+            }
+        }
+        // Deleted here, untouched there
+        (None, Some(head), Some(remote)) if same(remote, head) => ThreeWayDiffTypes::LocalDeleted,
+        (Some(local), Some(head), None) if same(local, head) => ThreeWayDiffTypes::RemoteDeleted,
+        // Deleted on one side but edited on the other
+        (None, Some(_), Some(_)) | (Some(_), Some(_), None) => ThreeWayDiffTypes::BothModified,
+        // Both sides agree the file is gone
+        (None, Some(_), None) => ThreeWayDiffTypes::Unchanged,
+        (Some(_), None, None) => ThreeWayDiffTypes::LocalAdded,
+        (None, None, Some(_)) => ThreeWayDiffTypes::RemoteAdded,
+        // Added on both sides, only fine if it's the same content
+        (Some(local), None, Some(remote)) if same(local, remote) => ThreeWayDiffTypes::Unchanged,
+        (Some(_), None, Some(_)) => ThreeWayDiffTypes::BothModified,
+        (None, None, None) => unreachable!("Path must exist in at least one tree"),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::collections::BTreeMap;
 
     use chrono::{TimeZone, Utc};
 
-    use crate::db::{types::{TreeItem, LocalFileData, LocalFileMetadata, FileDiff, DiffTypes, FileDiffData}, compare::find_diffs};
+    use crate::db::{content_hash::ContentHash, types::{TreeItem, LocalFileData, LocalFileMetadata, FileDiff, DiffTypes, FileDiffData, ThreeWayDiffTypes, ConflictReason}, compare::{compare_trees, find_diffs, find_diffs_with_base, find_three_way_diffs, stream_trees, DiffBase, NoBase, TreeDiffBase}, project_path::ProjectPath, test_support::path, typed_tree::TypedTree, memory_store::MemoryStore};
+    use crate::error::{Error, Result};
+
+    #[derive(Default)]
+    struct MapDiffBase {
+        head: BTreeMap<ProjectPath, LocalFileData>,
+        tombstones: BTreeMap<ProjectPath, LocalFileData>,
+    }
+
+    impl DiffBase for MapDiffBase {
+        fn head(&self, path: &ProjectPath) -> Result<Option<LocalFileData>> {
+            Ok(self.head.get(path).cloned())
+        }
+
+        fn left_tombstone(&self, path: &ProjectPath) -> Result<Option<LocalFileData>> {
+            Ok(self.tombstones.get(path).cloned())
+        }
+    }
+
+    fn file_data(file_path: &str, hash: u128) -> TreeItem {
+        (
+            path(file_path),
+            LocalFileData {
+                hash: ContentHash::xxh3_128(hash),
+                strong_hash: None,
+                name: path(file_path).file_name().unwrap().to_owned(),
+                metadata: LocalFileMetadata {
+                    path: path(file_path),
+                    modified: Utc.timestamp(100, 0),
+                    size: 1,
+                    update_time: Utc.timestamp(100, 0),
+                },
+            },
+        )
+    }
 
 
     #[test]
     fn test_merge_no_diff() {
         let left: Vec<TreeItem> = vec![(
-            PathBuf::from("/this/is/in/both"),
+            path("this/is/in/both"),
             LocalFileData {
-                hash: 0,
+                hash: ContentHash::xxh3_128(0),
+                strong_hash: None,
                 name: "eee".to_owned(),
                 metadata: LocalFileMetadata {
-                    path: PathBuf::from("/this/is/in/both"),
+                    path: path("this/is/in/both"),
                     modified: Utc.timestamp(100, 0),
                     size: 1,
                     update_time: Utc.timestamp(100, 0),
@@ -174,12 +560,13 @@ mod tests {
             },
         )];
         let right: Vec<TreeItem> = vec![(
-            PathBuf::from("/this/is/in/both"),
+            path("this/is/in/both"),
             LocalFileData {
-                hash: 0,
+                hash: ContentHash::xxh3_128(0),
+                strong_hash: None,
                 name: "eee".to_owned(),
                 metadata: LocalFileMetadata {
-                    path: PathBuf::from("/this/is/in/both"),
+                    path: path("this/is/in/both"),
                     modified: Utc.timestamp(100, 0),
                     size: 1,
                     update_time: Utc.timestamp(100, 0),
@@ -200,12 +587,13 @@ mod tests {
     #[test]
     fn test_both_new() {
         let left: Vec<TreeItem> = vec![(
-            PathBuf::from("/this/is/in/left"),
+            path("this/is/in/left"),
             LocalFileData {
-                hash: 0,
+                hash: ContentHash::xxh3_128(0),
+                strong_hash: None,
                 name: "eee".to_owned(),
                 metadata: LocalFileMetadata {
-                    path: PathBuf::from("/this/is/in/left"),
+                    path: path("this/is/in/left"),
                     modified: Utc.timestamp(100, 0),
                     size: 1,
                     update_time: Utc.timestamp(100, 0),
@@ -213,12 +601,13 @@ mod tests {
             },
         )];
         let right: Vec<TreeItem> = vec![(
-            PathBuf::from("/this/is/in/right"),
+            path("this/is/in/right"),
             LocalFileData {
-                hash: 2,
+                hash: ContentHash::xxh3_128(2),
+                strong_hash: None,
                 name: "ee2".to_owned(),
                 metadata: LocalFileMetadata {
-                    path: PathBuf::from("/this/is/in/right"),
+                    path: path("this/is/in/right"),
                     modified: Utc.timestamp(100, 0),
                     size: 1,
                     update_time: Utc.timestamp(100, 0),
@@ -227,12 +616,13 @@ mod tests {
         )];
         let mut expected: Vec<FileDiff> = vec![
             FileDiff::left_create(
-                PathBuf::from("/this/is/in/left"),
+                path("this/is/in/left"),
                 LocalFileData {
-                    hash: 0,
+                    hash: ContentHash::xxh3_128(0),
+                    strong_hash: None,
                     name: "eee".to_owned(),
                     metadata: LocalFileMetadata {
-                        path: PathBuf::from("/this/is/in/left"),
+                        path: path("this/is/in/left"),
                         modified: Utc.timestamp(100, 0),
                         size: 1,
                         update_time: Utc.timestamp(100, 0),
@@ -240,12 +630,13 @@ mod tests {
                 },
             ),
             FileDiff::right_create(
-                PathBuf::from("/this/is/in/right"),
+                path("this/is/in/right"),
                 LocalFileData {
-                    hash: 2,
+                    hash: ContentHash::xxh3_128(2),
+                    strong_hash: None,
                     name: "ee2".to_owned(),
                     metadata: LocalFileMetadata {
-                        path: PathBuf::from("/this/is/in/right"),
+                        path: path("this/is/in/right"),
                         modified: Utc.timestamp(100, 0),
                         size: 1,
                         update_time: Utc.timestamp(100, 0),
@@ -266,12 +657,13 @@ mod tests {
     #[test]
     fn test_right_newer() {
         let left: Vec<TreeItem> = vec![(
-            PathBuf::from("/this/is/in/both"),
+            path("this/is/in/both"),
             LocalFileData {
-                hash: 0,
+                hash: ContentHash::xxh3_128(0),
+                strong_hash: None,
                 name: "eee".to_owned(),
                 metadata: LocalFileMetadata {
-                    path: PathBuf::from("/this/is/in/both"),
+                    path: path("this/is/in/both"),
                     modified: Utc.timestamp(100, 0),
                     size: 1,
                     update_time: Utc::now(),
@@ -279,12 +671,13 @@ mod tests {
             },
         )];
         let right: Vec<TreeItem> = vec![(
-            PathBuf::from("/this/is/in/both"),
+            path("this/is/in/both"),
             LocalFileData {
-                hash: 2,
+                hash: ContentHash::xxh3_128(2),
+                strong_hash: None,
                 name: "eee".to_owned(),
                 metadata: LocalFileMetadata {
-                    path: PathBuf::from("/this/is/in/both"),
+                    path: path("this/is/in/both"),
                     modified: Utc.timestamp(102, 0),
                     size: 1,
                     update_time: Utc::now(),
@@ -292,24 +685,26 @@ mod tests {
             },
         )];
         let mut expected: Vec<FileDiff> = vec![FileDiff {
-            path: PathBuf::from("/this/is/in/both"),
+            path: path("this/is/in/both"),
             diff_type: DiffTypes::RightNewer,
             diff_metadata: FileDiffData::Both(
                 LocalFileData {
-                    hash: 0,
+                    hash: ContentHash::xxh3_128(0),
+                    strong_hash: None,
                     name: "eee".to_owned(),
                     metadata: LocalFileMetadata {
-                        path: PathBuf::from("/this/is/in/both"),
+                        path: path("this/is/in/both"),
                         modified: Utc.timestamp(100, 0),
                         size: 1,
                         update_time: Utc::now(),
                     },
                 },
                 LocalFileData {
-                    hash: 2,
+                    hash: ContentHash::xxh3_128(2),
+                    strong_hash: None,
                     name: "eee".to_owned(),
                     metadata: LocalFileMetadata {
-                        path: PathBuf::from("/this/is/in/both"),
+                        path: path("this/is/in/both"),
                         modified: Utc.timestamp(102, 0),
                         size: 1,
                         update_time: Utc::now(),
@@ -326,4 +721,253 @@ mod tests {
 
         assert_eq!(expected, res);
     }
+
+    #[test]
+    fn test_three_way_classification() {
+        let local: Vec<TreeItem> = vec![
+            file_data("both/changed", 1),
+            file_data("local/added", 5),
+            file_data("local/changed", 1),
+            file_data("remote/changed", 0),
+            file_data("remote/deleted", 0),
+            file_data("untouched", 0),
+        ];
+        let head: Vec<TreeItem> = vec![
+            file_data("both/changed", 0),
+            file_data("local/changed", 0),
+            file_data("local/deleted", 0),
+            file_data("remote/changed", 0),
+            file_data("remote/deleted", 0),
+            file_data("untouched", 0),
+        ];
+        let remote: Vec<TreeItem> = vec![
+            file_data("both/changed", 2),
+            file_data("local/changed", 0),
+            file_data("local/deleted", 0),
+            file_data("remote/added", 6),
+            file_data("remote/changed", 1),
+            file_data("untouched", 0),
+        ];
+
+        let res = find_three_way_diffs(
+            local.into_iter().peekable(),
+            head.into_iter().peekable(),
+            remote.into_iter().peekable(),
+        );
+        let res: Vec<(ProjectPath, ThreeWayDiffTypes)> = res
+            .into_iter()
+            .map(|diff| (diff.path, diff.diff_type))
+            .collect();
+
+        let expected = vec![
+            (path("both/changed"), ThreeWayDiffTypes::BothModified),
+            (path("local/added"), ThreeWayDiffTypes::LocalAdded),
+            (path("local/changed"), ThreeWayDiffTypes::LocalModified),
+            (path("local/deleted"), ThreeWayDiffTypes::LocalDeleted),
+            (path("remote/added"), ThreeWayDiffTypes::RemoteAdded),
+            (path("remote/changed"), ThreeWayDiffTypes::RemoteModified),
+            (path("remote/deleted"), ThreeWayDiffTypes::RemoteDeleted),
+            (path("untouched"), ThreeWayDiffTypes::Unchanged),
+        ];
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_three_way_delete_edit_conflict() {
+        let local: Vec<TreeItem> = vec![file_data("edited/remotely", 0)];
+        let head: Vec<TreeItem> = vec![
+            file_data("edited/locally", 0),
+            file_data("edited/remotely", 0),
+        ];
+        let remote: Vec<TreeItem> = vec![
+            file_data("edited/locally", 1),
+            file_data("same/add", 3),
+        ];
+
+        let res = find_three_way_diffs(
+            local.into_iter().peekable(),
+            head.into_iter().peekable(),
+            remote.into_iter().peekable(),
+        );
+        let res: Vec<(ProjectPath, ThreeWayDiffTypes)> = res
+            .into_iter()
+            .map(|diff| (diff.path, diff.diff_type))
+            .collect();
+
+        let expected = vec![
+            (path("edited/locally"), ThreeWayDiffTypes::BothModified),
+            (path("edited/remotely"), ThreeWayDiffTypes::RemoteDeleted),
+            (path("same/add"), ThreeWayDiffTypes::RemoteAdded),
+        ];
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_conflict_does_not_abort() {
+        let left: Vec<TreeItem> = vec![
+            file_data("a/conflict", 0),
+            file_data("b/left/only", 1),
+            file_data("c/conflict", 2),
+        ];
+        let right: Vec<TreeItem> = vec![
+            file_data("a/conflict", 3),
+            file_data("c/conflict", 4),
+            file_data("d/right/only", 5),
+        ];
+
+        let mut expected: Vec<FileDiff> = vec![
+            FileDiff::conflict(
+                path("a/conflict"),
+                file_data("a/conflict", 0).1,
+                file_data("a/conflict", 3).1,
+                ConflictReason::SameModifiedTime,
+            ),
+            FileDiff::left_create(path("b/left/only"), file_data("b/left/only", 1).1),
+            FileDiff::conflict(
+                path("c/conflict"),
+                file_data("c/conflict", 2).1,
+                file_data("c/conflict", 4).1,
+                ConflictReason::SameModifiedTime,
+            ),
+            FileDiff::right_create(path("d/right/only"), file_data("d/right/only", 5).1),
+        ];
+
+        let mut res =
+            find_diffs(left.into_iter().peekable(), right.into_iter().peekable()).unwrap();
+
+        expected.sort();
+        res.sort();
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_deletes_against_head() {
+        let left: Vec<TreeItem> = vec![
+            file_data("deleted/remotely", 0),
+            file_data("new/locally", 1),
+        ];
+        let right: Vec<TreeItem> = vec![
+            file_data("deleted/locally", 2),
+            file_data("new/remotely", 3),
+        ];
+        let mut base = MapDiffBase::default();
+        base.head.extend(vec![
+            file_data("deleted/locally", 2),
+            file_data("deleted/remotely", 0),
+        ]);
+
+        let mut expected: Vec<FileDiff> = vec![
+            FileDiff::left_delete(path("deleted/locally"), file_data("deleted/locally", 2).1),
+            FileDiff::right_delete(path("deleted/remotely"), file_data("deleted/remotely", 0).1),
+            FileDiff::left_create(path("new/locally"), file_data("new/locally", 1).1),
+            FileDiff::right_create(path("new/remotely"), file_data("new/remotely", 3).1),
+        ];
+
+        let mut res = find_diffs_with_base(
+            left.into_iter().peekable(),
+            right.into_iter().peekable(),
+            &base,
+        )
+        .unwrap();
+
+        expected.sort();
+        res.sort();
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_deletes_against_tombstones() {
+        let left: Vec<TreeItem> = vec![file_data("modified/then/deleted", 4)];
+        let right: Vec<TreeItem> = vec![
+            file_data("deleted/locally", 2),
+            file_data("edited/remotely", 5),
+        ];
+        let mut base = MapDiffBase::default();
+        base.head.insert(path("modified/then/deleted"), file_data("modified/then/deleted", 0).1);
+        base.tombstones.extend(vec![
+            file_data("deleted/locally", 2),
+            file_data("edited/remotely", 1),
+        ]);
+
+        let mut expected: Vec<FileDiff> = vec![
+            FileDiff::left_delete(path("deleted/locally"), file_data("deleted/locally", 2).1),
+            FileDiff::conflict(
+                path("edited/remotely"),
+                file_data("edited/remotely", 1).1,
+                file_data("edited/remotely", 5).1,
+                ConflictReason::LeftDeletedRightModified,
+            ),
+            FileDiff::conflict(
+                path("modified/then/deleted"),
+                file_data("modified/then/deleted", 4).1,
+                file_data("modified/then/deleted", 0).1,
+                ConflictReason::LeftModifiedRightDeleted,
+            ),
+        ];
+
+        let mut res = find_diffs_with_base(
+            left.into_iter().peekable(),
+            right.into_iter().peekable(),
+            &base,
+        )
+        .unwrap();
+
+        expected.sort();
+        res.sort();
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_stream_trees_in_path_order() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let left: TypedTree<ProjectPath, LocalFileData> = TypedTree::open(&db, "left").unwrap();
+        let right: TypedTree<ProjectPath, LocalFileData> = TypedTree::open(&db, "right").unwrap();
+
+        for (key, data) in [
+            file_data("a/b", 1),
+            file_data("a.txt", 2),
+            file_data("ab", 3),
+        ] {
+            left.insert(&key, &data).unwrap();
+        }
+        for (key, data) in [file_data("a/c", 4), file_data("ab", 3)] {
+            right.insert(&key, &data).unwrap();
+        }
+
+        let res: Vec<FileDiff> = stream_trees("left", "right", None, NoBase, &db)
+            .unwrap()
+            .collect::<crate::error::Result<_>>()
+            .unwrap();
+
+        let expected = vec![
+            FileDiff::left_create(path("a/b"), file_data("a/b", 1).1),
+            FileDiff::right_create(path("a/c"), file_data("a/c", 4).1),
+            FileDiff::left_create(path("a.txt"), file_data("a.txt", 2).1),
+        ];
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_unreadable_base_is_an_error() {
+        let db = MemoryStore::default();
+        let left: TypedTree<ProjectPath, LocalFileData> = TypedTree::open(&db, "left").unwrap();
+        let (key, data) = file_data("bolt.sldprt", 1);
+        left.insert(&key, &data).unwrap();
+        TypedTree::<ProjectPath, LocalFileData>::open(&db, "head")
+            .unwrap()
+            .inner()
+            .insert(b"bolt.sldprt", b"junk".to_vec())
+            .unwrap();
+
+        // Not a create, which is what a missing HEAD entry would have made it
+        let base = TreeDiffBase::open("head", "tombstones", &db).unwrap();
+        let res = compare_trees("left", "right", None, &base, &db);
+        assert!(matches!(res, Err(Error::CorruptRecord { .. })), "{:?}", res);
+    }
 }
@@ -0,0 +1,170 @@
+//! Hashes of file contents that remember which algorithm made them.
+//!
+//! Every file gets a fast xxh3-128 hash, which is all local change detection needs.
+//! A cryptographic hash (BLAKE3 or SHA-256) can be turned on too, for the server to
+//! check integrity and dedup against. Hashes serialize as `algorithm:hex`, e.g.
+//! `xxh3-128:00ff...`, so the frontend and the server can tell them apart.
+
+use std::{convert::TryFrom, fmt::Display, str::FromStr};
+
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    // What hashes were before they were tagged, only found in migrated data
+    Xxh3_64,
+    Xxh3_128,
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Xxh3_64 => "xxh3-64",
+            HashAlgorithm::Xxh3_128 => "xxh3-128",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// Digest length in bytes
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Xxh3_64 => 8,
+            HashAlgorithm::Xxh3_128 => 16,
+            HashAlgorithm::Blake3 | HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    pub fn is_cryptographic(&self) -> bool {
+        matches!(self, HashAlgorithm::Blake3 | HashAlgorithm::Sha256)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Ok(match name {
+            "xxh3-64" => HashAlgorithm::Xxh3_64,
+            "xxh3-128" => HashAlgorithm::Xxh3_128,
+            "blake3" => HashAlgorithm::Blake3,
+            "sha256" => HashAlgorithm::Sha256,
+            _ => return Err(format!("Unknown hash algorithm {}", name).into()),
+        })
+    }
+}
+
+/// A digest and the algorithm that produced it. Hashes from different
+/// algorithms never compare equal, even for the same content.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ContentHash {
+    algorithm: HashAlgorithm,
+    digest: Vec<u8>,
+}
+
+impl ContentHash {
+    pub fn new(algorithm: HashAlgorithm, digest: Vec<u8>) -> Result<Self> {
+        if digest.len() != algorithm.digest_len() {
+            return Err(format!(
+                "A {} digest is {} bytes, not {}",
+                algorithm.name(),
+                algorithm.digest_len(),
+                digest.len()
+            )
+            .into());
+        }
+
+        Ok(Self { algorithm, digest })
+    }
+
+    pub fn xxh3_128(hash: u128) -> Self {
+        Self {
+            algorithm: HashAlgorithm::Xxh3_128,
+            digest: hash.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn xxh3_64(hash: u64) -> Self {
+        Self {
+            algorithm: HashAlgorithm::Xxh3_64,
+            digest: hash.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn digest(&self) -> &[u8] {
+        &self.digest
+    }
+
+    pub fn to_hex(&self) -> String {
+        HEXLOWER.encode(&self.digest)
+    }
+}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.to_hex())
+    }
+}
+
+impl FromStr for ContentHash {
+    type Err = Error;
+
+    fn from_str(hash: &str) -> Result<Self> {
+        let (algorithm, digest) = hash
+            .split_once(':')
+            .ok_or_else(|| format!("Hash {} has no algorithm", hash))?;
+
+        let digest = HEXLOWER
+            .decode(digest.as_bytes())
+            .map_err(|err| format!("Hash {} isn't valid hex: {}", hash, err))?;
+
+        Self::new(algorithm.parse()?, digest)
+    }
+}
+
+impl TryFrom<String> for ContentHash {
+    type Error = Error;
+
+    fn try_from(hash: String) -> Result<Self> {
+        hash.parse()
+    }
+}
+
+impl From<ContentHash> for String {
+    fn from(hash: ContentHash) -> Self {
+        hash.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContentHash, HashAlgorithm};
+
+    #[test]
+    fn test_string_round_trip() {
+        let hash = ContentHash::xxh3_128(0xff);
+
+        let string = hash.to_string();
+
+        assert_eq!("xxh3-128:000000000000000000000000000000ff", string);
+        assert_eq!(hash, string.parse().unwrap());
+        assert!("xxh3-128:ff".parse::<ContentHash>().is_err());
+        assert!("md5:00".parse::<ContentHash>().is_err());
+    }
+
+    #[test]
+    fn test_algorithms_never_equal() {
+        assert_ne!(ContentHash::xxh3_64(1), ContentHash::xxh3_128(1));
+        assert_eq!(HashAlgorithm::Xxh3_64, ContentHash::xxh3_64(1).algorithm());
+    }
+}
@@ -0,0 +1,313 @@
+//! Hashing file contents without holding whole files in memory.
+//!
+//! Files are streamed through a fixed-size buffer into an xxh3 state, which gives the
+//! same hash as hashing the whole file at once. Every hash in flight holds one buffer's
+//! worth of a global budget, so however many files are queued up and however big they
+//! are, hashing never holds more than `HASH_MEMORY_BUDGET` at a time.
+
+use std::{
+    fs::File,
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use memmap2::MmapOptions;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    db::{
+        content_hash::{ContentHash, HashAlgorithm},
+        record::{decode, encode},
+        store::MetadataStore,
+    },
+    error::Result,
+};
+
+pub const HASH_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+pub const HASH_MEMORY_BUDGET: usize = 256 * 1024 * 1024;
+
+const HASH_MODE_KEY: &[u8] = b"hashMode";
+const STRONG_HASH_KEY: &[u8] = b"strongHash";
+
+// One permit per buffer that fits in the budget
+static HASH_BUFFERS: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(HASH_MEMORY_BUDGET / HASH_BUFFER_SIZE));
+
+/// How file contents get to the hasher
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HashMode {
+    // Read through a fixed-size buffer
+    #[default]
+    Buffered,
+    // Memory-map the file a buffer's worth at a time. Faster on local disks, but a file
+    // truncated while it's mapped can take the whole app down, so it's opt-in.
+    Mmap,
+}
+
+/// Everything from the preferences that decides how files get hashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HashSettings {
+    pub mode: HashMode,
+    // The cryptographic hash to compute next to xxh3-128, if any
+    pub strong: Option<HashAlgorithm>,
+}
+
+/// The hash mode set in the preferences, `Buffered` if it was never set
+pub fn hash_mode(db: &dyn MetadataStore) -> Result<HashMode> {
+    let prefs = db.open_tree("preferences")?;
+
+    Ok(match prefs.get(HASH_MODE_KEY)? {
+        Some(mode) => decode(&mode)?,
+        None => HashMode::default(),
+    })
+}
+
+pub fn set_hash_mode(db: &dyn MetadataStore, mode: HashMode) -> Result<()> {
+    let prefs = db.open_tree("preferences")?;
+    prefs.insert(HASH_MODE_KEY, encode(&mode)?)?;
+    Ok(())
+}
+
+/// The strong hash set in the preferences, none if it was never set
+pub fn strong_hash(db: &dyn MetadataStore) -> Result<Option<HashAlgorithm>> {
+    let prefs = db.open_tree("preferences")?;
+
+    Ok(match prefs.get(STRONG_HASH_KEY)? {
+        Some(algorithm) => decode(&algorithm)?,
+        None => None,
+    })
+}
+
+pub fn set_strong_hash(db: &dyn MetadataStore, algorithm: Option<HashAlgorithm>) -> Result<()> {
+    if let Some(algorithm) = algorithm {
+        if !algorithm.is_cryptographic() {
+            return Err(format!("{} isn't a cryptographic hash", algorithm.name()).into());
+        }
+    }
+
+    let prefs = db.open_tree("preferences")?;
+    prefs.insert(STRONG_HASH_KEY, encode(&algorithm)?)?;
+    Ok(())
+}
+
+pub fn hash_settings(db: &dyn MetadataStore) -> Result<HashSettings> {
+    Ok(HashSettings {
+        mode: hash_mode(db)?,
+        strong: strong_hash(db)?,
+    })
+}
+
+/// The hashes of one file's contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHashes {
+    pub hash: ContentHash,
+    pub strong_hash: Option<ContentHash>,
+}
+
+/// Wait until `bytes` fit in the memory budget, and hold them until the permit is dropped.
+/// Rounded up to whole buffers.
+pub async fn reserve_memory(bytes: usize) -> Result<SemaphorePermit<'static>> {
+    let buffers = bytes.div_ceil(HASH_BUFFER_SIZE).max(1) as u32;
+
+    Ok(HASH_BUFFERS
+        .acquire_many(buffers)
+        .await
+        .map_err(|err| format!("Hash budget closed: {}", err))?)
+}
+
+/// Hash a file, waiting for room in the memory budget first
+pub async fn hash_file(path: PathBuf, settings: HashSettings) -> Result<FileHashes> {
+    let _permit = reserve_memory(HASH_BUFFER_SIZE).await?;
+
+    tokio::task::spawn_blocking(move || hash_path(&path, settings))
+        .await
+        .map_err(|err| format!("Hashing task failed: {}", err))?
+}
+
+fn hash_path(path: &Path, settings: HashSettings) -> Result<FileHashes> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    match settings.mode {
+        // Empty files can't be mapped
+        HashMode::Mmap if len > 0 => hash_mapped(&file, len, settings.strong),
+        _ => {
+            // No point in a big buffer for a small file
+            let mut buffer = vec![0; (len as usize).clamp(1, HASH_BUFFER_SIZE)];
+            hash_reader(file, &mut buffer, settings.strong)
+        }
+    }
+}
+
+/// Hash `file` through one `HASH_BUFFER_SIZE` window of it mapped at a time, so the mapped
+/// pages stay within the permit the caller holds
+fn hash_mapped(file: &File, len: u64, strong: Option<HashAlgorithm>) -> Result<FileHashes> {
+    let mut hasher = ContentHasher::new(strong)?;
+
+    let mut offset = 0;
+    while offset < len {
+        // A file that shrank would fault on the pages past its end, so check first
+        if file.metadata()?.len() < len {
+            return Err("File shrank while it was being hashed".to_owned().into());
+        }
+        let window = (len - offset).min(HASH_BUFFER_SIZE as u64) as usize;
+
+        // Safety: nothing here writes through the map, and it's dropped before the next
+        // one is made. Changes to the file in the meantime only make the hash wrong, and
+        // the modified time changes with them, so the next scan hashes it again. A
+        // truncation between the check above and the read still raises SIGBUS, which
+        // kills the app, and is why this mode isn't the default.
+        let map = unsafe { MmapOptions::new().offset(offset).len(window).map(file)? };
+        hasher.update(&map);
+        offset += window as u64;
+    }
+
+    hasher.finish()
+}
+
+/// Stream everything from `reader` through `buffer` into the hashes
+pub fn hash_reader(
+    mut reader: impl Read,
+    buffer: &mut [u8],
+    strong: Option<HashAlgorithm>,
+) -> Result<FileHashes> {
+    let mut hasher = ContentHasher::new(strong)?;
+
+    loop {
+        match reader.read(buffer) {
+            Ok(0) => break,
+            Ok(read) => hasher.update(&buffer[..read]),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    hasher.finish()
+}
+
+enum StrongHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+}
+
+/// Feeds the same bytes to xxh3-128 and the strong hash, so a file is only read once
+struct ContentHasher {
+    fast: Xxh3,
+    strong: Option<StrongHasher>,
+}
+
+impl ContentHasher {
+    fn new(strong: Option<HashAlgorithm>) -> Result<Self> {
+        let strong = match strong {
+            None => None,
+            Some(HashAlgorithm::Blake3) => Some(StrongHasher::Blake3(Box::default())),
+            Some(HashAlgorithm::Sha256) => Some(StrongHasher::Sha256(Sha256::new())),
+            Some(algorithm) => {
+                return Err(format!("{} isn't a cryptographic hash", algorithm.name()).into())
+            }
+        };
+
+        Ok(Self {
+            fast: Xxh3::new(),
+            strong,
+        })
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        self.fast.update(bytes);
+
+        match &mut self.strong {
+            Some(StrongHasher::Blake3(hasher)) => {
+                hasher.update(bytes);
+            }
+            Some(StrongHasher::Sha256(hasher)) => hasher.update(bytes),
+            None => {}
+        }
+    }
+
+    fn finish(self) -> Result<FileHashes> {
+        let strong_hash = match self.strong {
+            Some(StrongHasher::Blake3(hasher)) => Some(ContentHash::new(
+                HashAlgorithm::Blake3,
+                hasher.finalize().as_bytes().to_vec(),
+            )?),
+            Some(StrongHasher::Sha256(hasher)) => Some(ContentHash::new(
+                HashAlgorithm::Sha256,
+                hasher.finalize().to_vec(),
+            )?),
+            None => None,
+        };
+
+        Ok(FileHashes {
+            hash: ContentHash::xxh3_128(self.fast.digest128()),
+            strong_hash,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use sha2::{Digest, Sha256};
+    use xxhash_rust::xxh3::xxh3_128;
+
+    use super::{hash_file, hash_reader, HashMode, HashSettings, HASH_BUFFER_SIZE};
+    use crate::db::content_hash::{ContentHash, HashAlgorithm};
+
+    #[test]
+    fn test_streaming_matches_whole_file() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        // A buffer that doesn't divide the data evenly
+        let mut buffer = vec![0; 4093];
+        let streamed = hash_reader(data.as_slice(), &mut buffer, Some(HashAlgorithm::Sha256)).unwrap();
+
+        assert_eq!(ContentHash::xxh3_128(xxh3_128(&data)), streamed.hash);
+        assert_eq!(
+            Some(ContentHash::new(HashAlgorithm::Sha256, Sha256::digest(&data).to_vec()).unwrap()),
+            streamed.strong_hash
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modes_agree() {
+        // Across more than one mapped window
+        let contents: Vec<u8> = (0..HASH_BUFFER_SIZE * 2 + 10_000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&contents).unwrap();
+        let empty = tempfile::NamedTempFile::new().unwrap();
+
+        let buffered = HashSettings {
+            mode: HashMode::Buffered,
+            strong: Some(HashAlgorithm::Blake3),
+        };
+        let mapped = HashSettings {
+            mode: HashMode::Mmap,
+            ..buffered
+        };
+
+        let buffered = hash_file(file.path().to_path_buf(), buffered).await.unwrap();
+        let mapped = hash_file(file.path().to_path_buf(), mapped).await.unwrap();
+
+        assert_eq!(ContentHash::xxh3_128(xxh3_128(&contents)), buffered.hash);
+        assert_eq!(
+            Some(blake3::hash(&contents).as_bytes().to_vec()),
+            buffered.strong_hash.as_ref().map(|hash| hash.digest().to_vec())
+        );
+        assert_eq!(buffered, mapped);
+        assert_eq!(
+            ContentHash::xxh3_128(xxh3_128(&[])),
+            hash_file(empty.path().to_path_buf(), HashSettings::default())
+                .await
+                .unwrap()
+                .hash
+        );
+    }
+}
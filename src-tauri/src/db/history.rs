@@ -0,0 +1,247 @@
+//! Every version a file has had, from local commits and from the remote.
+//!
+//! Commits are walked back from HEAD. When a commit moved the file here, older commits
+//! are followed under the name it had before, and the walk stops at the commit that
+//! added it, since anything older at that path was another file. Remote history is kept per path and knows
+//! its own moves, so it's read for every name the file went by.
+
+use std::collections::{BTreeSet, HashSet};
+
+use crate::{
+    db::{
+        commits::log,
+        content_hash::ContentHash,
+        objects::ObjectStore,
+        project_path::ProjectPath,
+        store::MetadataStore,
+        typed_tree::TypedTree,
+        types::{
+            CommitChangeKind, FileVersion, LocalFileData, Project, RemoteHistory, TreeNames,
+            VersionSource,
+        },
+    },
+    error::Result,
+};
+
+/// The versions of the file at `path`, newest first. A version that's the same as the one
+/// right before it, like after a move, is left out, but going back to an older one isn't.
+pub fn file_history(
+    project: &Project,
+    path: &ProjectPath,
+    objects: &ObjectStore,
+    db: &dyn MetadataStore,
+) -> Result<Vec<FileVersion>> {
+    let local: TypedTree<ProjectPath, LocalFileData> =
+        TypedTree::open(db, project.tree_name(TreeNames::HASH_LOCAL_METDATA))?;
+    let local_hash = local.get(path)?.map(|data| data.hash);
+    let available =
+        |hash: &ContentHash| objects.contains(hash) || local_hash.as_ref() == Some(hash);
+
+    let mut versions = Vec::new();
+    let mut names = BTreeSet::from([path.clone()]);
+
+    let mut name = path.clone();
+    for commit in log(project, db)? {
+        let change = match commit.changes.iter().find(|change| change.path == name) {
+            Some(change) => change,
+            None => continue,
+        };
+
+        if let Some(data) = &change.data {
+            versions.push(FileVersion {
+                path: name.clone(),
+                hash: data.hash.clone(),
+                size: data.metadata.size,
+                modified: data.metadata.modified,
+                author: Some(commit.author.clone()),
+                message: Some(commit.message.clone()),
+                timestamp: commit.timestamp,
+                source: VersionSource::Commit { id: commit.id },
+                available_locally: available(&data.hash),
+            });
+        }
+        match &change.kind {
+            CommitChangeKind::Renamed { from } => {
+                name = from.clone();
+                names.insert(name.clone());
+            }
+            CommitChangeKind::Added => break,
+            CommitChangeKind::Modified | CommitChangeKind::Deleted => {}
+        }
+    }
+
+    let remote: TypedTree<ProjectPath, RemoteHistory> =
+        TypedTree::open(db, project.tree_name(TreeNames::REMOTE_HISTORY))?;
+    let mut to_read: Vec<ProjectPath> = names.into_iter().collect();
+    let mut read = HashSet::new();
+    while let Some(name) = to_read.pop() {
+        if !read.insert(name.clone()) {
+            continue;
+        }
+
+        for version in remote.get(&name)?.unwrap_or_default().versions {
+            let data = version.data;
+            versions.push(FileVersion {
+                path: name.clone(),
+                available_locally: available(&data.hash),
+                hash: data.hash,
+                size: data.metadata.size,
+                modified: data.metadata.modified,
+                author: None,
+                message: None,
+                timestamp: version.seen,
+                source: VersionSource::Remote,
+            });
+            to_read.extend(version.renamed_from);
+        }
+    }
+
+    // Oldest first, so the first of a run of the same hash is where it came from
+    versions.sort_by_key(|version| version.timestamp);
+    versions.dedup_by(|later, earlier| later.hash == earlier.hash);
+    versions.reverse();
+
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::file_history;
+    use crate::db::{
+        commits::commit_project,
+        content_hash::ContentHash,
+        memory_store::MemoryStore,
+        objects::ObjectStore,
+        project_path::ProjectPath,
+        refresh_state::refresh_paths,
+        remote_state::set_remote_state,
+        test_support::{path, project_at},
+        typed_tree::TypedTree,
+        types::{LocalFileData, TreeNames, VersionSource},
+    };
+
+    #[tokio::test]
+    async fn test_history_follows_moves() {
+        let db = MemoryStore::default();
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        let root = dir.path().join("robot");
+        let project = project_at(&root);
+        let everything = [ProjectPath::default()];
+        let local: TypedTree<ProjectPath, LocalFileData> =
+            TypedTree::open(&db, project.tree_name(TreeNames::HASH_LOCAL_METDATA)).unwrap();
+
+        fs::create_dir_all(root.join("parts")).unwrap();
+        fs::write(root.join("bracket.sldprt"), b"bracket").unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        commit_project(&project, "ana".into(), "add bracket".into(), &objects, &db)
+            .await
+            .unwrap();
+
+        // Moved locally
+        fs::rename(
+            root.join("bracket.sldprt"),
+            root.join("parts/bracket.sldprt"),
+        )
+        .unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        commit_project(&project, "ben".into(), "move bracket".into(), &objects, &db)
+            .await
+            .unwrap();
+
+        // Someone else changes it on the remote
+        let mut remote = local.get(&path("parts/bracket.sldprt")).unwrap().unwrap();
+        remote.hash = ContentHash::xxh3_128(7);
+        set_remote_state(&project, vec![remote.clone()], &db).unwrap();
+
+        // Changed, then changed back
+        fs::write(root.join("parts/bracket.sldprt"), b"thicker bracket").unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        commit_project(&project, "ben".into(), "thicker".into(), &objects, &db)
+            .await
+            .unwrap();
+        fs::write(root.join("parts/bracket.sldprt"), b"bracket").unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        commit_project(
+            &project,
+            "ana".into(),
+            "thinner again".into(),
+            &objects,
+            &db,
+        )
+        .await
+        .unwrap();
+
+        let history = file_history(&project, &path("parts/bracket.sldprt"), &objects, &db).unwrap();
+        let summary: Vec<_> = history
+            .iter()
+            .map(|version| {
+                (
+                    version.path.clone(),
+                    version.message.clone(),
+                    version.available_locally,
+                )
+            })
+            .collect();
+        // The move didn't change the contents, so it isn't a version of its own, but
+        // going back to the first contents is
+        assert_eq!(
+            vec![
+                (
+                    path("parts/bracket.sldprt"),
+                    Some("thinner again".to_owned()),
+                    true
+                ),
+                (
+                    path("parts/bracket.sldprt"),
+                    Some("thicker".to_owned()),
+                    true
+                ),
+                (path("parts/bracket.sldprt"), None, false),
+                (path("bracket.sldprt"), Some("add bracket".to_owned()), true),
+            ],
+            summary
+        );
+        assert_eq!(VersionSource::Remote, history[2].source);
+        assert_eq!(remote.hash, history[2].hash);
+        assert_eq!(history[0].hash, history[3].hash);
+    }
+
+    #[tokio::test]
+    async fn test_history_stops_where_the_file_was_added() {
+        let db = MemoryStore::default();
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        let root = dir.path().join("robot");
+        let project = project_at(&root);
+        let everything = [ProjectPath::default()];
+
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("bracket.sldprt"), b"old bracket").unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        commit_project(&project, "ana".into(), "old bracket".into(), &objects, &db)
+            .await
+            .unwrap();
+        fs::remove_file(root.join("bracket.sldprt")).unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        commit_project(&project, "ana".into(), "drop it".into(), &objects, &db)
+            .await
+            .unwrap();
+
+        // A different part that happens to get the same name
+        fs::write(root.join("bracket.sldprt"), b"new bracket").unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        commit_project(&project, "ben".into(), "new bracket".into(), &objects, &db)
+            .await
+            .unwrap();
+
+        let history = file_history(&project, &path("bracket.sldprt"), &objects, &db).unwrap();
+        let messages: Vec<_> = history
+            .iter()
+            .map(|version| version.message.clone())
+            .collect();
+        assert_eq!(vec![Some("new bracket".to_owned())], messages);
+    }
+}
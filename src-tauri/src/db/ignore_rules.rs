@@ -0,0 +1,237 @@
+//! Which files the scanner skips.
+//!
+//! Rules come from `.splatcadignore` files, which use gitignore syntax and can sit in
+//! any folder of the project, plus a built-in list of CAD temp files and OS junk. Like
+//! git, a rule from a deeper file beats one from a shallower file, and the built-in
+//! list loses to all of them, so `!*.bak` in a project's file brings backups back.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder, Glob};
+use ignore::Match;
+
+use crate::{
+    db::{
+        project_path::ProjectPath,
+        types::{IgnoreReason, IgnoreSource},
+    },
+    error::Result,
+};
+
+pub const IGNORE_FILE_NAME: &str = ".splatcadignore";
+
+/// Ignored in every project, matched case-insensitively
+pub const DEFAULT_IGNORES: &[&str] = &[
+    // SolidWorks and Office lock files
+    "~$*",
+    // Generic backups and temp files
+    "*.bak",
+    "*.tmp",
+    "*~",
+    // AutoCAD locks and autosaves
+    "*.dwl",
+    "*.dwl2",
+    "*.sv$",
+    "*.ac$",
+    // Inventor keeps every old save here
+    "OldVersions/",
+    // FreeCAD and Rhino backups
+    "*.fcstd1",
+    "*.3dmbak",
+    "*.rhl",
+    // KiCad
+    "*.lck",
+    "_autosave-*",
+    "*-backups/",
+    // Autosave folders
+    "autosave/",
+    // OS junk
+    ".DS_Store",
+    "._*",
+    "Thumbs.db",
+    "desktop.ini",
+];
+
+/// The ignore rules of one project. Each folder's `.splatcadignore` is read the
+/// first time something in that folder is checked.
+pub struct IgnoreRules {
+    root: PathBuf,
+    defaults: Gitignore,
+    files: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl IgnoreRules {
+    pub fn new(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+
+        let mut builder = GitignoreBuilder::new(&root);
+        builder.case_insensitive(true)?;
+        for pattern in DEFAULT_IGNORES {
+            builder.add_line(None, pattern)?;
+        }
+
+        Ok(Self {
+            defaults: builder.build()?,
+            root,
+            files: HashMap::new(),
+        })
+    }
+
+    /// Whether the rules ignore this absolute path, assuming none of its parent
+    /// folders are ignored. That holds during a walk that skips ignored folders.
+    pub fn check(&mut self, path: &Path, is_dir: bool) -> Option<IgnoreReason> {
+        // Deepest ignore file first
+        let mut dir = path.parent();
+        while let Some(current) = dir {
+            if !current.starts_with(&self.root) {
+                break;
+            }
+
+            if let Some(rules) = load_ignore_file(&mut self.files, current) {
+                match rules.matched(path, is_dir) {
+                    Match::Ignore(glob) => return ignore_reason(&self.root, path, glob),
+                    Match::Whitelist(_) => return None,
+                    Match::None => {}
+                }
+            }
+
+            dir = current.parent();
+        }
+
+        match self.defaults.matched(path, is_dir) {
+            Match::Ignore(glob) => ignore_reason(&self.root, path, glob),
+            _ => None,
+        }
+    }
+
+    /// Why `path` is ignored, checking the folders above it too. `None` if it isn't.
+    pub fn explain(&mut self, path: &ProjectPath) -> Option<IgnoreReason> {
+        let count = path.components().count();
+        let mut current = ProjectPath::default();
+
+        for (index, component) in path.components().enumerate() {
+            current = current.join(component).ok()?;
+            let absolute = current.to_absolute(&self.root);
+            let is_dir = index + 1 < count || absolute.is_dir();
+
+            if let Some(reason) = self.check(&absolute, is_dir) {
+                return Some(reason);
+            }
+        }
+
+        None
+    }
+}
+
+/// The rules in `dir`'s ignore file, reading it if this is the first time
+fn load_ignore_file<'a>(
+    files: &'a mut HashMap<PathBuf, Option<Gitignore>>,
+    dir: &Path,
+) -> Option<&'a Gitignore> {
+    files
+        .entry(dir.to_path_buf())
+        .or_insert_with(|| {
+            let file = dir.join(IGNORE_FILE_NAME);
+            if !file.is_file() {
+                return None;
+            }
+
+            let mut builder = GitignoreBuilder::new(dir);
+            // Bad lines are skipped, the rest of the file still applies
+            if let Some(err) = builder.add(&file) {
+                println!("Problem reading {}: {}", file.display(), err);
+            }
+
+            match builder.build() {
+                Ok(rules) => Some(rules),
+                Err(err) => {
+                    println!("Skipping {}: {}", file.display(), err);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+fn ignore_reason(root: &Path, path: &Path, glob: &Glob) -> Option<IgnoreReason> {
+    let source = match glob.from() {
+        Some(file) => IgnoreSource::File(ProjectPath::from_absolute(root, file).ok()?),
+        None => IgnoreSource::Default,
+    };
+
+    Some(IgnoreReason {
+        matched: ProjectPath::from_absolute(root, path).ok()?,
+        pattern: glob.original().to_owned(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use super::{IgnoreRules, IGNORE_FILE_NAME};
+    use crate::db::{
+        refresh_state::get_metadatas,
+        test_support::path,
+        types::{IgnoreReason, IgnoreSource},
+    };
+
+    #[test]
+    fn test_nested_rules() {
+        let root = tempfile::tempdir().unwrap();
+        create_dir_all(root.path().join("exports/step")).unwrap();
+        create_dir_all(root.path().join("parts")).unwrap();
+        write(root.path().join(IGNORE_FILE_NAME), "exports/\n!keep.bak\n").unwrap();
+        write(root.path().join("parts").join(IGNORE_FILE_NAME), "*.step\n").unwrap();
+        for file in [
+            "exports/step/gearbox.step",
+            "parts/bolt.sldprt",
+            "parts/bolt.step",
+            "parts/~$bolt.sldprt",
+            "parts/old.bak",
+            "parts/keep.bak",
+        ] {
+            write(root.path().join(file), "data").unwrap();
+        }
+
+        let mut rules = IgnoreRules::new(root.path()).unwrap();
+
+        assert_eq!(
+            Some(IgnoreReason {
+                matched: path("exports"),
+                pattern: "exports/".to_owned(),
+                source: IgnoreSource::File(path(IGNORE_FILE_NAME)),
+            }),
+            rules.explain(&path("exports/step/gearbox.step"))
+        );
+        assert_eq!(
+            Some(IgnoreSource::File(path("parts/.splatcadignore"))),
+            rules.explain(&path("parts/bolt.step")).map(|reason| reason.source)
+        );
+        assert_eq!(
+            Some(IgnoreSource::Default),
+            rules.explain(&path("parts/~$bolt.sldprt")).map(|reason| reason.source)
+        );
+        assert!(rules.explain(&path("parts/keep.bak")).is_none());
+
+        let mut scanned: Vec<String> = get_metadatas(root.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().0.to_string())
+            .collect();
+        scanned.sort();
+
+        assert_eq!(
+            vec![
+                ".splatcadignore",
+                "parts/.splatcadignore",
+                "parts/bolt.sldprt",
+                "parts/keep.bak",
+            ],
+            scanned
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! Sled keys for paths.
+//!
+//! Every per-project tree in `TreeNames` is keyed by the `ProjectPath`, with its
+//! components joined by a `0x00` separator.
+//!
+//! Sled iterates in lexicographic byte order, and `0x00` sorts before any byte a
+//! component can contain, which makes `a/b` sort before `a.txt` just like
+//! `ProjectPath`'s own `Ord`. That lets two trees be merge-joined straight off their
+//! sled iterators, and makes everything under a folder a single `scan_prefix`.
+
+use crate::{db::project_path::ProjectPath, error::Result};
+
+const SEPARATOR: u8 = 0x00;
+
+/// Encode a path into a key whose byte order matches path order
+pub fn encode_path_key(path: &ProjectPath) -> Vec<u8> {
+    let mut key = Vec::with_capacity(path.as_str().len());
+
+    for (index, component) in path.components().enumerate() {
+        if index > 0 {
+            key.push(SEPARATOR);
+        }
+        key.extend_from_slice(component.as_bytes());
+    }
+
+    key
+}
+
+/// Reverse of `encode_path_key`
+pub fn decode_path_key(key: &[u8]) -> Result<ProjectPath> {
+    let mut components = Vec::new();
+
+    if !key.is_empty() {
+        for component in key.split(|byte| *byte == SEPARATOR) {
+            components.push(
+                std::str::from_utf8(component)
+                    .map_err(|err| format!("Path key is not valid UTF-8: {}", err))?,
+            );
+        }
+    }
+
+    ProjectPath::parse(&components.join("/"))
+}
+
+/// Key prefix matching everything inside `folder`, but not `folder` itself
+/// or siblings that merely start with the same name
+pub fn folder_prefix(folder: &ProjectPath) -> Vec<u8> {
+    let mut prefix = encode_path_key(folder);
+    if !prefix.is_empty() {
+        prefix.push(SEPARATOR);
+    }
+
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_path_key, encode_path_key, folder_prefix};
+    use crate::db::{project_path::ProjectPath, test_support::path};
+
+    #[test]
+    fn test_round_trip() {
+        let path = path("assemblies/gearbox/housing.sldprt");
+
+        let key = encode_path_key(&path);
+
+        assert_eq!(b"assemblies\0gearbox\0housing.sldprt".to_vec(), key);
+        assert_eq!(path, decode_path_key(&key).unwrap());
+    }
+
+    #[test]
+    fn test_key_order_matches_path_order() {
+        let mut paths = vec![
+            path("a.txt"),
+            path("a/b"),
+            path("ab"),
+            path("a"),
+            path("a/b/c"),
+            path("b"),
+            path("a-b"),
+        ];
+        let mut keys: Vec<Vec<u8>> = paths.iter().map(encode_path_key).collect();
+
+        paths.sort();
+        keys.sort();
+
+        let decoded: Vec<ProjectPath> = keys.iter().map(|key| decode_path_key(key).unwrap()).collect();
+        assert_eq!(paths, decoded);
+    }
+
+    #[test]
+    fn test_folder_prefix() {
+        let prefix = folder_prefix(&path("assemblies/gearbox"));
+
+        assert!(encode_path_key(&path("assemblies/gearbox/housing.sldprt")).starts_with(&prefix));
+        assert!(!encode_path_key(&path("assemblies/gearbox2/housing.sldprt")).starts_with(&prefix));
+        assert!(folder_prefix(&ProjectPath::default()).is_empty());
+    }
+}
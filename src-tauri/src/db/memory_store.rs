@@ -0,0 +1,316 @@
+//! A `MetadataStore` that never touches the disk.
+//!
+//! Everything is gone once the store is dropped, which is what tests want. Trees are
+//! `BTreeMap`s, so keys come out in the same order sled gives them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
+};
+
+use crate::{
+    db::store::{
+        MetadataStore, StoreEvent, StoreIter, StoreTree, StoreWatch, TreeEvent, WriteBatch,
+    },
+    error::Result,
+};
+
+type Entries = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Default)]
+pub struct MemoryStore {
+    trees: Mutex<HashMap<String, Arc<MemoryTree>>>,
+    next_id: AtomicU64,
+}
+
+impl MemoryStore {
+    // A panic mid-write can't leave a map half changed, so poisoning is ignored here
+    // and in `MemoryTree`
+    fn trees(&self) -> MutexGuard<'_, HashMap<String, Arc<MemoryTree>>> {
+        self.trees.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn tree(&self, name: &str) -> Arc<MemoryTree> {
+        self.trees()
+            .entry(name.to_owned())
+            .or_insert_with(|| Arc::new(MemoryTree::new(name)))
+            .clone()
+    }
+}
+
+impl MetadataStore for MemoryStore {
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn StoreTree>> {
+        Ok(self.tree(name))
+    }
+
+    fn drop_tree(&self, name: &str) -> Result<bool> {
+        Ok(self.trees().remove(name).is_some())
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        Ok(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn apply_batches(&self, batches: &[(&str, &WriteBatch)]) -> Result<()> {
+        // Locked in name order, so two of these at once can't deadlock
+        let mut trees: BTreeMap<&str, (Arc<MemoryTree>, Vec<&WriteBatch>)> = BTreeMap::new();
+        for (name, batch) in batches {
+            trees
+                .entry(name)
+                .or_insert_with(|| (self.tree(name), Vec::new()))
+                .1
+                .push(batch);
+        }
+
+        let mut locked: Vec<(RwLockWriteGuard<'_, Entries>, &MemoryTree, Vec<&WriteBatch>)> =
+            trees
+                .values()
+                .map(|(tree, batches)| (tree.write(), tree.as_ref(), batches.clone()))
+                .collect();
+
+        let mut events = Vec::new();
+        for (entries, tree, batches) in &mut locked {
+            for batch in batches {
+                for event in apply(entries, batch) {
+                    events.push((*tree, event));
+                }
+            }
+        }
+        drop(locked);
+
+        for (tree, event) in events {
+            tree.notify(event);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct MemoryTree {
+    name: String,
+    entries: RwLock<Entries>,
+    watchers: Mutex<Vec<(Vec<u8>, Sender<StoreEvent>)>>,
+}
+
+impl MemoryTree {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            entries: RwLock::default(),
+            watchers: Mutex::default(),
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Entries> {
+        self.entries.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Entries> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Tell the watchers of the key, forgetting the ones that stopped listening
+    fn notify(&self, event: StoreEvent) {
+        let key = match &event {
+            TreeEvent::Insert { key, .. } | TreeEvent::Remove { key } => key.clone(),
+        };
+
+        self.watchers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(prefix, sender)| {
+                !key.starts_with(prefix) || sender.send(event.clone()).is_ok()
+            });
+    }
+}
+
+impl StoreTree for MemoryTree {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.read().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let old = self.write().insert(key.to_vec(), value.clone());
+        self.notify(TreeEvent::Insert {
+            key: key.to_vec(),
+            value,
+        });
+        Ok(old)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let old = self.write().remove(key);
+        if old.is_some() {
+            self.notify(TreeEvent::Remove { key: key.to_vec() });
+        }
+        Ok(old)
+    }
+
+    fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    fn clear(&self) -> Result<()> {
+        let removed = std::mem::take(&mut *self.write());
+        for key in removed.into_keys() {
+            self.notify(TreeEvent::Remove { key });
+        }
+        Ok(())
+    }
+
+    // Copies the matching entries out, so the tree isn't locked while they're used
+    fn scan_prefix(&self, prefix: &[u8]) -> StoreIter {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = self
+            .read()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        Box::new(entries.into_iter().map(Ok))
+    }
+
+    fn watch_prefix(&self, prefix: &[u8]) -> Box<dyn StoreWatch> {
+        let (sender, receiver) = channel();
+        self.watchers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((prefix.to_vec(), sender));
+
+        Box::new(MemoryWatch { receiver })
+    }
+
+    fn apply_batch(&self, batch: &WriteBatch) -> Result<()> {
+        let events = apply(&mut self.write(), batch);
+        for event in events {
+            self.notify(event);
+        }
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mut entries = self.write();
+        if entries.get(key).map(Vec::as_slice) != old {
+            return Ok(false);
+        }
+
+        let mut batch = WriteBatch::default();
+        match new {
+            Some(value) => batch.insert(key.to_vec(), value),
+            None => batch.remove(key.to_vec()),
+        }
+        let events = apply(&mut entries, &batch);
+        drop(entries);
+
+        for event in events {
+            self.notify(event);
+        }
+        Ok(true)
+    }
+}
+
+struct MemoryWatch {
+    receiver: Receiver<StoreEvent>,
+}
+
+impl StoreWatch for MemoryWatch {
+    fn next_event(&mut self) -> Option<StoreEvent> {
+        self.receiver.recv().ok()
+    }
+
+    fn next_event_timeout(&mut self, timeout: Duration) -> Option<StoreEvent> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+/// Write a batch into `entries`, returning the changes for the watchers
+fn apply(entries: &mut Entries, batch: &WriteBatch) -> Vec<StoreEvent> {
+    let mut events = Vec::new();
+
+    for (key, value) in batch.writes() {
+        match value {
+            Some(value) => {
+                entries.insert(key.to_vec(), value.to_vec());
+                events.push(TreeEvent::Insert {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                });
+            }
+            None => {
+                if entries.remove(key).is_some() {
+                    events.push(TreeEvent::Remove { key: key.to_vec() });
+                }
+            }
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::MemoryStore;
+    use crate::db::store::{MetadataStore, TreeEvent, WriteBatch};
+
+    #[test]
+    fn test_batches_and_watchers() {
+        let store = MemoryStore::default();
+        let parts = store.open_tree("parts").unwrap();
+        let mut watch = parts.watch_prefix(b"bo");
+
+        parts.insert(b"bolt", b"1".to_vec()).unwrap();
+        parts.insert(b"nut", b"1".to_vec()).unwrap();
+
+        let mut part_writes = WriteBatch::default();
+        part_writes.remove(b"bolt".to_vec());
+        part_writes.insert(b"washer".to_vec(), b"2".to_vec());
+        let mut log_writes = WriteBatch::default();
+        log_writes.insert(b"1".to_vec(), b"removed bolt".to_vec());
+        store
+            .apply_batches(&[("parts", &part_writes), ("log", &log_writes)])
+            .unwrap();
+
+        let keys: Vec<Vec<u8>> = parts.scan_prefix(b"").map(|item| item.unwrap().0).collect();
+        assert_eq!(vec![b"nut".to_vec(), b"washer".to_vec()], keys);
+        assert_eq!(1, store.open_tree("log").unwrap().len());
+
+        // Only the keys under the prefix
+        let timeout = Duration::from_millis(100);
+        assert_eq!(
+            Some(TreeEvent::Insert {
+                key: b"bolt".to_vec(),
+                value: b"1".to_vec()
+            }),
+            watch.next_event_timeout(timeout)
+        );
+        assert_eq!(
+            Some(TreeEvent::Remove {
+                key: b"bolt".to_vec()
+            }),
+            watch.next_event_timeout(timeout)
+        );
+        assert_eq!(None, watch.next_event_timeout(timeout));
+
+        assert!(store.drop_tree("parts").unwrap());
+        assert!(store.open_tree("parts").unwrap().is_empty());
+    }
+}
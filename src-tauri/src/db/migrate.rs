@@ -0,0 +1,812 @@
+//! Upgrades existing databases to the current layout.
+//!
+//! The schema version lives in the `preferences` tree. Every migration with a
+//! higher version than the stored one is run in order when the DB is opened, after
+//! the whole DB is copied to a backup next to it.
+//!
+//! Migrations work on records as they were at their version, not as they are now,
+//! so the older ones keep their own copies of the types they read and write.
+//!
+//! Records a migration can't make sense of are moved to `SET_ASIDE_TREE` rather than
+//! deleted, so they can still be looked at or recovered by hand.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_cbor::{from_slice, to_vec};
+
+use crate::{
+    db::{
+        content_hash::ContentHash,
+        keys::encode_path_key,
+        project_path::ProjectPath,
+        record::{decode, is_wrapped, wrap_unversioned},
+        types::{LocalFileData, LocalFileMetadata, Project, Tombstone, TreeNames},
+    },
+    error::Result,
+};
+
+const SCHEMA_VERSION_KEY: &[u8] = b"schemaVersion";
+
+/// Where records that couldn't be migrated go, keyed by their tree's name, a zero byte
+/// and their old key
+pub const SET_ASIDE_TREE: &str = "migrationSetAside";
+
+type Migration = fn(&sled::Db) -> Result<()>;
+
+/// (version it upgrades to, migration)
+const MIGRATIONS: &[(u32, Migration)] = &[
+    (1, path_keys_v1),
+    (2, project_paths_v2),
+    (3, project_registry_v3),
+    (4, content_hashes_v4),
+    (5, record_envelopes_v5),
+];
+
+/// The version a fully migrated database is at
+pub fn latest_schema_version() -> u32 {
+    MIGRATIONS.last().map(|(version, _)| *version).unwrap_or(0)
+}
+
+pub fn schema_version(db: &sled::Db) -> Result<u32> {
+    let prefs = db.open_tree("preferences")?;
+
+    Ok(match prefs.get(SCHEMA_VERSION_KEY)? {
+        Some(version) => from_slice(&version)?,
+        None => 0,
+    })
+}
+
+fn set_schema_version(db: &sled::Db, version: u32) -> Result<()> {
+    let prefs = db.open_tree("preferences")?;
+    prefs.insert(SCHEMA_VERSION_KEY, to_vec(&version)?)?;
+    Ok(())
+}
+
+/// Run every migration the database hasn't seen yet
+pub fn migrate(db: &sled::Db) -> Result<()> {
+    let current = schema_version(db)?;
+
+    for (version, migration) in MIGRATIONS {
+        if *version <= current {
+            continue;
+        }
+
+        println!("Migrating database to schema version {}", version);
+        migration(db)?;
+        set_schema_version(db, *version)?;
+    }
+
+    db.flush()?;
+    Ok(())
+}
+
+/// Run every migration the database hasn't seen yet, copying it into `backup_dir`
+/// first if there are any. Returns where the backup went.
+pub fn migrate_with_backup(db: &sled::Db, backup_dir: &Path) -> Result<Option<PathBuf>> {
+    let current = schema_version(db)?;
+    // Nothing to lose in a brand new DB
+    let fresh = db.is_empty() && db.tree_names().len() == 1;
+
+    let backup_path = if current < latest_schema_version() && !fresh {
+        let path = backup_dir.join(format!(
+            "splatcad.db.v{}-backup-{}",
+            current,
+            Utc::now().format("%Y%m%d%H%M%S")
+        ));
+        println!("Backing up the database to {}", path.display());
+        backup(db, &path)?;
+        Some(path)
+    } else {
+        None
+    };
+
+    migrate(db)?;
+    Ok(backup_path)
+}
+
+/// Copy every tree of `db` into a new database at `path`
+pub fn backup(db: &sled::Db, path: &Path) -> Result<()> {
+    if path.exists() {
+        return Err(format!("There's already a backup at {}", path.display()).into());
+    }
+
+    let backup = sled::open(path)?;
+    backup.import(db.export());
+    backup.flush()?;
+    Ok(())
+}
+
+/// The trees of a project, as (tree prefix, project root) for every project tree in the DB.
+/// Only meaningful before v3, when trees were still named by their root.
+fn project_trees(db: &sled::Db) -> Vec<(&'static str, String)> {
+    // The project trees as of v3, newer ones were always named by project ID
+    let prefixes = [
+        TreeNames::BASIC_LOCAL_METADATA,
+        TreeNames::HASH_LOCAL_METDATA,
+        TreeNames::HASH_HEAD_METDATA,
+        TreeNames::HASH_REMOTE_METDATA,
+        TreeNames::LOCAL_TOMBSTONES,
+    ];
+
+    db.tree_names()
+        .into_iter()
+        .filter_map(|name| {
+            let name = String::from_utf8(name.to_vec()).ok()?;
+            prefixes.iter().find_map(|prefix| {
+                name.strip_prefix(prefix)
+                    .map(|root| (*prefix, root.to_owned()))
+            })
+        })
+        .collect()
+}
+
+/// v1: keys go from CBOR `PathBuf`s to root-relative `encode_path_key` keys
+fn path_keys_v1(db: &sled::Db) -> Result<()> {
+    for (prefix, root) in project_trees(db) {
+        let tree = db.open_tree(prefix.to_owned() + &root)?;
+        let mut batch = sled::Batch::default();
+
+        for item in tree.iter() {
+            let (key, value) = item?;
+
+            let path: PathBuf = match from_slice(&key) {
+                Ok(path) => path,
+                Err(err) => {
+                    println!("Skipping undecodable key in {}{}: {}", prefix, root, err);
+                    continue;
+                }
+            };
+
+            batch.remove(key);
+            match ProjectPath::from_absolute(Path::new(&root), &path) {
+                Ok(path) => batch.insert(encode_path_key(&path), value),
+                // Can't be addressed under the new scheme anyway, the next scan will redo it
+                Err(err) => println!("Dropping {}: {}", path.display(), err),
+            }
+        }
+
+        tree.apply_batch(batch)?;
+    }
+
+    Ok(())
+}
+
+/// Records as they were stored up to v1, with absolute `PathBuf`s
+mod v1 {
+    use std::path::PathBuf;
+
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct LocalFileMetadata {
+        pub path: PathBuf,
+        pub size: u64,
+        pub modified: DateTime<Utc>,
+        pub update_time: DateTime<Utc>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct LocalFileData {
+        pub name: String,
+        pub hash: u128,
+        pub metadata: LocalFileMetadata,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Tombstone {
+        pub data: LocalFileData,
+        pub deleted_time: DateTime<Utc>,
+    }
+}
+
+/// Records as they were stored from v2 up to v3, with an untagged `u128` hash
+mod v3 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+
+    use crate::db::types::LocalFileMetadata;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct LocalFileData {
+        pub name: String,
+        pub hash: u128,
+        pub metadata: LocalFileMetadata,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Tombstone {
+        pub data: LocalFileData,
+        pub deleted_time: DateTime<Utc>,
+    }
+}
+
+/// Move a record out of `tree` into `SET_ASIDE_TREE`
+fn set_aside(
+    db: &sled::Db,
+    tree: &sled::Tree,
+    key: &[u8],
+    value: &[u8],
+    batch: &mut sled::Batch,
+    err: &dyn std::fmt::Display,
+) -> Result<()> {
+    println!(
+        "Setting aside unmigratable record in {}: {}",
+        String::from_utf8_lossy(&tree.name()),
+        err
+    );
+
+    let mut aside_key = tree.name().to_vec();
+    aside_key.push(0);
+    aside_key.extend_from_slice(key);
+    // Before it's removed from the tree, so a crash in between loses nothing
+    db.open_tree(SET_ASIDE_TREE)?.insert(aside_key, value)?;
+
+    batch.remove(key);
+    Ok(())
+}
+
+/// Decode every value in a tree as `Old`, convert it and write it back.
+/// Values that can't be converted are set aside, the next scan will redo them.
+///
+/// Each tree is its own batch and the version is only bumped at the end, so after a
+/// crash this runs again over trees that are already done. Values that don't convert
+/// but already decode as `New` are left as they are.
+fn rewrite_values<Old, New>(
+    db: &sled::Db,
+    tree: &sled::Tree,
+    convert: impl Fn(Old) -> Result<New>,
+) -> Result<()>
+where
+    Old: DeserializeOwned,
+    New: Serialize + DeserializeOwned,
+{
+    let mut batch = sled::Batch::default();
+
+    for item in tree.iter() {
+        let (key, value) = item?;
+
+        // Converting comes first, since an old value can look like a new one. A Windows
+        // path like `C:\robot\bolt.sldprt` parses as a `ProjectPath`.
+        match from_slice(&value).map_err(Into::into).and_then(&convert) {
+            Ok(new) => batch.insert(key, to_vec(&new)?),
+            Err(_) if from_slice::<New>(&value).is_ok() => {}
+            Err(err) => set_aside(db, tree, &key, &value, &mut batch, &err)?,
+        }
+    }
+
+    tree.apply_batch(batch)?;
+    Ok(())
+}
+
+fn metadata_v2(root: &Path, old: v1::LocalFileMetadata) -> Result<LocalFileMetadata> {
+    Ok(LocalFileMetadata {
+        path: ProjectPath::from_absolute(root, &old.path)?,
+        size: old.size,
+        modified: old.modified,
+        update_time: old.update_time,
+    })
+}
+
+fn data_v2(root: &Path, old: v1::LocalFileData) -> Result<v3::LocalFileData> {
+    Ok(v3::LocalFileData {
+        name: old.name,
+        hash: old.hash,
+        metadata: metadata_v2(root, old.metadata)?,
+    })
+}
+
+/// v2: paths stored inside records become `ProjectPath`s too
+fn project_paths_v2(db: &sled::Db) -> Result<()> {
+    for (prefix, root) in project_trees(db) {
+        let tree = db.open_tree(prefix.to_owned() + &root)?;
+        let root = Path::new(&root);
+
+        match prefix {
+            TreeNames::BASIC_LOCAL_METADATA => {
+                rewrite_values(db, &tree, |old: v1::LocalFileMetadata| metadata_v2(root, old))?
+            }
+            TreeNames::LOCAL_TOMBSTONES => rewrite_values(db, &tree, |old: v1::Tombstone| {
+                Ok(v3::Tombstone {
+                    data: data_v2(root, old.data)?,
+                    deleted_time: old.deleted_time,
+                })
+            })?,
+            _ => rewrite_values(db, &tree, |old: v1::LocalFileData| data_v2(root, old))?,
+        }
+    }
+
+    Ok(())
+}
+
+/// v3: every root found in the tree names becomes a registered project, and its
+/// trees move over to being named by the project ID.
+///
+/// If it's cut off and runs again, a root that's already registered keeps its project,
+/// and old trees are only dropped once every one of them is copied, so nothing ends up
+/// split across two projects.
+fn project_registry_v3(db: &sled::Db) -> Result<()> {
+    let registered = bare_projects(db)?;
+    let mut roots: BTreeMap<String, Vec<&'static str>> = BTreeMap::new();
+    for (prefix, root) in project_trees(db) {
+        // Already moved over by the run that was cut off
+        if registered
+            .iter()
+            .any(|project| project.id.to_string() == root)
+        {
+            continue;
+        }
+        roots.entry(root).or_default().push(prefix);
+    }
+
+    let mut copied = Vec::new();
+    for (root, prefixes) in roots {
+        let root_path = PathBuf::from(&root);
+        let project = match registered.iter().find(|project| project.root == root_path) {
+            Some(project) => project.clone(),
+            None => register_bare_project(db, root_path)?,
+        };
+
+        for prefix in prefixes {
+            let old_name = prefix.to_owned() + &root;
+            let old_tree = db.open_tree(&old_name)?;
+            let new_tree = db.open_tree(project.tree_name(prefix))?;
+
+            let mut batch = sled::Batch::default();
+            for item in old_tree.iter() {
+                let (key, value) = item?;
+                batch.insert(key, value);
+            }
+            new_tree.apply_batch(batch)?;
+
+            copied.push(old_name);
+        }
+    }
+
+    for old_name in copied {
+        db.drop_tree(old_name)?;
+    }
+
+    Ok(())
+}
+
+fn data_v4(old: v3::LocalFileData) -> LocalFileData {
+    LocalFileData {
+        name: old.name,
+        // Every hash so far was an xxh3-64 widened to fit
+        hash: ContentHash::xxh3_64(old.hash as u64),
+        strong_hash: None,
+        metadata: old.metadata,
+    }
+}
+
+/// v4: hashes get tagged with their algorithm. Local files are rehashed with xxh3-128
+/// on the next scan, since the scan redoes any hash that isn't current.
+fn content_hashes_v4(db: &sled::Db) -> Result<()> {
+    // The trees with hashes in them as of v4, newer trees never had untagged hashes
+    let hashed_trees = [
+        TreeNames::HASH_LOCAL_METDATA,
+        TreeNames::HASH_HEAD_METDATA,
+        TreeNames::HASH_REMOTE_METDATA,
+        TreeNames::LOCAL_TOMBSTONES,
+    ];
+
+    for project in bare_projects(db)? {
+        for prefix in hashed_trees {
+            let tree = db.open_tree(project.tree_name(prefix))?;
+
+            match prefix {
+                TreeNames::LOCAL_TOMBSTONES => rewrite_values(db, &tree, |old: v3::Tombstone| {
+                    Ok(Tombstone {
+                        data: data_v4(old.data),
+                        deleted_time: old.deleted_time,
+                    })
+                })?,
+                _ => rewrite_values(db, &tree, |old: v3::LocalFileData| Ok(data_v4(old)))?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Projects as stored before v5, as bare CBOR. Also reads ones v5 already wrapped, in
+/// case it's running again after being cut off.
+fn bare_projects(db: &sled::Db) -> Result<Vec<Project>> {
+    let projects = db.open_tree(TreeNames::PROJECTS)?;
+
+    projects
+        .iter()
+        .values()
+        .map(|project| {
+            let project = project?;
+            if is_wrapped(&project) {
+                decode(&project)
+            } else {
+                Ok(from_slice(&project)?)
+            }
+        })
+        .collect()
+}
+
+/// Register a project the way it was stored before v5
+fn register_bare_project(db: &sled::Db, root: PathBuf) -> Result<Project> {
+    let project = Project {
+        id: db.generate_id()?,
+        root,
+        cloud_project_id: None,
+        created: Utc::now(),
+        last_scanned: None,
+    };
+
+    let projects = db.open_tree(TreeNames::PROJECTS)?;
+    projects.insert(project.id.to_be_bytes(), to_vec(&project)?)?;
+    Ok(project)
+}
+
+/// v5: every record gets wrapped in a versioned envelope, starting at version 1
+fn record_envelopes_v5(db: &sled::Db) -> Result<()> {
+    // The trees with records in them as of v5
+    let project_trees = [
+        TreeNames::BASIC_LOCAL_METADATA,
+        TreeNames::HASH_LOCAL_METDATA,
+        TreeNames::HASH_HEAD_METDATA,
+        TreeNames::HASH_REMOTE_METDATA,
+        TreeNames::LOCAL_TOMBSTONES,
+        TreeNames::CHUNK_MANIFESTS,
+    ];
+
+    let mut trees = vec![db.open_tree(TreeNames::SCAN_REPORTS)?];
+    for project in bare_projects(db)? {
+        for prefix in project_trees {
+            trees.push(db.open_tree(project.tree_name(prefix))?);
+        }
+    }
+    // Last, since the project list is read from it
+    trees.push(db.open_tree(TreeNames::PROJECTS)?);
+
+    // Each tree is its own batch and the version is only bumped at the end, so after a
+    // crash this runs again over trees that are already done. Wrapped values are skipped.
+    for tree in trees {
+        let mut batch = sled::Batch::default();
+
+        for item in tree.iter() {
+            let (key, value) = item?;
+            if is_wrapped(&value) {
+                continue;
+            }
+
+            match wrap_unversioned(&value) {
+                Ok(wrapped) => batch.insert(key, wrapped),
+                Err(err) => set_aside(db, &tree, &key, &value, &mut batch, &err)?,
+            }
+        }
+
+        tree.apply_batch(batch)?;
+    }
+
+    // Every preference but the schema version, which stays bare so it can always be read
+    let prefs = db.open_tree("preferences")?;
+    for key in [b"hashMode".as_ref(), b"strongHash", b"appdata"] {
+        if let Some(value) = prefs.get(key)? {
+            if !is_wrapped(&value) {
+                prefs.insert(key, wrap_unversioned(&value)?)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chrono::{TimeZone, Utc};
+    use serde_cbor::{from_slice, to_vec};
+
+    use super::{
+        bare_projects, content_hashes_v4, latest_schema_version, migrate, migrate_with_backup,
+        path_keys_v1, project_paths_v2, project_registry_v3, record_envelopes_v5,
+        register_bare_project, schema_version, set_schema_version, v1, v3, SET_ASIDE_TREE,
+    };
+    use crate::db::{
+        compare::{compare_project, compare_three_trees},
+        content_hash::ContentHash,
+        hashing::{hash_mode, HashMode},
+        keys::encode_path_key,
+        project_path::ProjectPath,
+        projects::list_projects,
+        record::decode,
+        test_support::path,
+        typed_tree::TypedTree,
+        types::{
+            ConflictReason, DiffTypes, LocalFileData, LocalFileMetadata, ThreeWayDiffTypes,
+            TreeNames,
+        },
+    };
+
+    #[test]
+    fn test_fresh_db_is_latest() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+
+        migrate(&db).unwrap();
+
+        assert_eq!(latest_schema_version(), schema_version(&db).unwrap());
+    }
+
+    #[test]
+    fn test_path_keys_v1() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let root = "/projects/robot";
+        let tree = db
+            .open_tree(TreeNames::HASH_LOCAL_METDATA.to_owned() + root)
+            .unwrap();
+        let path = PathBuf::from("/projects/robot/assemblies/gearbox.sldasm");
+        tree.insert(to_vec(&path).unwrap(), b"value".to_vec()).unwrap();
+        // Not a tree we know about, has to be left alone
+        let other = db.open_tree("unrelated").unwrap();
+        other.insert(to_vec(&path).unwrap(), b"value".to_vec()).unwrap();
+
+        path_keys_v1(&db).unwrap();
+
+        let expected_key = encode_path_key(&ProjectPath::parse("assemblies/gearbox.sldasm").unwrap());
+        let keys: Vec<Vec<u8>> = tree.iter().keys().map(|key| key.unwrap().to_vec()).collect();
+        assert_eq!(vec![expected_key], keys);
+        assert!(other.contains_key(to_vec(&path).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_project_paths_v2() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let root = "/projects/robot";
+        let tree = db
+            .open_tree(TreeNames::HASH_REMOTE_METDATA.to_owned() + root)
+            .unwrap();
+        let old = v1::LocalFileData {
+            name: "gearbox.sldasm".to_owned(),
+            hash: 7,
+            metadata: v1::LocalFileMetadata {
+                path: PathBuf::from("/projects/robot/assemblies/gearbox.sldasm"),
+                size: 10,
+                modified: Utc.timestamp(100, 0),
+                update_time: Utc.timestamp(100, 0),
+            },
+        };
+        tree.insert(b"key", to_vec(&old).unwrap()).unwrap();
+
+        project_paths_v2(&db).unwrap();
+
+        let new: v3::LocalFileData = from_slice(&tree.get(b"key").unwrap().unwrap()).unwrap();
+        assert_eq!(
+            ProjectPath::parse("assemblies/gearbox.sldasm").unwrap(),
+            new.metadata.path
+        );
+        assert_eq!(7, new.hash);
+
+        // As if it was cut off before the version was bumped
+        project_paths_v2(&db).unwrap();
+
+        let again: v3::LocalFileData = from_slice(&tree.get(b"key").unwrap().unwrap()).unwrap();
+        assert_eq!(new.metadata.path, again.metadata.path);
+        assert!(db.open_tree(SET_ASIDE_TREE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_project_registry_v3() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let root = "/projects/robot";
+        let old_name = TreeNames::HASH_LOCAL_METDATA.to_owned() + root;
+        let tree = db.open_tree(&old_name).unwrap();
+        tree.insert(b"key", b"value".to_vec()).unwrap();
+
+        project_registry_v3(&db).unwrap();
+
+        let projects = bare_projects(&db).unwrap();
+        assert_eq!(1, projects.len());
+        assert_eq!(PathBuf::from(root), projects[0].root);
+
+        let tree = db
+            .open_tree(projects[0].tree_name(TreeNames::HASH_LOCAL_METDATA))
+            .unwrap();
+        assert_eq!(Some(b"value".as_ref().into()), tree.get(b"key").unwrap());
+        assert!(!db.tree_names().contains(&old_name.as_bytes().into()));
+    }
+
+    #[test]
+    fn test_project_registry_v3_reruns() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let root = "/projects/robot";
+        for prefix in [TreeNames::HASH_LOCAL_METDATA, TreeNames::HASH_HEAD_METDATA] {
+            let tree = db.open_tree(prefix.to_owned() + root).unwrap();
+            tree.insert(b"key", prefix.as_bytes().to_vec()).unwrap();
+        }
+        // Cut off after the project was registered and one tree was moved
+        let project = register_bare_project(&db, PathBuf::from(root)).unwrap();
+        db.open_tree(project.tree_name(TreeNames::HASH_LOCAL_METDATA))
+            .unwrap()
+            .insert(b"key", TreeNames::HASH_LOCAL_METDATA.as_bytes().to_vec())
+            .unwrap();
+
+        project_registry_v3(&db).unwrap();
+        project_registry_v3(&db).unwrap();
+
+        assert_eq!(vec![project.clone()], bare_projects(&db).unwrap());
+        for prefix in [TreeNames::HASH_LOCAL_METDATA, TreeNames::HASH_HEAD_METDATA] {
+            let tree = db.open_tree(project.tree_name(prefix)).unwrap();
+            assert_eq!(Some(prefix.as_bytes().into()), tree.get(b"key").unwrap());
+            assert!(!db.tree_names().contains(&(prefix.to_owned() + root).as_bytes().into()));
+        }
+    }
+
+    #[test]
+    fn test_content_hashes_v4() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let project = register_bare_project(&db, PathBuf::from("/projects/robot")).unwrap();
+        let tree = db
+            .open_tree(project.tree_name(TreeNames::HASH_HEAD_METDATA))
+            .unwrap();
+        let old = v3::LocalFileData {
+            name: "gearbox.sldasm".to_owned(),
+            hash: 7,
+            metadata: LocalFileMetadata {
+                path: ProjectPath::parse("assemblies/gearbox.sldasm").unwrap(),
+                size: 10,
+                modified: Utc.timestamp(100, 0),
+                update_time: Utc.timestamp(100, 0),
+            },
+        };
+        tree.insert(b"key", to_vec(&old).unwrap()).unwrap();
+
+        content_hashes_v4(&db).unwrap();
+
+        let new: LocalFileData = from_slice(&tree.get(b"key").unwrap().unwrap()).unwrap();
+        assert_eq!(ContentHash::xxh3_64(7), new.hash);
+        assert_eq!(None, new.strong_hash);
+        assert_eq!(old.metadata, new.metadata);
+    }
+
+    #[test]
+    fn test_record_envelopes_v5() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let project = register_bare_project(&db, PathBuf::from("/projects/robot")).unwrap();
+        let tree = db
+            .open_tree(project.tree_name(TreeNames::BASIC_LOCAL_METADATA))
+            .unwrap();
+        let metadata = LocalFileMetadata {
+            path: ProjectPath::parse("assemblies/gearbox.sldasm").unwrap(),
+            size: 10,
+            modified: Utc.timestamp(100, 0),
+            update_time: Utc.timestamp(100, 0),
+        };
+        tree.insert(b"key", to_vec(&metadata).unwrap()).unwrap();
+        tree.insert(b"junk", b"\xff".to_vec()).unwrap();
+        let prefs = db.open_tree("preferences").unwrap();
+        prefs.insert(b"hashMode", to_vec(&HashMode::Mmap).unwrap()).unwrap();
+        set_schema_version(&db, 4).unwrap();
+
+        record_envelopes_v5(&db).unwrap();
+        // As if it was cut off before the version was bumped
+        record_envelopes_v5(&db).unwrap();
+
+        assert_eq!(vec![project], list_projects(&db).unwrap());
+        let migrated: LocalFileMetadata = decode(&tree.get(b"key").unwrap().unwrap()).unwrap();
+        assert_eq!(metadata, migrated);
+        assert!(tree.get(b"junk").unwrap().is_none());
+        let mut aside_key = tree.name().to_vec();
+        aside_key.extend_from_slice(b"\0junk");
+        assert_eq!(
+            Some(b"\xff".as_ref()),
+            db.open_tree(SET_ASIDE_TREE)
+                .unwrap()
+                .get(aside_key)
+                .unwrap()
+                .as_deref()
+        );
+        assert_eq!(HashMode::Mmap, hash_mode(&db).unwrap());
+        // Still bare, or nothing could tell which migrations to run
+        assert_eq!(4, schema_version(&db).unwrap());
+    }
+
+    #[test]
+    fn test_backup_before_migrate() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::open(dir.path().join("splatcad.db")).unwrap();
+        register_bare_project(&db, PathBuf::from("/projects/robot")).unwrap();
+        set_schema_version(&db, 4).unwrap();
+
+        let backup_path = migrate_with_backup(&db, dir.path()).unwrap().unwrap();
+
+        assert_eq!(latest_schema_version(), schema_version(&db).unwrap());
+        let backup = sled::open(&backup_path).unwrap();
+        assert_eq!(4, schema_version(&backup).unwrap());
+        assert_eq!(1, bare_projects(&backup).unwrap().len());
+
+        // Nothing left to migrate, so no more backups
+        assert_eq!(None, migrate_with_backup(&db, dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_migrated_hashes_still_diff() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let project = register_bare_project(&db, PathBuf::from("/projects/robot")).unwrap();
+        let legacy = |file_path: &str, hash: u128| v3::LocalFileData {
+            name: file_path.to_owned(),
+            hash,
+            metadata: LocalFileMetadata {
+                path: path(file_path),
+                size: 10,
+                modified: Utc.timestamp(100, 0),
+                update_time: Utc.timestamp(100, 0),
+            },
+        };
+        let insert = |prefix: &str, file_path: &str, value: Vec<u8>| {
+            db.open_tree(project.tree_name(prefix))
+                .unwrap()
+                .insert(encode_path_key(&path(file_path)), value)
+                .unwrap();
+        };
+
+        // In sync before the upgrade, then nut.sldprt was moved into parts/
+        for prefix in [TreeNames::HASH_HEAD_METDATA, TreeNames::HASH_REMOTE_METDATA] {
+            insert(prefix, "bolt.sldprt", to_vec(&legacy("bolt.sldprt", 1)).unwrap());
+            insert(prefix, "nut.sldprt", to_vec(&legacy("nut.sldprt", 2)).unwrap());
+        }
+        insert(
+            TreeNames::HASH_LOCAL_METDATA,
+            "bolt.sldprt",
+            to_vec(&legacy("bolt.sldprt", 1)).unwrap(),
+        );
+        let tombstone = v3::Tombstone {
+            data: legacy("nut.sldprt", 2),
+            deleted_time: Utc.timestamp(200, 0),
+        };
+        insert(
+            TreeNames::LOCAL_TOMBSTONES,
+            "nut.sldprt",
+            to_vec(&tombstone).unwrap(),
+        );
+        set_schema_version(&db, 3).unwrap();
+
+        migrate(&db).unwrap();
+
+        // What the first scan after the upgrade writes, hashed with xxh3-128
+        let local: TypedTree<ProjectPath, LocalFileData> =
+            TypedTree::open(&db, project.tree_name(TreeNames::HASH_LOCAL_METDATA)).unwrap();
+        for (file_path, hash) in [("bolt.sldprt", 11), ("parts/nut.sldprt", 12)] {
+            let mut data = local.get(&path("bolt.sldprt")).unwrap().unwrap();
+            data.name = file_path.to_owned();
+            data.hash = ContentHash::xxh3_128(hash);
+            data.metadata.path = path(file_path);
+            local.insert(&path(file_path), &data).unwrap();
+        }
+
+        // The bolt's old hash says nothing about the new one, so it's up to someone to look
+        let diffs = compare_project(&project, None, &db).unwrap();
+        let kinds: Vec<_> = diffs.iter().map(|diff| diff.diff_type.clone()).collect();
+        assert!(matches!(
+            &kinds[..],
+            [
+                DiffTypes::Conflict {
+                    reason: ConflictReason::HashesNotComparable,
+                    ..
+                },
+                DiffTypes::Renamed { from, to },
+            ] if from == &path("nut.sldprt") && to == &path("parts/nut.sldprt")
+        ));
+
+        let three_way = compare_three_trees(
+            project.tree_name(TreeNames::HASH_LOCAL_METDATA),
+            project.tree_name(TreeNames::HASH_HEAD_METDATA),
+            project.tree_name(TreeNames::HASH_REMOTE_METDATA),
+            &db,
+        )
+        .unwrap();
+        assert_eq!(ThreeWayDiffTypes::LocalModified, three_way[0].diff_type);
+    }
+}
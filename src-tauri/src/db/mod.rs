@@ -1,4 +1,28 @@
 pub mod types;
+pub mod chunking;
+pub mod commits;
 pub mod compare;
+pub mod content_hash;
+pub mod hashing;
+pub mod history;
+pub mod ignore_rules;
+pub mod keys;
+pub mod memory_store;
+pub mod migrate;
+pub mod objects;
+pub mod project_path;
+pub mod projects;
+pub mod record;
 pub mod refresh_state;
+pub mod remote_state;
+pub mod renames;
+pub mod restore;
+pub mod scan;
 pub mod setup;
+pub mod staging;
+pub mod store;
+pub mod tags;
+#[cfg(test)]
+pub mod test_support;
+pub mod typed_tree;
+pub mod verify;
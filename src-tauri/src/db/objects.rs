@@ -0,0 +1,488 @@
+//! The bytes of every committed file version, so old versions can be had offline.
+//!
+//! Objects live under the app dir, one file each, named by the xxh3-128 hash in the
+//! file's `LocalFileData`. The same contents are only ever stored once, however many
+//! files or commits share them. Objects can be zstd compressed, those end in `.zst`.
+//!
+//! The `objects` tree counts how many commit changes point at each object. Once nothing
+//! does, the object is deleted. Counts are only changed while holding `REFS`, so two
+//! commits, or a commit and a history removal, can't lose each other's changes.
+//!
+//! Only commits fill the store for now. Syncing happens in the frontend, so downloaded
+//! versions don't come through here until they're committed.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{
+        content_hash::{ContentHash, HashAlgorithm},
+        hashing::{hash_reader, reserve_memory, HASH_BUFFER_SIZE},
+        record::{decode, encode},
+        store::{MetadataStore, WriteBatch},
+        typed_tree::{TypedBatch, TypedTree},
+        types::{Commit, TreeNames},
+    },
+    error::Result,
+};
+
+const COMPRESSION_KEY: &[u8] = b"objectCompression";
+
+// Held from reading a ref count until the change to it is applied
+static REFS: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+fn lock_refs() -> Result<MutexGuard<'static, ()>> {
+    REFS.lock()
+        .map_err(|_| "Object ref lock was poisoned".to_string().into())
+}
+
+/// How objects are written. Reading handles either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ObjectCompression {
+    #[default]
+    None,
+    // 1 to 22, higher is smaller and slower. 3 is zstd's default.
+    Zstd {
+        level: i32,
+    },
+}
+
+/// What's known about a stored object
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ObjectRecord {
+    // Of the contents, not of what's on disk
+    pub size: u64,
+    pub stored_size: u64,
+    pub compressed: bool,
+    // Commit changes pointing at it
+    pub refs: u64,
+}
+
+/// An object that was just written, or found to be there already
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub hash: ContentHash,
+    pub size: u64,
+    pub stored_size: u64,
+    pub compressed: bool,
+}
+
+/// The object compression set in the preferences, none if it was never set
+pub fn object_compression(db: &dyn MetadataStore) -> Result<ObjectCompression> {
+    let prefs = db.open_tree("preferences")?;
+
+    Ok(match prefs.get(COMPRESSION_KEY)? {
+        Some(compression) => decode(&compression)?,
+        None => ObjectCompression::default(),
+    })
+}
+
+pub fn set_object_compression(
+    db: &dyn MetadataStore,
+    compression: ObjectCompression,
+) -> Result<()> {
+    if let ObjectCompression::Zstd { level } = compression {
+        if !zstd::compression_level_range().contains(&level) {
+            return Err(format!("{} isn't a zstd compression level", level).into());
+        }
+    }
+
+    let prefs = db.open_tree("preferences")?;
+    prefs.insert(COMPRESSION_KEY, encode(&compression)?)?;
+    Ok(())
+}
+
+fn object_records(db: &dyn MetadataStore) -> Result<TypedTree<ContentHash, ObjectRecord>> {
+    TypedTree::open(db, TreeNames::OBJECTS)
+}
+
+/// The directory objects are kept in
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    root: PathBuf,
+}
+
+impl ObjectStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    // <root>/<algorithm>/<first two hex digits>/<the rest>, so no folder gets too big
+    fn object_path(&self, hash: &ContentHash, compressed: bool) -> PathBuf {
+        let hex = hash.to_hex();
+        let (folder, name) = hex.split_at(2);
+        let path = self
+            .root
+            .join(hash.algorithm().name())
+            .join(folder)
+            .join(name);
+
+        if compressed {
+            path.with_extension("zst")
+        } else {
+            path
+        }
+    }
+
+    /// Where the object is, and whether it's compressed
+    fn find(&self, hash: &ContentHash) -> Option<(PathBuf, bool)> {
+        [false, true].into_iter().find_map(|compressed| {
+            let path = self.object_path(hash, compressed);
+            path.is_file().then_some((path, compressed))
+        })
+    }
+
+    pub fn contains(&self, hash: &ContentHash) -> bool {
+        self.find(hash).is_some()
+    }
+
+    /// The contents of an object, decompressed
+    pub fn open(&self, hash: &ContentHash) -> Result<Box<dyn Read + Send>> {
+        let (path, compressed) = self
+            .find(hash)
+            .ok_or_else(|| format!("No local copy of {}", hash))?;
+        let file = File::open(path)?;
+
+        Ok(if compressed {
+            Box::new(zstd::Decoder::new(file)?)
+        } else {
+            Box::new(BufReader::new(file))
+        })
+    }
+
+    pub fn read(&self, hash: &ContentHash) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.open(hash)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Write the object out to a new file at `to`, checking it's still what it should be.
+    /// Blocks, and `to` is left half written if it fails.
+    pub fn copy_to(&self, hash: &ContentHash, to: &Path) -> Result<()> {
+        let mut buffer = vec![0; HASH_BUFFER_SIZE];
+        let mut tee = Tee::new(self.open(hash)?, File::create(to)?);
+        let hashes = hash_reader(&mut tee, &mut buffer, None)?;
+        tee.out.sync_all()?;
+
+        // Hashes from before xxh3-128 can't be checked
+        if hash.algorithm() == HashAlgorithm::Xxh3_128 && &hashes.hash != hash {
+            return Err(format!(
+                "The local copy of {} is corrupt, it's {} now",
+                hash, hashes.hash
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Copy the file at `path` in as the object for `hash`, unless it's there already.
+    /// Fails if the file doesn't hash to `hash` anymore.
+    pub async fn store_file(
+        &self,
+        path: PathBuf,
+        hash: ContentHash,
+        compression: ObjectCompression,
+    ) -> Result<StoredObject> {
+        let _permit = reserve_memory(HASH_BUFFER_SIZE).await?;
+        let objects = self.clone();
+
+        tokio::task::spawn_blocking(move || objects.store_path(&path, &hash, compression))
+            .await
+            .map_err(|err| format!("Storing task failed: {}", err))?
+    }
+
+    fn store_path(
+        &self,
+        path: &Path,
+        hash: &ContentHash,
+        compression: ObjectCompression,
+    ) -> Result<StoredObject> {
+        if let Some((object_path, compressed)) = self.find(hash) {
+            let stored_size = object_path.metadata()?.len();
+            let size = if compressed {
+                io::copy(&mut self.open(hash)?, &mut io::sink())?
+            } else {
+                stored_size
+            };
+
+            return Ok(StoredObject {
+                hash: hash.clone(),
+                size,
+                stored_size,
+                compressed,
+            });
+        }
+
+        let compressed = matches!(compression, ObjectCompression::Zstd { .. });
+        let object_path = self.object_path(hash, compressed);
+        let folder = object_path
+            .parent()
+            .ok_or_else(|| format!("Object path {} has no parent", object_path.display()))?;
+        fs::create_dir_all(folder)?;
+
+        // Written next to where it goes under a name nobody else is writing to, then
+        // renamed, so a half-written object never shows up under its hash. The temp file
+        // is deleted if anything goes wrong before that.
+        let temp = tempfile::NamedTempFile::new_in(folder)?;
+        let (size, actual) = write_object(path, temp.as_file(), compression)?;
+        if &actual != hash {
+            return Err(format!(
+                "{} changed since it was scanned, it's {} now and not {}",
+                path.display(),
+                actual,
+                hash
+            )
+            .into());
+        }
+        temp.persist(&object_path).map_err(|err| err.error)?;
+
+        Ok(StoredObject {
+            hash: hash.clone(),
+            size,
+            stored_size: object_path.metadata()?.len(),
+            compressed,
+        })
+    }
+
+    fn delete(&self, hash: &ContentHash) -> Result<()> {
+        if let Some((path, _)) = self.find(hash) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy `from` into `out`, returning the size and xxh3-128 hash of what was read
+fn write_object(
+    from: &Path,
+    out: &File,
+    compression: ObjectCompression,
+) -> Result<(u64, ContentHash)> {
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+
+    let (hashes, read) = match compression {
+        ObjectCompression::None => {
+            let mut tee = Tee::new(File::open(from)?, out);
+            let hashes = hash_reader(&mut tee, &mut buffer, None)?;
+            tee.out.sync_all()?;
+            (hashes, tee.read)
+        }
+        ObjectCompression::Zstd { level } => {
+            let mut tee = Tee::new(File::open(from)?, zstd::Encoder::new(out, level)?);
+            let hashes = hash_reader(&mut tee, &mut buffer, None)?;
+            tee.out.finish()?.sync_all()?;
+            (hashes, tee.read)
+        }
+    };
+
+    Ok((read, hashes.hash))
+}
+
+/// Passes everything read from `inner` on to `out` too
+struct Tee<R, W> {
+    inner: R,
+    out: W,
+    read: u64,
+}
+
+impl<R: Read, W: Write> Tee<R, W> {
+    fn new(inner: R, out: W) -> Self {
+        Self {
+            inner,
+            out,
+            read: 0,
+        }
+    }
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.out.write_all(&buf[..read])?;
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+/// The stored object for every file version in `commit`
+pub async fn store_commit_objects(
+    commit: &Commit,
+    root: &Path,
+    objects: &ObjectStore,
+    db: &dyn MetadataStore,
+) -> Result<Vec<StoredObject>> {
+    let compression = object_compression(db)?;
+    let mut stored = Vec::new();
+
+    for change in &commit.changes {
+        if let Some(data) = &change.data {
+            let path = change.path.to_absolute(root);
+            stored.push(
+                objects
+                    .store_file(path, data.hash.clone(), compression)
+                    .await?,
+            );
+        }
+    }
+
+    Ok(stored)
+}
+
+/// Count a reference to each of `stored`, applied together with `batches`. Fails if one
+/// of them was deleted since it was stored, or if `check` fails. It runs under the same
+/// lock, so whatever it reads can't change before the batches are applied.
+pub fn add_refs(
+    stored: &[StoredObject],
+    batches: &[(&str, &WriteBatch)],
+    check: impl FnOnce() -> Result<()>,
+    objects: &ObjectStore,
+    db: &dyn MetadataStore,
+) -> Result<()> {
+    let _refs = lock_refs()?;
+    check()?;
+    let records = object_records(db)?;
+    let mut counts: HashMap<&ContentHash, (&StoredObject, u64)> = HashMap::new();
+    for object in stored {
+        counts.entry(&object.hash).or_insert((object, 0)).1 += 1;
+    }
+
+    let mut batch: TypedBatch<ContentHash, ObjectRecord> = TypedBatch::default();
+    for (hash, (object, count)) in counts {
+        // Whatever dropped its last reference deleted it
+        if !objects.contains(hash) {
+            return Err(format!("The local copy of {} was removed, commit again", hash).into());
+        }
+
+        let record = match records.get(hash)? {
+            Some(record) => ObjectRecord {
+                refs: record.refs + count,
+                ..record
+            },
+            None => ObjectRecord {
+                size: object.size,
+                stored_size: object.stored_size,
+                compressed: object.compressed,
+                refs: count,
+            },
+        };
+        batch.insert(hash, &record)?;
+    }
+
+    let mut batches = batches.to_vec();
+    batches.push((TreeNames::OBJECTS, batch.inner()));
+    db.apply_batches(&batches)
+}
+
+/// Drop the references `commits` hold, deleting objects nothing points at anymore
+pub fn release_refs(
+    commits: &[Commit],
+    objects: &ObjectStore,
+    db: &dyn MetadataStore,
+) -> Result<()> {
+    let _refs = lock_refs()?;
+    let records = object_records(db)?;
+    let mut counts: HashMap<&ContentHash, u64> = HashMap::new();
+    for commit in commits {
+        for data in commit
+            .changes
+            .iter()
+            .filter_map(|change| change.data.as_ref())
+        {
+            *counts.entry(&data.hash).or_default() += 1;
+        }
+    }
+
+    let mut batch = TypedBatch::default();
+    let mut unused = Vec::new();
+    for (hash, count) in counts {
+        match records.get(hash)? {
+            Some(record) if record.refs > count => batch.insert(
+                hash,
+                &ObjectRecord {
+                    refs: record.refs - count,
+                    ..record
+                },
+            )?,
+            Some(_) => {
+                batch.remove(hash);
+                unused.push(hash);
+            }
+            None => {}
+        }
+    }
+    records.apply_batch(&batch)?;
+
+    // Only once nothing can find them through the tree
+    for hash in unused {
+        objects.delete(hash)?;
+    }
+
+    Ok(())
+}
+
+/// What's known about the object for `hash`, if it's stored
+pub fn object_record(hash: &ContentHash, db: &dyn MetadataStore) -> Result<Option<ObjectRecord>> {
+    object_records(db)?.get(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use xxhash_rust::xxh3::xxh3_128;
+
+    use super::{ObjectCompression, ObjectStore};
+    use crate::db::content_hash::ContentHash;
+
+    #[tokio::test]
+    async fn test_store_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        let contents = b"bolt bolt bolt bolt bolt bolt bolt bolt".repeat(100);
+        let hash = ContentHash::xxh3_128(xxh3_128(&contents));
+        fs::write(dir.path().join("bolt.sldprt"), &contents).unwrap();
+        fs::write(dir.path().join("copy.sldprt"), &contents).unwrap();
+
+        let compression = ObjectCompression::Zstd { level: 3 };
+        let stored = objects
+            .store_file(dir.path().join("bolt.sldprt"), hash.clone(), compression)
+            .await
+            .unwrap();
+        assert!(stored.compressed);
+        assert_eq!(contents.len() as u64, stored.size);
+        assert!(stored.stored_size < stored.size);
+        assert_eq!(contents, objects.read(&hash).unwrap());
+
+        // Already there, so it's the same object even though compression is off now
+        let again = objects
+            .store_file(
+                dir.path().join("copy.sldprt"),
+                hash.clone(),
+                ObjectCompression::None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(stored, again);
+
+        // A file that doesn't match its hash anymore isn't stored
+        fs::write(dir.path().join("bolt.sldprt"), b"changed").unwrap();
+        let other = ContentHash::xxh3_128(1);
+        assert!(objects
+            .store_file(
+                dir.path().join("bolt.sldprt"),
+                other.clone(),
+                ObjectCompression::None
+            )
+            .await
+            .is_err());
+        assert!(!objects.contains(&other));
+    }
+}
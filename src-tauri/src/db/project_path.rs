@@ -0,0 +1,204 @@
+//! Paths inside a project, independent of where the project lives and which OS it's on.
+//!
+//! Everything stored in the DB or exchanged with the remote uses `ProjectPath`, so a
+//! teammate's state pushed from macOS lines up with ours on Windows. Converting to an
+//! absolute `PathBuf` only happens right at the filesystem boundary.
+
+use std::{
+    cmp::Ordering,
+    convert::TryFrom,
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// A path relative to the project root, with `/` separators and no `.` or `..` components.
+/// The empty path is the root itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ProjectPath(String);
+
+impl ProjectPath {
+    /// Parse a relative path, accepting either separator
+    pub fn parse(path: &str) -> Result<Self> {
+        if path.starts_with('/') || path.starts_with('\\') {
+            return Err(format!("Project path {} must be relative", path).into());
+        }
+
+        let mut components = Vec::new();
+        for component in path.split(['/', '\\']) {
+            match component {
+                "" | "." => continue,
+                ".." => return Err(format!("Project path {} must not contain ..", path).into()),
+                component => components.push(component),
+            }
+        }
+
+        Ok(Self(components.join("/")))
+    }
+
+    /// Make a project path from an absolute path under `root`
+    pub fn from_absolute(root: &Path, path: &Path) -> Result<Self> {
+        let relative = path.strip_prefix(root).map_err(|_| {
+            format!(
+                "Path {} is not under project root {}",
+                path.display(),
+                root.display()
+            )
+        })?;
+
+        let mut components = Vec::new();
+        for component in relative.components() {
+            match component {
+                Component::Normal(component) => components.push(
+                    component
+                        .to_str()
+                        .ok_or_else(|| format!("Path {} is not valid UTF-8", path.display()))?,
+                ),
+                Component::CurDir => continue,
+                _ => return Err(format!("Path {} can't be made relative", path.display()).into()),
+            }
+        }
+
+        Ok(Self(components.join("/")))
+    }
+
+    /// Where this path is on disk for a project living at `root`
+    pub fn to_absolute(&self, root: &Path) -> PathBuf {
+        let mut path = root.to_path_buf();
+        path.extend(self.components());
+        path
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = &str> {
+        self.0.split('/').filter(|component| !component.is_empty())
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        self.components().last()
+    }
+
+    pub fn parent(&self) -> Option<ProjectPath> {
+        if self.is_root() {
+            return None;
+        }
+
+        Some(match self.0.rfind('/') {
+            Some(index) => Self(self.0[..index].to_owned()),
+            None => Self::default(),
+        })
+    }
+
+    pub fn join(&self, name: &str) -> Result<ProjectPath> {
+        if self.is_root() {
+            return Self::parse(name);
+        }
+
+        Self::parse(&format!("{}/{}", self.0, name))
+    }
+
+    /// Whether this is `folder` or anything inside it
+    pub fn starts_with(&self, folder: &ProjectPath) -> bool {
+        folder.is_root()
+            || self.0 == folder.0
+            || (self.0.starts_with(&folder.0) && self.0[folder.0.len()..].starts_with('/'))
+    }
+}
+
+/// Ordered component by component, the same as `Path` and the sled keys
+impl Ord for ProjectPath {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.components().cmp(other.components())
+    }
+}
+
+impl PartialOrd for ProjectPath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for ProjectPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl TryFrom<String> for ProjectPath {
+    type Error = Error;
+
+    fn try_from(path: String) -> Result<Self> {
+        Self::parse(&path)
+    }
+}
+
+impl From<ProjectPath> for String {
+    fn from(path: ProjectPath) -> Self {
+        path.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::ProjectPath;
+
+    #[test]
+    fn test_parse_normalizes_separators() {
+        let path = ProjectPath::parse(r"assemblies\gearbox/./housing.sldprt").unwrap();
+
+        assert_eq!("assemblies/gearbox/housing.sldprt", path.as_str());
+        assert!(ProjectPath::parse("/absolute").is_err());
+        assert!(ProjectPath::parse("a/../escape").is_err());
+    }
+
+    #[test]
+    fn test_absolute_round_trip() {
+        let root = PathBuf::from("/projects/robot");
+        let absolute = root.join("assemblies").join("gearbox.sldasm");
+
+        let path = ProjectPath::from_absolute(&root, &absolute).unwrap();
+
+        assert_eq!("assemblies/gearbox.sldasm", path.as_str());
+        assert_eq!(absolute, path.to_absolute(&root));
+        assert!(ProjectPath::from_absolute(&root, Path::new("/elsewhere/part")).is_err());
+    }
+
+    #[test]
+    fn test_order_is_component_wise() {
+        let mut paths: Vec<ProjectPath> = ["a.txt", "a/b", "ab", "a"]
+            .iter()
+            .map(|path| ProjectPath::parse(path).unwrap())
+            .collect();
+
+        paths.sort();
+
+        let paths: Vec<&str> = paths.iter().map(|path| path.as_str()).collect();
+        assert_eq!(vec!["a", "a/b", "a.txt", "ab"], paths);
+    }
+
+    #[test]
+    fn test_starts_with() {
+        let folder = ProjectPath::parse("assemblies/gearbox").unwrap();
+
+        assert!(ProjectPath::parse("assemblies/gearbox/housing").unwrap().starts_with(&folder));
+        assert!(!ProjectPath::parse("assemblies/gearbox2").unwrap().starts_with(&folder));
+        assert_eq!(folder, ProjectPath::default().join("assemblies/gearbox").unwrap());
+        assert_eq!(
+            Some(ProjectPath::parse("assemblies").unwrap()),
+            folder.parent()
+        );
+    }
+}
@@ -0,0 +1,165 @@
+//! The project registry.
+//!
+//! Every local project gets a stable ID when it's registered. Per-project trees are
+//! named by that ID rather than by the root path, so moving the folder on disk is
+//! just a relink instead of a fresh, empty namespace.
+
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+
+use crate::{
+    db::{
+        commits::remove_history,
+        objects::ObjectStore,
+        store::MetadataStore,
+        tags::remove_tags,
+        typed_tree::TypedTree,
+        types::{Project, ProjectId, ScanReport, TreeNames},
+    },
+    error::Result,
+};
+
+fn projects(db: &dyn MetadataStore) -> Result<TypedTree<ProjectId, Project>> {
+    TypedTree::open(db, TreeNames::PROJECTS)
+}
+
+fn scan_reports(db: &dyn MetadataStore) -> Result<TypedTree<ProjectId, ScanReport>> {
+    TypedTree::open(db, TreeNames::SCAN_REPORTS)
+}
+
+pub fn get_project(db: &dyn MetadataStore, id: ProjectId) -> Result<Project> {
+    match projects(db)?.get(&id)? {
+        Some(project) => Ok(project),
+        None => Err(format!("No project with ID {}", id).into()),
+    }
+}
+
+pub fn save_project(db: &dyn MetadataStore, project: &Project) -> Result<()> {
+    projects(db)?.insert(&project.id, project)?;
+    Ok(())
+}
+
+pub fn list_projects(db: &dyn MetadataStore) -> Result<Vec<Project>> {
+    projects(db)?
+        .iter()
+        .map(|item| Ok(item?.1))
+        .collect()
+}
+
+pub fn find_project_by_root(db: &dyn MetadataStore, root: &Path) -> Result<Option<Project>> {
+    Ok(list_projects(db)?
+        .into_iter()
+        .find(|project| project.root == root))
+}
+
+/// The report of the project's last full scan, if it's been scanned since reports were kept
+pub fn last_scan_report(db: &dyn MetadataStore, id: ProjectId) -> Result<Option<ScanReport>> {
+    scan_reports(db)?.get(&id)
+}
+
+/// Keep `report` as its project's last one
+pub fn save_scan_report(db: &dyn MetadataStore, report: &ScanReport) -> Result<()> {
+    scan_reports(db)?.insert(&report.project_id, report)?;
+    Ok(())
+}
+
+/// Register a new project living at `root`
+pub fn register_project(
+    db: &dyn MetadataStore,
+    root: PathBuf,
+    cloud_project_id: Option<u64>,
+) -> Result<Project> {
+    if let Some(existing) = find_project_by_root(db, &root)? {
+        return Err(format!(
+            "{} is already registered as project {}",
+            root.display(),
+            existing.id
+        )
+        .into());
+    }
+
+    let project = Project {
+        id: db.generate_id()?,
+        root,
+        cloud_project_id,
+        created: Utc::now(),
+        last_scanned: None,
+    };
+    save_project(db, &project)?;
+
+    Ok(project)
+}
+
+/// Point an existing project at a new root, keeping all of its state
+pub fn relink_project(db: &dyn MetadataStore, id: ProjectId, root: PathBuf) -> Result<Project> {
+    if let Some(existing) = find_project_by_root(db, &root)? {
+        if existing.id != id {
+            return Err(format!(
+                "{} is already registered as project {}",
+                root.display(),
+                existing.id
+            )
+            .into());
+        }
+    }
+
+    let mut project = get_project(db, id)?;
+    project.root = root;
+    save_project(db, &project)?;
+
+    Ok(project)
+}
+
+/// Forget a project and drop every tree that belongs to it, and the objects only it used
+pub fn remove_project(db: &dyn MetadataStore, objects: &ObjectStore, id: ProjectId) -> Result<()> {
+    let project = get_project(db, id)?;
+
+    for tree_name in TreeNames::PROJECT_TREES {
+        db.drop_tree(&project.tree_name(tree_name))?;
+    }
+    remove_history(&project, objects, db)?;
+    remove_tags(&project, db)?;
+
+    projects(db)?.remove(&id)?;
+    scan_reports(db)?.remove(&id)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{get_project, list_projects, register_project, relink_project, remove_project};
+    use crate::db::{objects::ObjectStore, types::TreeNames};
+
+    #[test]
+    fn test_register_relink_remove() {
+        let db = sled::Config::default().temporary(true).open().unwrap();
+
+        let project = register_project(&db, PathBuf::from("/projects/robot"), Some(12)).unwrap();
+        assert!(register_project(&db, PathBuf::from("/projects/robot"), None).is_err());
+
+        let tree = db
+            .open_tree(project.tree_name(TreeNames::HASH_LOCAL_METDATA))
+            .unwrap();
+        tree.insert(b"key", b"value".to_vec()).unwrap();
+
+        let relinked = relink_project(&db, project.id, PathBuf::from("/moved/robot")).unwrap();
+        assert_eq!(PathBuf::from("/moved/robot"), get_project(&db, project.id).unwrap().root);
+        // Same ID, so the same trees
+        assert_eq!(
+            project.tree_name(TreeNames::HASH_LOCAL_METDATA),
+            relinked.tree_name(TreeNames::HASH_LOCAL_METDATA)
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        remove_project(&db, &ObjectStore::new(dir.path()), project.id).unwrap();
+        assert!(list_projects(&db).unwrap().is_empty());
+        let tree = db
+            .open_tree(project.tree_name(TreeNames::HASH_LOCAL_METDATA))
+            .unwrap();
+        assert!(tree.is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+//! How records are stored in sled.
+//!
+//! Every record is CBOR wrapped in an envelope that says which version of its type it
+//! was written as. Changing a stored type means bumping its `VERSION` and adding a
+//! migration that rewrites the old records. Until then, reading a record of another
+//! version fails with an error that says so, instead of a confusing CBOR one.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_cbor::Value;
+
+use crate::{
+    db::{
+        content_hash::HashAlgorithm,
+        hashing::HashMode,
+        objects::{ObjectCompression, ObjectRecord},
+        types::{
+            ChunkManifest, Commit, CommitId, LocalFileData, LocalFileMetadata, Project,
+            RemoteHistory, ScanReport, Tag, Tombstone, TrashEntry,
+        },
+    },
+    error::Result,
+};
+
+/// A type that's stored in the DB
+pub trait Record: Serialize + DeserializeOwned {
+    // Shows up in errors
+    const NAME: &'static str;
+    // Bump when the stored form changes, along with a migration
+    const VERSION: u32;
+}
+
+#[derive(Serialize)]
+struct EnvelopeRef<'a, T> {
+    version: u32,
+    record: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Envelope<T> {
+    record: T,
+}
+
+// Just the version, so a record of another version isn't decoded as this one
+#[derive(Deserialize)]
+struct EnvelopeVersion {
+    version: u32,
+}
+
+pub fn encode<T: Record>(record: &T) -> Result<Vec<u8>> {
+    Ok(serde_cbor::to_vec(&EnvelopeRef {
+        version: T::VERSION,
+        record,
+    })?)
+}
+
+pub fn decode<T: Record>(bytes: &[u8]) -> Result<T> {
+    let EnvelopeVersion { version } = serde_cbor::from_slice(bytes)?;
+    if version != T::VERSION {
+        return Err(format!(
+            "Stored {} is version {}, but this build reads version {}",
+            T::NAME,
+            version,
+            T::VERSION
+        )
+        .into());
+    }
+
+    let envelope: Envelope<T> = serde_cbor::from_slice(bytes)?;
+    Ok(envelope.record)
+}
+
+/// Whether `bytes` are already wrapped in an envelope, of any version
+pub fn is_wrapped(bytes: &[u8]) -> bool {
+    // Exactly the two fields, a bare record with a `version` of its own has others too
+    match serde_cbor::from_slice::<BTreeMap<String, Value>>(bytes) {
+        Ok(fields) => {
+            fields.len() == 2
+                && matches!(fields.get("version"), Some(Value::Integer(_)))
+                && fields.contains_key("record")
+        }
+        Err(_) => false,
+    }
+}
+
+/// Wrap a record written before envelopes existed, without knowing its type
+pub fn wrap_unversioned(bytes: &[u8]) -> Result<Vec<u8>> {
+    let record: Value = serde_cbor::from_slice(bytes)?;
+
+    Ok(serde_cbor::to_vec(&EnvelopeRef {
+        version: 1,
+        record: &record,
+    })?)
+}
+
+impl Record for LocalFileMetadata {
+    const NAME: &'static str = "LocalFileMetadata";
+    const VERSION: u32 = 1;
+}
+
+impl Record for LocalFileData {
+    const NAME: &'static str = "LocalFileData";
+    const VERSION: u32 = 1;
+}
+
+impl Record for Tombstone {
+    const NAME: &'static str = "Tombstone";
+    const VERSION: u32 = 1;
+}
+
+impl Record for ChunkManifest {
+    const NAME: &'static str = "ChunkManifest";
+    const VERSION: u32 = 1;
+}
+
+impl Record for Project {
+    const NAME: &'static str = "Project";
+    const VERSION: u32 = 1;
+}
+
+impl Record for ScanReport {
+    const NAME: &'static str = "ScanReport";
+    const VERSION: u32 = 1;
+}
+
+impl Record for Commit {
+    const NAME: &'static str = "Commit";
+    const VERSION: u32 = 1;
+}
+
+impl Record for CommitId {
+    const NAME: &'static str = "CommitId";
+    const VERSION: u32 = 1;
+}
+
+impl Record for RemoteHistory {
+    const NAME: &'static str = "RemoteHistory";
+    const VERSION: u32 = 1;
+}
+
+impl Record for Tag {
+    const NAME: &'static str = "Tag";
+    const VERSION: u32 = 1;
+}
+
+impl Record for TrashEntry {
+    const NAME: &'static str = "TrashEntry";
+    const VERSION: u32 = 1;
+}
+
+impl Record for HashMode {
+    const NAME: &'static str = "HashMode";
+    const VERSION: u32 = 1;
+}
+
+impl Record for ObjectCompression {
+    const NAME: &'static str = "ObjectCompression";
+    const VERSION: u32 = 1;
+}
+
+impl Record for ObjectRecord {
+    const NAME: &'static str = "ObjectRecord";
+    const VERSION: u32 = 1;
+}
+
+impl Record for Option<HashAlgorithm> {
+    const NAME: &'static str = "StrongHash";
+    const VERSION: u32 = 1;
+}
+
+impl Record for PathBuf {
+    const NAME: &'static str = "Path";
+    const VERSION: u32 = 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{decode, encode, wrap_unversioned, Record};
+    use crate::db::{project_path::ProjectPath, types::LocalFileMetadata};
+
+    fn metadata() -> LocalFileMetadata {
+        LocalFileMetadata {
+            path: ProjectPath::parse("parts/bolt.sldprt").unwrap(),
+            size: 10,
+            modified: Utc.timestamp(100, 0),
+            update_time: Utc.timestamp(100, 0),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = encode(&metadata()).unwrap();
+
+        assert_eq!(metadata(), decode::<LocalFileMetadata>(&bytes).unwrap());
+        // Bare CBOR isn't a record anymore
+        assert!(decode::<LocalFileMetadata>(&serde_cbor::to_vec(&metadata()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_wrapped_records_decode() {
+        let bare = serde_cbor::to_vec(&metadata()).unwrap();
+
+        let wrapped = wrap_unversioned(&bare).unwrap();
+
+        assert_eq!(metadata(), decode::<LocalFileMetadata>(&wrapped).unwrap());
+    }
+
+    #[test]
+    fn test_other_versions_are_refused() {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Newer(LocalFileMetadata);
+        impl Record for Newer {
+            const NAME: &'static str = "LocalFileMetadata";
+            const VERSION: u32 = 2;
+        }
+
+        let bytes = encode(&Newer(metadata())).unwrap();
+        let err = decode::<LocalFileMetadata>(&bytes).unwrap_err();
+
+        assert!(err.to_string().contains("version 2"), "{}", err);
+    }
+}
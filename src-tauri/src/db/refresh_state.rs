@@ -1,30 +1,346 @@
-use std::path::Path;
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  io::{self, ErrorKind},
+  path::{Path, PathBuf},
+};
 
 use chrono::{DateTime, Utc};
 use walkdir::WalkDir;
 
-use crate::{db::types::{LocalFileMetadata, TreeItem}, error::Result};
+use crate::{
+    db::{
+        content_hash::{ContentHash, HashAlgorithm},
+        hashing::{hash_file, hash_settings, HashSettings},
+        ignore_rules::IgnoreRules,
+        project_path::ProjectPath,
+        staging::{LocalTrees, StagedChanges},
+        store::MetadataStore,
+        typed_tree::TypedTree,
+        types::{
+            LocalFileData, LocalFileMetadata, Project, SkipReason, SkippedPath,
+        },
+    },
+    error::{Error, Result},
+};
 
+/// One thing the walk found: a file, or a path it had to leave out
+pub type ScanEntry = std::result::Result<(ProjectPath, LocalFileMetadata), SkippedPath>;
 
-pub fn get_metadatas(root: dyn AsRef<Path>) -> Result<impl Iterator<Item = TreeItem>> {
-  let entries = WalkDir::new(root).into_iter().filter_map(|entry| {
-      let entry = entry.ok()?;
+/// Walk the project, paths come out relative to `root`.
+/// Anything the ignore rules match is skipped, and ignored folders aren't walked at all.
+/// Anything that can't be read comes out as a `SkippedPath` rather than vanishing.
+pub fn get_metadatas(root: impl AsRef<Path>) -> Result<impl Iterator<Item = ScanEntry>> {
+  get_metadatas_in(root, &ProjectPath::default())
+}
+
+/// Same as `get_metadatas`, but only walks `start`, which can be a folder or a single file.
+/// Nothing comes out if `start` doesn't exist.
+pub fn get_metadatas_in(
+  root: impl AsRef<Path>,
+  start: &ProjectPath,
+) -> Result<impl Iterator<Item = ScanEntry>> {
+  let root = root.as_ref().to_path_buf();
+  let mut rules = IgnoreRules::new(&root)?;
+  // The walk only checks what it finds, so the start itself has to be checked up front
+  let start_ignored = rules.explain(start).is_some();
+  let start_path = start.to_absolute(&root);
+  let entries = WalkDir::new(&start_path)
+    .into_iter()
+    .filter_entry(move |entry| {
+      if entry.depth() == 0 {
+        return !start_ignored;
+      }
+      rules.check(entry.path(), entry.file_type().is_dir()).is_none()
+    })
+    .filter_map(move |entry| {
+      let entry = match entry {
+        Ok(entry) => entry,
+        // A start that doesn't exist just means there's nothing there
+        Err(err) if err.depth() == 0 && is_not_found(&err) => return None,
+        Err(err) => {
+          let reason = match err.io_error() {
+            Some(io_err) => skip_reason(io_err),
+            None => SkipReason::Io { error: err.to_string() },
+          };
+          return Some(Err(skipped(&root, err.path().unwrap_or(&start_path), reason)));
+        }
+      };
+
+      if entry.file_type().is_symlink() {
+        let reason = match std::fs::metadata(entry.path()) {
+          Ok(_) => SkipReason::Symlink,
+          Err(_) => SkipReason::BrokenSymlink,
+        };
+        return Some(Err(skipped(&root, entry.path(), reason)));
+      }
       if !entry.file_type().is_file() {
           return None;
       }
 
-      let path = entry.path().to_path_buf();
-      let size = entry.metadata().ok()?.len();
-      let modified = entry.metadata().ok()?.modified().ok()?;
-      let modified = DateTime::from(modified);
+      let path = match ProjectPath::from_absolute(&root, entry.path()) {
+          Ok(path) => path,
+          Err(err) => {
+              let reason = SkipReason::InvalidPath { error: err.to_string() };
+              return Some(Err(skipped(&root, entry.path(), reason)));
+          }
+      };
+      let file_metadata = match entry.metadata() {
+        Ok(file_metadata) => file_metadata,
+        Err(err) => {
+          let reason = match err.io_error() {
+            Some(io_err) => skip_reason(io_err),
+            None => SkipReason::Io { error: err.to_string() },
+          };
+          return Some(Err(skipped(&root, entry.path(), reason)));
+        }
+      };
+      let modified = match file_metadata.modified() {
+        Ok(modified) => DateTime::from(modified),
+        Err(err) => return Some(Err(skipped(&root, entry.path(), skip_reason(&err)))),
+      };
       let metadata = LocalFileMetadata {
           path,
-          size,
+          size: file_metadata.len(),
           modified,
           update_time: Utc::now(),
       };
-      Some((metadata.path.clone(), metadata))
-  });
+      Some(Ok((metadata.path.clone(), metadata)))
+    });
 
   Ok(entries)
-}
\ No newline at end of file
+}
+
+fn is_not_found(err: &walkdir::Error) -> bool {
+  err.io_error().map(io::Error::kind) == Some(ErrorKind::NotFound)
+}
+
+fn skipped(root: &Path, path: &Path, reason: SkipReason) -> SkippedPath {
+  SkippedPath {
+    path: path.strip_prefix(root).unwrap_or(path).to_path_buf(),
+    reason,
+  }
+}
+
+/// Sort an IO error into why the file it came from was skipped
+pub fn skip_reason(err: &io::Error) -> SkipReason {
+  match err.kind() {
+    ErrorKind::PermissionDenied => SkipReason::PermissionDenied,
+    ErrorKind::NotFound => SkipReason::Vanished,
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION, which is how Windows says another
+    // program has the file open
+    _ if cfg!(windows) && matches!(err.raw_os_error(), Some(32) | Some(33)) => SkipReason::Locked,
+    _ => SkipReason::Io { error: err.to_string() },
+  }
+}
+
+/// The project paths of everything skipped, for telling whether a stored file is
+/// really gone or just couldn't be looked at this time
+pub fn skipped_project_paths(root: &Path, skipped: &[SkippedPath]) -> Vec<ProjectPath> {
+  skipped
+    .iter()
+    .filter_map(|skipped| ProjectPath::from_absolute(root, &root.join(&skipped.path)).ok())
+    .collect()
+}
+
+pub async fn hash_and_finalize(
+  root: PathBuf,
+  metadata: LocalFileMetadata,
+  settings: HashSettings,
+) -> Result<LocalFileData> {
+  let hashes = hash_file(metadata.path.to_absolute(&root), settings).await?;
+
+  Ok(LocalFileData {
+    name: metadata
+      .path
+      .file_name()
+      .ok_or_else(|| "No filename".to_string())?
+      .to_owned(),
+    metadata,
+    hash: hashes.hash,
+    strong_hash: hashes.strong_hash,
+  })
+}
+
+/// Whether the stored hashes are of the file as `metadata` describes it, and were made
+/// with the current settings. Ones that weren't, like migrated xxh3-64 hashes, get redone
+/// by the next scan.
+pub fn has_current_hashes(
+  meta_hash_tree: &TypedTree<ProjectPath, LocalFileData>,
+  metadata: &LocalFileMetadata,
+  settings: &HashSettings,
+) -> Result<bool> {
+  let data = match meta_hash_tree.get(&metadata.path)? {
+    Some(data) => data,
+    None => return Ok(false),
+  };
+
+  Ok(&data.metadata == metadata
+    && data.hash.algorithm() == HashAlgorithm::Xxh3_128
+    && data.strong_hash.as_ref().map(ContentHash::algorithm) == settings.strong)
+}
+
+/// Bring the local trees up to date for just `paths`, without walking the whole project.
+/// Each path can be a file or a folder, and doesn't have to exist anymore.
+///
+/// Returns every file whose local state changed.
+pub async fn refresh_paths(
+  project: &Project,
+  paths: &[ProjectPath],
+  db: &dyn MetadataStore,
+) -> Result<Vec<ProjectPath>> {
+  let update_time = Utc::now();
+  let trees = LocalTrees::open(project, db)?;
+  let settings = hash_settings(db)?;
+  let mut rules = IgnoreRules::new(&project.root)?;
+
+  let mut changed = BTreeSet::new();
+
+  for path in paths {
+    let mut on_disk = BTreeMap::new();
+    let mut skipped = Vec::new();
+    for entry in get_metadatas_in(&project.root, path)? {
+      match entry {
+        Ok((file_path, metadatum)) => {
+          on_disk.insert(file_path, metadatum);
+        }
+        Err(skip) => {
+          println!("Skipping {}: {:?}", skip.path.display(), skip.reason);
+          skipped.push(skip);
+        }
+      }
+    }
+    // Files that couldn't be looked at keep what's stored for them
+    let skipped = skipped_project_paths(&project.root, &skipped);
+
+    // Anything stored at or under this path that's gone now was deleted
+    let mut staged = StagedChanges::default();
+    let mut stored = trees.metadata.keys_under(path).collect::<Result<Vec<_>>>()?;
+    if !path.is_root() && trees.metadata.contains_key(path)? {
+      stored.push(path.clone());
+    }
+
+    for stored_path in stored {
+      // Ignored files aren't walked, but that doesn't make tracked ones gone
+      if on_disk.contains_key(&stored_path)
+        || skipped.iter().any(|skipped| stored_path.starts_with(skipped))
+        || rules.explain(&stored_path).is_some()
+      {
+        continue;
+      }
+
+      let data = trees.hashes.get(&stored_path)?;
+      staged.deleted(&stored_path, data, update_time)?;
+      changed.insert(stored_path);
+    }
+
+    // And anything on disk that doesn't match what's stored needs a rehash
+    for (file_path, metadatum) in on_disk {
+      let unchanged = match trees.metadata.get(&file_path) {
+        Ok(prev) => prev.as_ref() == Some(&metadatum),
+        Err(Error::CorruptRecord { .. }) => false,
+        Err(err) => return Err(err),
+      } && has_current_hashes(&trees.hashes, &metadatum, &settings)?;
+      if unchanged {
+        continue;
+      }
+
+      // Files that are still being written can vanish under us, the next event will catch up
+      let data = match hash_and_finalize(project.root.clone(), metadatum.clone(), settings).await {
+        Ok(data) => data,
+        Err(err) => {
+          println!("Skipping {}: {}", file_path, err);
+          continue;
+        }
+      };
+
+      staged.hashed(&data)?;
+      changed.insert(file_path);
+    }
+
+    staged.apply(&trees)?;
+  }
+
+  Ok(changed.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    fs::{create_dir_all, remove_file, write},
+    path::PathBuf,
+  };
+
+  use super::{get_metadatas, refresh_paths};
+  use crate::db::{
+    project_path::ProjectPath,
+    staging::LocalTrees,
+    test_support::{path, project_at},
+    types::{SkipReason, SkippedPath},
+  };
+
+  #[tokio::test]
+  async fn test_refresh_paths() {
+    let root = tempfile::tempdir().unwrap();
+    create_dir_all(root.path().join("parts")).unwrap();
+    write(root.path().join("parts/bolt.sldprt"), "bolt").unwrap();
+    write(root.path().join("parts/nut.sldprt"), "nut").unwrap();
+    write(root.path().join("untouched.sldprt"), "untouched").unwrap();
+
+    let db = sled::Config::default().temporary(true).open().unwrap();
+    let project = project_at(root.path());
+
+    let changed = refresh_paths(&project, &[path("parts")], &db).await.unwrap();
+    assert_eq!(vec![path("parts/bolt.sldprt"), path("parts/nut.sldprt")], changed);
+
+    // Nothing changed on disk, so nothing to do
+    let changed = refresh_paths(&project, &[path("parts")], &db).await.unwrap();
+    assert!(changed.is_empty());
+
+    remove_file(root.path().join("parts/nut.sldprt")).unwrap();
+    let changed = refresh_paths(&project, &[path("parts/nut.sldprt")], &db)
+      .await
+      .unwrap();
+    assert_eq!(vec![path("parts/nut.sldprt")], changed);
+
+    let trees = LocalTrees::open(&project, &db).unwrap();
+    let tombstone = trees.tombstones.get(&path("parts/nut.sldprt")).unwrap().unwrap();
+    assert_eq!(path("parts/nut.sldprt"), tombstone.data.metadata.path);
+
+    assert_eq!(1, trees.hashes.len());
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_skipped_paths_are_not_deletions() {
+    let root = tempfile::tempdir().unwrap();
+    write(root.path().join("bolt.sldprt"), "bolt").unwrap();
+    std::os::unix::fs::symlink(root.path().join("nowhere"), root.path().join("dangling.sldprt"))
+      .unwrap();
+
+    let skipped: Vec<SkippedPath> = get_metadatas(root.path())
+      .unwrap()
+      .filter_map(|entry| entry.err())
+      .collect();
+    assert_eq!(
+      vec![SkippedPath {
+        path: PathBuf::from("dangling.sldprt"),
+        reason: SkipReason::BrokenSymlink,
+      }],
+      skipped
+    );
+
+    let db = sled::Config::default().temporary(true).open().unwrap();
+    let project = project_at(root.path());
+    refresh_paths(&project, &[ProjectPath::default()], &db).await.unwrap();
+
+    // Swapped for a link the scanner won't follow, which isn't the same as deleted
+    remove_file(root.path().join("bolt.sldprt")).unwrap();
+    std::os::unix::fs::symlink(root.path().join("nowhere"), root.path().join("bolt.sldprt"))
+      .unwrap();
+    let changed = refresh_paths(&project, &[ProjectPath::default()], &db).await.unwrap();
+
+    assert!(changed.is_empty());
+    let trees = LocalTrees::open(&project, &db).unwrap();
+    assert!(trees.tombstones.is_empty());
+  }
+}
@@ -0,0 +1,111 @@
+//! What the remote says it has, as the frontend last heard it.
+//!
+//! Only the latest state is compared against, but every version it's been seen with is
+//! kept in the remote history tree, so file history can show versions that never went
+//! through a local commit.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+
+use crate::{
+    db::{
+        project_path::ProjectPath,
+        renames::detect_renames,
+        store::MetadataStore,
+        typed_tree::TypedTree,
+        types::{
+            DiffTypes, FileDiff, LocalFileData, Project, RemoteHistory, RemoteVersion, Tombstone,
+            TreeNames,
+        },
+    },
+    error::Result,
+};
+
+/// Replace the stored remote state of the project with `remote_state`
+pub fn set_remote_state(
+    project: &Project,
+    remote_state: Vec<LocalFileData>,
+    db: &dyn MetadataStore,
+) -> Result<()> {
+    let remote_tree: TypedTree<ProjectPath, LocalFileData> =
+        TypedTree::open(db, project.tree_name(TreeNames::HASH_REMOTE_METDATA))?;
+
+    let previous = remote_tree.iter().collect::<Result<BTreeMap<_, _>>>()?;
+    record_history(project, &previous, &remote_state, db)?;
+
+    remote_tree.clear()?;
+
+    for item in remote_state {
+        remote_tree.insert(&item.metadata.path, &item)?;
+    }
+
+    // Once the remote no longer has a file, our deletion of it has gone through
+    let tombstone_tree: TypedTree<ProjectPath, Tombstone> =
+        TypedTree::open(db, project.tree_name(TreeNames::LOCAL_TOMBSTONES))?;
+
+    for path in tombstone_tree.keys() {
+        let path = path?;
+        if !remote_tree.contains_key(&path)? {
+            tombstone_tree.remove(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Add every new or changed file in `remote_state` to its path's remote history
+fn record_history(
+    project: &Project,
+    previous: &BTreeMap<ProjectPath, LocalFileData>,
+    remote_state: &[LocalFileData],
+    db: &dyn MetadataStore,
+) -> Result<()> {
+    let history_tree: TypedTree<ProjectPath, RemoteHistory> =
+        TypedTree::open(db, project.tree_name(TreeNames::REMOTE_HISTORY))?;
+
+    // Files that are gone against ones that are new, so moves can be told apart
+    let mut diffs = Vec::new();
+    for data in remote_state {
+        match previous.get(&data.metadata.path) {
+            Some(old) if old.hash == data.hash => {}
+            Some(old) => diffs.push(FileDiff::left_newer(
+                data.metadata.path.clone(),
+                data.clone(),
+                old.clone(),
+            )),
+            None => diffs.push(FileDiff::left_create(
+                data.metadata.path.clone(),
+                data.clone(),
+            )),
+        }
+    }
+    let current: BTreeMap<&ProjectPath, &LocalFileData> = remote_state
+        .iter()
+        .map(|data| (&data.metadata.path, data))
+        .collect();
+    for (path, old) in previous {
+        if !current.contains_key(path) {
+            diffs.push(FileDiff::left_delete(path.clone(), old.clone()));
+        }
+    }
+
+    let seen = Utc::now();
+    for diff in detect_renames(diffs) {
+        let renamed_from = match diff.diff_type {
+            DiffTypes::Renamed { from, .. } => Some(from),
+            DiffTypes::LeftDelete => continue,
+            _ => None,
+        };
+
+        let mut history = history_tree.get(&diff.path)?.unwrap_or_default();
+        history.versions.push(RemoteVersion {
+            data: current[&diff.path].clone(),
+            seen,
+            renamed_from,
+        });
+        history_tree.insert(&diff.path, &history)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,374 @@
+//! Pair up creates and deletes that are really the same file moved somewhere else.
+//!
+//! A move shows up from `find_diffs` as a create at the new path and a delete at
+//! the old one, both on the same side and with the same hash. Turning those into a
+//! single `Renamed` lets sync do a server-side move instead of a re-upload.
+//!
+//! Hashes from different algorithms never match, so pairs like that, a record kept
+//! from before v4 against a fresh scan, get a second go by size and modified time.
+//!
+//! `RenameWindow` does the same for diffs that are streamed out as they're found. It only
+//! holds on to a bounded number of creates and deletes, so a move between paths that are
+//! far apart in a big project can still come out as a create and a delete.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    mem,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::db::{
+    content_hash::ContentHash,
+    project_path::ProjectPath,
+    types::{DiffTypes, FileDiff, FileDiffData, LocalFileData},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Side {
+    Left,
+    Right,
+}
+
+// A create or delete, by its index in the diffs
+type Entry<'a> = (usize, &'a LocalFileData);
+// key -> the creates and deletes with that key
+type Candidates<'a, K> = HashMap<K, (Vec<Entry<'a>>, Vec<Entry<'a>>)>;
+
+/// Holds creates and deletes back from a stream of diffs until they've had a chance to
+/// pair up with the ones after them
+#[derive(Debug)]
+pub struct RenameWindow {
+    held: Vec<FileDiff>,
+    limit: usize,
+}
+
+impl RenameWindow {
+    /// Holds at most `limit` creates and deletes at once
+    pub fn new(limit: usize) -> Self {
+        Self {
+            held: Vec::new(),
+            limit: limit.max(2),
+        }
+    }
+
+    /// The diffs that are ready to send now that `diff` came in
+    pub fn push(&mut self, diff: FileDiff) -> Vec<FileDiff> {
+        if candidate(&diff).is_none() {
+            return vec![diff];
+        }
+        self.held.push(diff);
+        if self.held.len() <= self.limit {
+            return Vec::new();
+        }
+
+        // Renames are done. Of what's left, the oldest half has had the longest to find
+        // a pair, so it goes and the newest half gets to wait for more.
+        let (mut ready, mut unpaired): (Vec<_>, Vec<_>) = detect_renames(mem::take(&mut self.held))
+            .into_iter()
+            .partition(|diff| matches!(diff.diff_type, DiffTypes::Renamed { .. }));
+        let keep = unpaired.len().min(self.limit / 2);
+        self.held = unpaired.split_off(unpaired.len() - keep);
+        ready.append(&mut unpaired);
+        ready
+    }
+
+    /// Everything still held, with whatever renames are among it
+    pub fn finish(self) -> Vec<FileDiff> {
+        detect_renames(self.held)
+    }
+}
+
+/// The side and data of a create or delete, and whether it's the create
+fn candidate(diff: &FileDiff) -> Option<(Side, bool, &LocalFileData)> {
+    match (&diff.diff_type, &diff.diff_metadata) {
+        (DiffTypes::LeftCreate, FileDiffData::Left(data)) => Some((Side::Left, true, data)),
+        (DiffTypes::LeftDelete, FileDiffData::Right(data)) => Some((Side::Left, false, data)),
+        (DiffTypes::RightCreate, FileDiffData::Right(data)) => Some((Side::Right, true, data)),
+        (DiffTypes::RightDelete, FileDiffData::Left(data)) => Some((Side::Right, false, data)),
+        _ => None,
+    }
+}
+
+/// Replace matching create/delete pairs with `Renamed` diffs, leaving everything else alone
+pub fn detect_renames(file_diffs: Vec<FileDiff>) -> Vec<FileDiff> {
+    // (index, side, whether it's the create, data)
+    let candidates: Vec<_> = file_diffs
+        .iter()
+        .enumerate()
+        .filter_map(|(index, diff)| {
+            candidate(diff).map(|(side, is_create, data)| (index, side, is_create, data))
+        })
+        .collect();
+
+    // create index -> delete index
+    let mut pairs: HashMap<usize, usize> = HashMap::new();
+
+    let by_hash: Candidates<(Side, &ContentHash)> =
+        group(&candidates, |side, data| (side, &data.hash));
+    pair_up(&by_hash, &file_diffs, &mut pairs, |_, _| true);
+
+    let by_metadata: Candidates<(Side, u64, DateTime<Utc>)> = group(&candidates, |side, data| {
+        (side, data.metadata.size, data.metadata.modified)
+    });
+    pair_up(&by_metadata, &file_diffs, &mut pairs, |create, delete| {
+        create.hash.algorithm() != delete.hash.algorithm()
+    });
+
+    if pairs.is_empty() {
+        return file_diffs;
+    }
+
+    let paired_deletes: HashSet<usize> = pairs.values().copied().collect();
+    let mut from_paths: HashMap<usize, _> = pairs
+        .iter()
+        .map(|(&create, &delete)| (create, file_diffs[delete].path.clone()))
+        .collect();
+
+    file_diffs
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !paired_deletes.contains(index))
+        .map(|(index, diff)| match from_paths.remove(&index) {
+            Some(from) => FileDiff::renamed(from, diff.path, diff.diff_metadata),
+            None => diff,
+        })
+        .collect()
+}
+
+fn group<'a, K: Eq + Hash>(
+    candidates: &[(usize, Side, bool, &'a LocalFileData)],
+    key: impl Fn(Side, &'a LocalFileData) -> K,
+) -> Candidates<'a, K> {
+    let mut grouped: Candidates<K> = HashMap::new();
+    for &(index, side, is_create, data) in candidates {
+        let entry = grouped.entry(key(side, data)).or_default();
+        if is_create {
+            entry.0.push((index, data));
+        } else {
+            entry.1.push((index, data));
+        }
+    }
+    grouped
+}
+
+/// Pair the creates and deletes of every group that `may_pair` allows and neither of
+/// which is paired yet, most alike names first
+fn pair_up<K>(
+    candidates: &Candidates<K>,
+    file_diffs: &[FileDiff],
+    pairs: &mut HashMap<usize, usize>,
+    may_pair: impl Fn(&LocalFileData, &LocalFileData) -> bool,
+) {
+    let mut used_deletes: HashSet<usize> = pairs.values().copied().collect();
+
+    for (creates, deletes) in candidates.values() {
+        if creates.is_empty() || deletes.is_empty() {
+            continue;
+        }
+
+        // Score every possible pairing, then hand them out best first
+        let mut scored = Vec::with_capacity(creates.len() * deletes.len());
+        for &(create, create_data) in creates {
+            for &(delete, delete_data) in deletes {
+                if !may_pair(create_data, delete_data) {
+                    continue;
+                }
+                let score = name_similarity(&file_diffs[delete].path, &file_diffs[create].path);
+                scored.push((score, create, delete));
+            }
+        }
+        scored.sort_by(|a, b| {
+            b.0.cmp(&a.0)
+                .then_with(|| file_diffs[a.1].path.cmp(&file_diffs[b.1].path))
+                .then_with(|| file_diffs[a.2].path.cmp(&file_diffs[b.2].path))
+        });
+
+        for (_, create, delete) in scored {
+            if pairs.contains_key(&create) || used_deletes.contains(&delete) {
+                continue;
+            }
+            pairs.insert(create, delete);
+            used_deletes.insert(delete);
+        }
+    }
+}
+
+/// How alike two paths look, higher is more alike. Only used to break ties
+/// when several files share the same hash.
+fn name_similarity(from: &ProjectPath, to: &ProjectPath) -> (bool, usize, usize) {
+    let from_name = from.file_name().unwrap_or_default();
+    let to_name = to.file_name().unwrap_or_default();
+
+    let same_name = from_name == to_name;
+    let shared_name_prefix = from_name
+        .chars()
+        .zip(to_name.chars())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let from_parent = from.parent().unwrap_or_default();
+    let to_parent = to.parent().unwrap_or_default();
+    let shared_parents = from_parent
+        .components()
+        .zip(to_parent.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    (same_name, shared_parents, shared_name_prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::db::{
+        content_hash::ContentHash,
+        renames::{detect_renames, RenameWindow},
+        test_support::path,
+        types::{FileDiff, FileDiffData, LocalFileData, LocalFileMetadata},
+    };
+
+    fn file_data(file_path: &str, hash: u128) -> LocalFileData {
+        LocalFileData {
+            hash: ContentHash::xxh3_128(hash),
+            strong_hash: None,
+            name: path(file_path).file_name().unwrap().to_owned(),
+            metadata: LocalFileMetadata {
+                path: path(file_path),
+                modified: Utc.timestamp(100, 0),
+                size: 1,
+                update_time: Utc.timestamp(100, 0),
+            },
+        }
+    }
+
+    #[test]
+    fn test_local_move() {
+        let diffs = vec![
+            FileDiff::left_create(path("new/gearbox.sldasm"), file_data("new/gearbox.sldasm", 7)),
+            FileDiff::left_delete(path("old/gearbox.sldasm"), file_data("old/gearbox.sldasm", 7)),
+            FileDiff::left_create(path("new/unrelated.step"), file_data("new/unrelated.step", 8)),
+        ];
+
+        let mut res = detect_renames(diffs);
+        res.sort();
+
+        let mut expected = vec![
+            FileDiff::renamed(
+                path("old/gearbox.sldasm"),
+                path("new/gearbox.sldasm"),
+                FileDiffData::Left(file_data("new/gearbox.sldasm", 7)),
+            ),
+            FileDiff::left_create(path("new/unrelated.step"), file_data("new/unrelated.step", 8)),
+        ];
+        expected.sort();
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_same_hash_prefers_similar_name() {
+        let diffs = vec![
+            FileDiff::right_create(path("b/bolt.sldprt"), file_data("b/bolt.sldprt", 3)),
+            FileDiff::right_create(path("b/nut.sldprt"), file_data("b/nut.sldprt", 3)),
+            FileDiff::right_delete(path("a/nut.sldprt"), file_data("a/nut.sldprt", 3)),
+            FileDiff::right_delete(path("a/bolt.sldprt"), file_data("a/bolt.sldprt", 3)),
+        ];
+
+        let mut res = detect_renames(diffs);
+        res.sort();
+
+        let mut expected = vec![
+            FileDiff::renamed(
+                path("a/bolt.sldprt"),
+                path("b/bolt.sldprt"),
+                FileDiffData::Right(file_data("b/bolt.sldprt", 3)),
+            ),
+            FileDiff::renamed(
+                path("a/nut.sldprt"),
+                path("b/nut.sldprt"),
+                FileDiffData::Right(file_data("b/nut.sldprt", 3)),
+            ),
+        ];
+        expected.sort();
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_sides_are_not_mixed() {
+        // A local create and a remote create with the same hash isn't a move
+        let diffs = vec![
+            FileDiff::left_create(path("left"), file_data("left", 1)),
+            FileDiff::right_create(path("right"), file_data("right", 1)),
+        ];
+
+        let mut res = detect_renames(diffs);
+        res.sort();
+
+        let mut expected = vec![
+            FileDiff::left_create(path("left"), file_data("left", 1)),
+            FileDiff::right_create(path("right"), file_data("right", 1)),
+        ];
+        expected.sort();
+
+        assert_eq!(expected, res);
+    }
+
+    #[test]
+    fn test_window_pairs_what_it_holds() {
+        let mut window = RenameWindow::new(2);
+
+        // Not a create or delete, so it goes straight out
+        let changed = FileDiff::left_newer(
+            path("a/shaft.sldprt"),
+            file_data("a/shaft.sldprt", 4),
+            file_data("a/shaft.sldprt", 5),
+        );
+        assert_eq!(vec![changed.clone()], window.push(changed));
+
+        assert!(window
+            .push(FileDiff::left_delete(
+                path("a/bolt.sldprt"),
+                file_data("a/bolt.sldprt", 1)
+            ))
+            .is_empty());
+        assert!(window
+            .push(FileDiff::left_create(
+                path("b/bolt.sldprt"),
+                file_data("b/bolt.sldprt", 1)
+            ))
+            .is_empty());
+
+        // Over the limit, so the rename is done and the oldest of the rest go
+        let sent = window.push(FileDiff::left_create(
+            path("c/nut.sldprt"),
+            file_data("c/nut.sldprt", 2),
+        ));
+        assert_eq!(
+            vec![FileDiff::renamed(
+                path("a/bolt.sldprt"),
+                path("b/bolt.sldprt"),
+                FileDiffData::Left(file_data("b/bolt.sldprt", 1)),
+            )],
+            sent
+        );
+
+        // The newest one stayed, and can still pair with what comes after it
+        assert!(window
+            .push(FileDiff::left_delete(
+                path("d/nut.sldprt"),
+                file_data("d/nut.sldprt", 2)
+            ))
+            .is_empty());
+        assert_eq!(
+            vec![FileDiff::renamed(
+                path("d/nut.sldprt"),
+                path("c/nut.sldprt"),
+                FileDiffData::Left(file_data("c/nut.sldprt", 2)),
+            )],
+            window.finish()
+        );
+    }
+}
@@ -0,0 +1,473 @@
+//! Putting old versions of files back from the object store.
+//!
+//! Each file is written to a temp file next to it, given the modified time it had in
+//! that version, then renamed over the current one, so nothing ever sees it half
+//! written. Whatever was there before goes to the `Trash` under the app dir first, where
+//! it can be recovered from. Afterwards the touched paths are refreshed like the watcher
+//! would, so the local trees describe exactly what's on disk.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    slice,
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    db::{
+        commits::files_at,
+        content_hash::ContentHash,
+        objects::ObjectStore,
+        project_path::ProjectPath,
+        refresh_state::{get_metadatas_in, refresh_paths},
+        staging::LocalTrees,
+        store::MetadataStore,
+        typed_tree::TypedTree,
+        types::{
+            CommitId, LocalFileData, LocalFileMetadata, Project, ProjectId, TrashEntry, TreeNames,
+        },
+    },
+    error::{Error, Result},
+};
+
+fn trash_entries(db: &dyn MetadataStore) -> Result<TypedTree<u64, TrashEntry>> {
+    TypedTree::open(db, TreeNames::TRASH)
+}
+
+/// The directory overwritten and deleted files are kept in
+#[derive(Debug, Clone)]
+pub struct Trash {
+    root: PathBuf,
+}
+
+impl Trash {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Keep a copy of the file at `target` as trash entry `id`. Blocks.
+    fn put(
+        &self,
+        project_id: ProjectId,
+        id: u64,
+        path: &ProjectPath,
+        target: &Path,
+    ) -> Result<TrashEntry> {
+        let location =
+            path.to_absolute(&self.root.join(project_id.to_string()).join(id.to_string()));
+        if let Some(folder) = location.parent() {
+            fs::create_dir_all(folder)?;
+        }
+        let metadata = fs::metadata(target)?;
+        link_or_copy(target, &location, metadata.modified()?)?;
+
+        Ok(TrashEntry {
+            id,
+            project_id,
+            path: path.clone(),
+            location,
+            size: metadata.len(),
+            modified: DateTime::from(metadata.modified()?),
+            trashed: Utc::now(),
+        })
+    }
+}
+
+// A hard link is free and keeps everything, but can't cross drives
+fn link_or_copy(from: &Path, to: &Path, modified: SystemTime) -> Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+        File::options()
+            .write(true)
+            .open(to)?
+            .set_modified(modified)?;
+    }
+    Ok(())
+}
+
+/// What to do to one file
+enum Action {
+    // Put this version there, with this modified time
+    Write {
+        path: ProjectPath,
+        source: Source,
+        modified: SystemTime,
+    },
+    // It wasn't there in the version being restored
+    Remove {
+        path: ProjectPath,
+    },
+}
+
+enum Source {
+    Object(ContentHash),
+    Trashed(PathBuf),
+}
+
+impl Action {
+    fn path(&self) -> &ProjectPath {
+        match self {
+            Action::Write { path, .. } | Action::Remove { path } => path,
+        }
+    }
+}
+
+/// Restore `path`, a file or a folder, to how it was at commit `version`
+pub async fn restore_path(
+    project: &Project,
+    path: &ProjectPath,
+    version: CommitId,
+    objects: &ObjectStore,
+    trash: &Trash,
+    db: &dyn MetadataStore,
+) -> Result<Vec<ProjectPath>> {
+    let wanted = files_at(project, version, db)?
+        .into_iter()
+        .filter(|(file_path, _)| file_path.starts_with(path))
+        .collect();
+
+    restore_files(project, slice::from_ref(path), wanted, objects, trash, db).await
+}
+
+/// Throw away local changes to `paths`, files or folders, putting back what HEAD has
+pub async fn revert_to_head(
+    project: &Project,
+    paths: &[ProjectPath],
+    objects: &ObjectStore,
+    trash: &Trash,
+    db: &dyn MetadataStore,
+) -> Result<Vec<ProjectPath>> {
+    let head: TypedTree<ProjectPath, LocalFileData> =
+        TypedTree::open(db, project.tree_name(TreeNames::HASH_HEAD_METDATA))?;
+
+    let mut wanted = BTreeMap::new();
+    for path in paths {
+        if !path.is_root() {
+            if let Some(data) = head.get(path)? {
+                wanted.insert(path.clone(), data);
+            }
+        }
+        for item in head.under(path) {
+            let (file_path, data) = item?;
+            wanted.insert(file_path, data);
+        }
+    }
+
+    restore_files(project, paths, wanted, objects, trash, db).await
+}
+
+/// Make `paths` hold exactly the `wanted` files. Returns every file that changed.
+async fn restore_files(
+    project: &Project,
+    paths: &[ProjectPath],
+    wanted: BTreeMap<ProjectPath, LocalFileData>,
+    objects: &ObjectStore,
+    trash: &Trash,
+    db: &dyn MetadataStore,
+) -> Result<Vec<ProjectPath>> {
+    let trees = LocalTrees::open(project, db)?;
+
+    // Don't start unless every version can be had
+    let missing: Vec<String> = wanted
+        .values()
+        .filter(|data| !objects.contains(&data.hash))
+        .map(|data| data.metadata.path.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("No local copy of the version of {}", missing.join(", ")).into());
+    }
+
+    let mut actions = Vec::new();
+    for path in paths {
+        for entry in get_metadatas_in(&project.root, path)? {
+            // Files that can't be looked at are left alone
+            let (file_path, _) = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if !wanted.contains_key(&file_path) {
+                actions.push(Action::Remove { path: file_path });
+            }
+        }
+    }
+    for (path, data) in wanted {
+        if !is_on_disk(project, &data, &trees)? {
+            actions.push(Action::Write {
+                path,
+                source: Source::Object(data.hash),
+                modified: SystemTime::from(data.metadata.modified),
+            });
+        }
+    }
+
+    apply_actions(project, actions, objects, trash, db).await
+}
+
+/// Whether the file on disk is already `data`, going by what the last scan found
+fn is_on_disk(project: &Project, data: &LocalFileData, trees: &LocalTrees) -> Result<bool> {
+    let path = &data.metadata.path;
+    let metadata = match fs::metadata(path.to_absolute(&project.root)) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(false),
+    };
+    let on_disk = LocalFileMetadata {
+        path: path.clone(),
+        size: metadata.len(),
+        modified: DateTime::from(metadata.modified()?),
+        update_time: Utc::now(),
+    };
+
+    let scanned = trees.metadata.get(path)?;
+    let hashed = trees.hashes.get(path)?;
+    Ok(scanned.as_ref() == Some(&on_disk)
+        && hashed.map(|hashed| hashed.hash) == Some(data.hash.clone()))
+}
+
+/// Do `actions` and refresh what they touched
+async fn apply_actions(
+    project: &Project,
+    actions: Vec<Action>,
+    objects: &ObjectStore,
+    trash: &Trash,
+    db: &dyn MetadataStore,
+) -> Result<Vec<ProjectPath>> {
+    let paths: Vec<ProjectPath> = actions.iter().map(|action| action.path().clone()).collect();
+    let ids = actions
+        .iter()
+        .map(|_| db.generate_id())
+        .collect::<Result<Vec<_>>>()?;
+
+    let root = project.root.clone();
+    let project_id = project.id;
+    let objects = objects.clone();
+    let trash = trash.clone();
+    let entries = trash_entries(db)?;
+    let result = tokio::task::spawn_blocking(move || {
+        for (action, id) in actions.iter().zip(ids) {
+            apply_action(&root, project_id, id, action, &objects, &trash, &entries)?;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|err| format!("Restore task failed: {}", err))?;
+
+    // Even if it stopped partway, what it did get to needs to be in the trees
+    let changed = refresh_paths(project, &paths, db).await?;
+
+    result.map(|_| changed)
+}
+
+/// Blocks. Whatever was there before is trashed, and its entry stored, before it's touched.
+fn apply_action(
+    root: &Path,
+    project_id: ProjectId,
+    id: u64,
+    action: &Action,
+    objects: &ObjectStore,
+    trash: &Trash,
+    entries: &TypedTree<u64, TrashEntry>,
+) -> Result<()> {
+    let target = action.path().to_absolute(root);
+    if target.is_file() {
+        let entry = trash.put(project_id, id, action.path(), &target)?;
+        if let Err(err) = entries.insert(&entry.id, &entry) {
+            let _ = fs::remove_file(&entry.location);
+            return Err(err);
+        }
+    }
+
+    match action {
+        Action::Write {
+            path,
+            source,
+            modified,
+        } => {
+            let folder = target
+                .parent()
+                .ok_or_else(|| format!("{} has no parent", target.display()))?;
+            fs::create_dir_all(folder)?;
+
+            // Ignored by default, so neither the watcher nor a scan picks it up
+            let name = path.file_name().unwrap_or_default();
+            let temp = folder.join(format!(".{}.splatcad-restore.tmp", name));
+            let result = write_temp(source, &temp, *modified, objects)
+                .and_then(|_| fs::rename(&temp, &target).map_err(Error::from));
+            if result.is_err() {
+                let _ = fs::remove_file(&temp);
+            }
+            result?;
+        }
+        Action::Remove { .. } => fs::remove_file(&target)?,
+    }
+
+    Ok(())
+}
+
+fn write_temp(
+    source: &Source,
+    temp: &Path,
+    modified: SystemTime,
+    objects: &ObjectStore,
+) -> Result<()> {
+    match source {
+        Source::Object(hash) => objects.copy_to(hash, temp)?,
+        Source::Trashed(location) => {
+            fs::copy(location, temp)?;
+        }
+    }
+
+    // The version's own modified time, not now
+    let file = File::options().write(true).open(temp)?;
+    file.set_modified(modified)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// What the restores of a project put in the trash, newest first
+pub fn list_trash(project: &Project, db: &dyn MetadataStore) -> Result<Vec<TrashEntry>> {
+    let mut entries = Vec::new();
+    for item in trash_entries(db)?.iter().rev() {
+        let (_, entry) = item?;
+        if entry.project_id == project.id {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Put a trashed file back where it was. Whatever's there now gets trashed in turn.
+pub async fn recover_trashed(
+    project: &Project,
+    id: u64,
+    objects: &ObjectStore,
+    trash: &Trash,
+    db: &dyn MetadataStore,
+) -> Result<Vec<ProjectPath>> {
+    let entries = trash_entries(db)?;
+    let entry = match entries.get(&id)? {
+        Some(entry) if entry.project_id == project.id => entry,
+        _ => return Err(format!("No trashed file {} in project {}", id, project.id).into()),
+    };
+
+    let action = Action::Write {
+        path: entry.path.clone(),
+        source: Source::Trashed(entry.location.clone()),
+        modified: SystemTime::from(entry.modified),
+    };
+    let changed = apply_actions(project, vec![action], objects, trash, db).await?;
+
+    entries.remove(&id)?;
+    fs::remove_file(&entry.location)?;
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{list_trash, recover_trashed, restore_path, revert_to_head, Trash};
+    use crate::db::{
+        commits::commit_project,
+        memory_store::MemoryStore,
+        objects::ObjectStore,
+        project_path::ProjectPath,
+        refresh_state::refresh_paths,
+        test_support::{path, project_at},
+    };
+
+    #[tokio::test]
+    async fn test_restore_and_recover() {
+        let db = MemoryStore::default();
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        let trash = Trash::new(dir.path().join("trash"));
+        let root = dir.path().join("robot");
+        let project = project_at(&root);
+        let everything = [ProjectPath::default()];
+
+        fs::create_dir_all(root.join("parts")).unwrap();
+        fs::write(root.join("parts/bolt.sldprt"), b"released bolt").unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        let first = commit_project(&project, "ana".into(), "release".into(), &objects, &db)
+            .await
+            .unwrap();
+        let released = fs::metadata(root.join("parts/bolt.sldprt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        // Saved over by accident, and a new file next to it
+        fs::write(root.join("parts/bolt.sldprt"), b"oops").unwrap();
+        fs::write(root.join("parts/nut.sldprt"), b"nut").unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+
+        let changed = revert_to_head(&project, &[path("parts")], &objects, &trash, &db)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![path("parts/bolt.sldprt"), path("parts/nut.sldprt")],
+            changed
+        );
+        assert_eq!(
+            b"released bolt".to_vec(),
+            fs::read(root.join("parts/bolt.sldprt")).unwrap()
+        );
+        assert!(!root.join("parts/nut.sldprt").exists());
+        let bolt = fs::metadata(root.join("parts/bolt.sldprt")).unwrap();
+        assert_eq!(released, bolt.modified().unwrap());
+        // The trees already know, so a refresh finds nothing new
+        assert!(refresh_paths(&project, &everything, &db)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Both overwritten files can be had back
+        let trashed = list_trash(&project, &db).unwrap();
+        assert_eq!(2, trashed.len());
+        let oops = trashed
+            .iter()
+            .find(|entry| entry.path == path("parts/bolt.sldprt"))
+            .unwrap();
+        assert_eq!(b"oops".to_vec(), fs::read(&oops.location).unwrap());
+
+        recover_trashed(&project, oops.id, &objects, &trash, &db)
+            .await
+            .unwrap();
+        assert_eq!(
+            b"oops".to_vec(),
+            fs::read(root.join("parts/bolt.sldprt")).unwrap()
+        );
+        assert_eq!(2, list_trash(&project, &db).unwrap().len());
+
+        // Already as it was in the commit, so nothing to do the second time
+        let changed = restore_path(
+            &project,
+            &path("parts/bolt.sldprt"),
+            first.id,
+            &objects,
+            &trash,
+            &db,
+        )
+        .await
+        .unwrap();
+        assert_eq!(vec![path("parts/bolt.sldprt")], changed);
+        let changed = restore_path(
+            &project,
+            &path("parts/bolt.sldprt"),
+            first.id,
+            &objects,
+            &trash,
+            &db,
+        )
+        .await
+        .unwrap();
+        assert!(changed.is_empty());
+        assert_eq!(
+            b"released bolt".to_vec(),
+            fs::read(root.join("parts/bolt.sldprt")).unwrap()
+        );
+    }
+}
@@ -0,0 +1,428 @@
+//! Full scans of a project, bringing the local trees in line with the disk.
+//!
+//! A scan walks the whole project, hashes what changed and records what's gone. It
+//! reports its progress as it goes and can be cancelled between files. Changes are
+//! staged and applied a batch at a time, with a file's metadata and hash always in
+//! the same transaction. However far a scan got, crashed or not, the trees agree with
+//! each other and the next scan picks up where it left off.
+
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use futures::prelude::*;
+
+use crate::{
+    db::{
+        hashing::hash_settings,
+        ignore_rules::IgnoreRules,
+        project_path::ProjectPath,
+        projects::{save_project, save_scan_report},
+        refresh_state::{
+            get_metadatas, has_current_hashes, hash_and_finalize, skip_reason,
+            skipped_project_paths,
+        },
+        staging::{LocalTrees, StagedChanges},
+        store::MetadataStore,
+        types::{
+            Project, ScanPhase, ScanProgress, ScanReport, SkipReason, SkippedPath,
+        },
+    },
+    error::{Error, Result},
+};
+
+// Plenty for a progress bar, without flooding the frontend with events
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+// Files staged before they're applied. Bigger batches are quicker, but a crash
+// loses more of the scan.
+const STAGE_SIZE: usize = 500;
+
+/// Scan `project`, calling `on_progress` every so often. Setting `cancelled` stops the
+/// scan after the file it's on, and the report says it was cancelled.
+pub async fn scan_project(
+    project: &Project,
+    db: &dyn MetadataStore,
+    on_progress: impl FnMut(&ScanProgress),
+    cancelled: &AtomicBool,
+) -> Result<ScanReport> {
+    let root = project.root.clone();
+    let settings = hash_settings(db)?;
+    let update_start_time = Utc::now();
+    let mut progress = Progress::new(project, on_progress);
+
+    let trees = LocalTrees::open(project, db)?;
+    let mut staged = StagedChanges::default();
+
+    let mut skipped = Vec::new();
+    let mut files_seen = 0;
+    let mut bytes_seen = 0;
+    let mut files_to_rehash = Vec::new();
+
+    // Check metadata for each file, seeing if it needs to be rehashed. Unchanged files
+    // get their update time bumped so pruning knows they're still there, changed ones
+    // are only staged once they're hashed.
+    progress.phase(ScanPhase::Walking, None, None);
+    for entry in get_metadatas(&root)? {
+        if cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let (path, metadatum) = match entry {
+            Ok(entry) => entry,
+            Err(skip) => {
+                skipped.push(skip);
+                continue;
+            }
+        };
+        files_seen += 1;
+        bytes_seen += metadatum.size;
+        progress.advance(&path, metadatum.size);
+
+        let unchanged = match trees.metadata.get(&path) {
+            Ok(prev) => prev.as_ref() == Some(&metadatum),
+            // A record that doesn't decode gets overwritten by a fresh one
+            Err(Error::CorruptRecord { .. }) => false,
+            Err(err) => return Err(err),
+        // Hashes from older settings, or of an older version of the file, need redoing
+        } && has_current_hashes(&trees.hashes, &metadatum, &settings)?;
+
+        if unchanged {
+            staged.seen(&metadatum)?;
+            if staged.len() >= STAGE_SIZE {
+                staged.apply(&trees)?;
+            }
+        } else {
+            files_to_rehash.push((path, metadatum));
+        }
+    }
+
+    // Hashing holds at most `HASH_MEMORY_BUDGET` no matter how many of these are in flight
+    progress.phase(
+        ScanPhase::Hashing,
+        Some(files_to_rehash.len()),
+        Some(files_to_rehash.iter().map(|(_, metadatum)| metadatum.size).sum()),
+    );
+    let mut hashes = stream::iter(files_to_rehash)
+        .map(|(path, metadata)| {
+            let root = root.clone();
+            tokio::spawn(async move { (path, hash_and_finalize(root, metadata, settings).await) })
+        })
+        .buffer_unordered(200);
+
+    let mut files_hashed = 0;
+    let mut bytes_hashed = 0;
+    while !cancelled.load(Ordering::Relaxed) {
+        let (path, hashed) = match hashes.next().await {
+            Some(hashed) => hashed.map_err(|err| format!("Hashing task failed: {}", err))?,
+            None => break,
+        };
+
+        match hashed {
+            Ok(data) => {
+                files_hashed += 1;
+                bytes_hashed += data.metadata.size;
+                progress.advance(&path, data.metadata.size);
+
+                staged.hashed(&data)?;
+                if staged.len() >= STAGE_SIZE {
+                    staged.apply(&trees)?;
+                }
+            }
+            // What's stored stays as it was, so the next scan tries again
+            Err(err) => {
+                let reason = match &err {
+                    Error::IoError(err) => skip_reason(err),
+                    err => SkipReason::Io { error: err.to_string() },
+                };
+                skipped.push(SkippedPath {
+                    path: PathBuf::from(path.as_str()),
+                    reason,
+                });
+            }
+        }
+    }
+    // Hashes still in flight are dropped, their files just look changed next time
+    drop(hashes);
+    // Pruning goes by the update times, so they have to be in the tree first
+    staged.apply(&trees)?;
+
+    // A cancelled walk didn't see everything, so what it missed isn't deleted
+    let mut files_removed = 0;
+    if !cancelled.load(Ordering::Relaxed) {
+        // Skipped files might well still be there, so they aren't deletions
+        let skipped_paths = skipped_project_paths(&root, &skipped);
+        // Neither are tracked files that an ignore rule hides from the walk now
+        let mut rules = IgnoreRules::new(&root)?;
+
+        progress.phase(ScanPhase::Pruning, Some(trees.metadata.len()), None);
+        for item in trees.metadata.iter() {
+            if cancelled.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let (path, metadatum) = item?;
+            progress.advance(&metadatum.path, 0);
+
+            let skipped = skipped_paths
+                .iter()
+                .any(|skipped| metadatum.path.starts_with(skipped));
+
+            if metadatum.update_time < update_start_time
+                && !skipped
+                && rules.explain(&metadatum.path).is_none()
+            {
+                let data = trees.hashes.get(&path)?;
+                files_removed += 1;
+
+                staged.deleted(&path, data, update_start_time)?;
+                if staged.len() >= STAGE_SIZE {
+                    staged.apply(&trees)?;
+                }
+            }
+        }
+    }
+    staged.apply(&trees)?;
+    progress.finish();
+
+    db.flush()?;
+
+    let cancelled = cancelled.load(Ordering::Relaxed);
+    if !cancelled {
+        let mut project = project.clone();
+        project.last_scanned = Some(update_start_time);
+        save_project(db, &project)?;
+    }
+
+    println!(
+        "Metadata: {}, Hashed: {}, Skipped: {}",
+        trees.metadata.len(),
+        trees.hashes.len(),
+        skipped.len()
+    );
+
+    let finished = Utc::now();
+    let report = ScanReport {
+        project_id: project.id,
+        started: update_start_time,
+        finished,
+        duration_ms: (finished - update_start_time).num_milliseconds().max(0) as u64,
+        files_seen,
+        bytes_seen,
+        files_hashed,
+        bytes_hashed,
+        files_removed,
+        skipped,
+        cancelled,
+    };
+    save_scan_report(db, &report)?;
+
+    Ok(report)
+}
+
+/// Keeps the running totals and hands them on, at most once per `PROGRESS_INTERVAL`
+/// apart from phase changes
+struct Progress<F> {
+    progress: ScanProgress,
+    on_progress: F,
+    last_sent: Option<Instant>,
+}
+
+impl<F: FnMut(&ScanProgress)> Progress<F> {
+    fn new(project: &Project, on_progress: F) -> Self {
+        Self {
+            progress: ScanProgress {
+                project_id: project.id,
+                phase: ScanPhase::Walking,
+                files_done: 0,
+                files_total: None,
+                bytes_done: 0,
+                bytes_total: None,
+                current_file: None,
+            },
+            on_progress,
+            last_sent: None,
+        }
+    }
+
+    fn phase(&mut self, phase: ScanPhase, files_total: Option<usize>, bytes_total: Option<u64>) {
+        self.progress = ScanProgress {
+            phase,
+            files_done: 0,
+            files_total,
+            bytes_done: 0,
+            bytes_total,
+            current_file: None,
+            ..self.progress
+        };
+        self.send();
+    }
+
+    fn advance(&mut self, path: &ProjectPath, bytes: u64) {
+        self.progress.files_done += 1;
+        self.progress.bytes_done += bytes;
+
+        let due = self
+            .last_sent
+            .is_none_or(|last_sent| last_sent.elapsed() >= PROGRESS_INTERVAL);
+        if due {
+            self.progress.current_file = Some(path.clone());
+            self.send();
+        }
+    }
+
+    /// Send the final totals, which the interval might have held back
+    fn finish(&mut self) {
+        self.progress.current_file = None;
+        self.send();
+    }
+
+    fn send(&mut self) {
+        (self.on_progress)(&self.progress);
+        self.last_sent = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::write,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use xxhash_rust::xxh3::xxh3_128;
+
+    use super::scan_project;
+    use crate::db::{
+        content_hash::ContentHash,
+        ignore_rules::IGNORE_FILE_NAME,
+        memory_store::MemoryStore,
+        projects::last_scan_report,
+        refresh_state::{get_metadatas, refresh_paths},
+        staging::LocalTrees,
+        test_support::{path, project_at},
+        types::{LocalFileMetadata, ScanPhase},
+    };
+
+    #[tokio::test]
+    async fn test_scan_reports_progress() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path().join("bolt.sldprt"), "bolt").unwrap();
+        write(root.path().join("nut.sldprt"), "nut").unwrap();
+        let db = sled::Config::default().temporary(true).open().unwrap();
+
+        let mut updates = Vec::new();
+        let report = scan_project(
+            &project_at(root.path()),
+            &db,
+            |progress| updates.push(progress.clone()),
+            &AtomicBool::new(false),
+        )
+        .await
+        .unwrap();
+
+        assert!(!report.cancelled);
+        assert_eq!(2, report.files_hashed);
+        assert_eq!(7, report.bytes_hashed);
+        assert_eq!(Some(report.files_seen), last_scan_report(&db, 1).unwrap().map(|r| r.files_seen));
+
+        let phases: Vec<ScanPhase> = updates.iter().map(|progress| progress.phase).collect();
+        assert!(phases.contains(&ScanPhase::Hashing));
+        let last = updates.last().unwrap();
+        assert_eq!(ScanPhase::Pruning, last.phase);
+        assert_eq!(last.files_total, Some(last.files_done));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_scan_keeps_trees_consistent() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path().join("bolt.sldprt"), "bolt").unwrap();
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let project = project_at(root.path());
+
+        // Cancelled as soon as it starts, so nothing gets recorded at all
+        let cancelled = AtomicBool::new(false);
+        let report = scan_project(
+            &project,
+            &db,
+            |_| cancelled.store(true, Ordering::Relaxed),
+            &cancelled,
+        )
+        .await
+        .unwrap();
+
+        assert!(report.cancelled);
+        let trees = LocalTrees::open(&project, &db).unwrap();
+        assert_eq!(trees.metadata.len(), trees.hashes.len());
+
+        // And the next scan finishes the job
+        let report = scan_project(&project, &db, |_| {}, &AtomicBool::new(false))
+            .await
+            .unwrap();
+        assert_eq!(1, report.files_hashed);
+        assert_eq!(1, trees.hashes.len());
+    }
+
+    #[tokio::test]
+    async fn test_rehashes_when_hash_tree_is_behind() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path().join("bolt.sldprt"), "bolt").unwrap();
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let project = project_at(root.path());
+        scan_project(&project, &db, |_| {}, &AtomicBool::new(false))
+            .await
+            .unwrap();
+
+        // What an older scan that died between the two writes left behind
+        write(root.path().join("bolt.sldprt"), "bolt v2").unwrap();
+        let (path, metadatum) = get_metadatas(root.path()).unwrap().next().unwrap().unwrap();
+        let trees = LocalTrees::open(&project, &db).unwrap();
+        trees.metadata.insert(&path, &metadatum).unwrap();
+
+        let report = scan_project(&project, &db, |_| {}, &AtomicBool::new(false))
+            .await
+            .unwrap();
+
+        assert_eq!(1, report.files_hashed);
+        let data = trees.hashes.get(&path).unwrap().unwrap();
+        assert_eq!(ContentHash::xxh3_128(xxh3_128(b"bolt v2")), data.hash);
+    }
+
+    #[tokio::test]
+    async fn test_newly_ignored_files_are_not_deleted() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path().join("bolt.sldprt"), "bolt").unwrap();
+        write(root.path().join("notes.bak"), "notes").unwrap();
+        let db = MemoryStore::default();
+        let project = project_at(root.path());
+        let trees = LocalTrees::open(&project, &db).unwrap();
+
+        // Tracked from before `*.bak` was ignored
+        scan_project(&project, &db, |_| {}, &AtomicBool::new(false))
+            .await
+            .unwrap();
+        let bolt = trees.metadata.get(&path("bolt.sldprt")).unwrap().unwrap();
+        let notes = LocalFileMetadata {
+            path: path("notes.bak"),
+            ..bolt
+        };
+        trees.metadata.insert(&notes.path, &notes).unwrap();
+
+        // And the bolt by a project rule
+        write(root.path().join(IGNORE_FILE_NAME), "bolt.sldprt\n").unwrap();
+        let report = scan_project(&project, &db, |_| {}, &AtomicBool::new(false))
+            .await
+            .unwrap();
+        let changed = refresh_paths(&project, &[path("bolt.sldprt"), path("notes.bak")], &db)
+            .await
+            .unwrap();
+
+        assert_eq!(0, report.files_removed);
+        assert!(changed.is_empty());
+        assert!(trees.tombstones.is_empty());
+        assert!(trees.metadata.contains_key(&path("bolt.sldprt")).unwrap());
+        assert!(trees.metadata.contains_key(&path("notes.bak")).unwrap());
+    }
+}
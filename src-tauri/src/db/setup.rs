@@ -1,40 +1,63 @@
 use std::{path::{Path, PathBuf}, sync::Arc};
 
 use once_cell::sync::OnceCell;
-use serde_cbor::to_vec;
 use tauri::{Config, api::path::app_dir};
 
-use crate::error::Result;
+use crate::{
+    db::{
+        migrate::migrate_with_backup, objects::ObjectStore, record::encode, restore::Trash,
+        store::Store,
+    },
+    error::Result,
+};
 
 
-static DB: OnceCell<sled::Db> = OnceCell::new();
+static DB: OnceCell<Store> = OnceCell::new();
 
-pub fn init_db(db_path: dyn AsRef<Path>, app_path: dyn AsRef<Path>) -> Result<sled::Db> {
+pub fn init_db(db_path: impl AsRef<Path>, app_path: impl AsRef<Path>) -> Result<sled::Db> {
     let db = sled::open(db_path).unwrap();
+    // Before anything is written in the current format
+    migrate_with_backup(&db, app_path.as_ref())?;
+
     let prefs = db.open_tree("preferences")?;
     prefs.insert(
         b"appdata",
-        to_vec(&app_path)?
+        encode(&app_path.as_ref().to_path_buf())?
     )?;
     Ok(db)
 }
 
-pub fn make_db<'a>(config: Arc<Config>) -> &'a sled::Db {
+/// Where the app keeps its data
+pub fn app_path(config: &Config) -> PathBuf {
+    match app_dir(config) {
+        Some(app_dir) => app_dir,
+        None => PathBuf::from("./"),
+    }
+}
+
+pub fn make_db<'a>(config: Arc<Config>) -> &'a Store {
     if DB.get().is_none() {
-        let app_dir = match app_dir(&*config) {
-            Some(app_dir) => app_dir,
-            None => PathBuf::from("./"),
-        };
+        let app_dir = app_path(&config);
 
         let db = init_db(app_dir.join("splatcad.db"), app_dir).unwrap();
 
-        DB.set(db).expect("Failed to create DB")
+        if DB.set(Arc::new(db)).is_err() {
+            panic!("Failed to create DB");
+        }
     }
 
     DB.get().expect("Failed to get DB")
 }
 
-pub fn get_db<'a>() -> &'a sled::Db {
+pub fn make_object_store(config: &Config) -> ObjectStore {
+    ObjectStore::new(app_path(config).join("objects"))
+}
+
+pub fn make_trash(config: &Config) -> Trash {
+    Trash::new(app_path(config).join("trash"))
+}
+
+pub fn get_db<'a>() -> &'a Store {
     DB.get()
         .expect("Failed to get DB, be sure to call `make_db` first")
 }
\ No newline at end of file
@@ -0,0 +1,113 @@
+//! Applying changes to a project's local trees together, or not at all.
+//!
+//! Scans stage what they find here and apply it a batch at a time, in one
+//! `apply_batches` across every tree it touches. If the app dies partway through, each
+//! file either has its new metadata and hash, or its old ones. The hash tree is never
+//! left behind the metadata tree, which would make a changed file look up to date.
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    db::{
+        project_path::ProjectPath,
+        store::MetadataStore,
+        typed_tree::{TypedBatch, TypedTree},
+        types::{ChunkManifest, LocalFileData, LocalFileMetadata, Project, Tombstone, TreeNames},
+    },
+    error::Result,
+};
+
+/// The trees that local state changes touch
+pub struct LocalTrees<'a> {
+    store: &'a dyn MetadataStore,
+    pub metadata: TypedTree<ProjectPath, LocalFileMetadata>,
+    pub hashes: TypedTree<ProjectPath, LocalFileData>,
+    pub tombstones: TypedTree<ProjectPath, Tombstone>,
+    pub manifests: TypedTree<ProjectPath, ChunkManifest>,
+}
+
+impl<'a> LocalTrees<'a> {
+    pub fn open(project: &Project, db: &'a dyn MetadataStore) -> Result<Self> {
+        Ok(Self {
+            store: db,
+            metadata: TypedTree::open(db, project.tree_name(TreeNames::BASIC_LOCAL_METADATA))?,
+            hashes: TypedTree::open(db, project.tree_name(TreeNames::HASH_LOCAL_METDATA))?,
+            tombstones: TypedTree::open(db, project.tree_name(TreeNames::LOCAL_TOMBSTONES))?,
+            manifests: TypedTree::open(db, project.tree_name(TreeNames::CHUNK_MANIFESTS))?,
+        })
+    }
+}
+
+/// Changes waiting to be applied to `LocalTrees`
+#[derive(Default)]
+pub struct StagedChanges {
+    metadata: TypedBatch<ProjectPath, LocalFileMetadata>,
+    hashes: TypedBatch<ProjectPath, LocalFileData>,
+    tombstones: TypedBatch<ProjectPath, Tombstone>,
+    manifests: TypedBatch<ProjectPath, ChunkManifest>,
+    len: usize,
+}
+
+impl StagedChanges {
+    /// The file is still there and unchanged, so only its metadata's update time moves
+    pub fn seen(&mut self, metadata: &LocalFileMetadata) -> Result<()> {
+        self.metadata.insert(&metadata.path, metadata)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// The file was (re)hashed. It exists, so it's no longer deleted either.
+    pub fn hashed(&mut self, data: &LocalFileData) -> Result<()> {
+        let path = &data.metadata.path;
+
+        self.metadata.insert(path, &data.metadata)?;
+        self.hashes.insert(path, data)?;
+        self.tombstones.remove(path);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// The file is gone. `data` is what it was last hashed as, if it ever was.
+    pub fn deleted(
+        &mut self,
+        path: &ProjectPath,
+        data: Option<LocalFileData>,
+        deleted_time: DateTime<Utc>,
+    ) -> Result<()> {
+        self.metadata.remove(path);
+        self.hashes.remove(path);
+        // Keep what the file looked like so the deletion can be propagated
+        if let Some(data) = data {
+            let tombstone = Tombstone { data, deleted_time };
+            self.tombstones.insert(path, &tombstone)?;
+        }
+        // Chunk manifests of deleted files are no use anymore
+        self.manifests.remove(path);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// How many files have changes waiting
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Apply everything staged in one transaction, leaving nothing staged
+    pub fn apply(&mut self, trees: &LocalTrees) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let staged = std::mem::take(self);
+        trees.store.apply_batches(&[
+            (&trees.metadata.name(), staged.metadata.inner()),
+            (&trees.hashes.name(), staged.hashes.inner()),
+            (&trees.tombstones.name(), staged.tombstones.inner()),
+            (&trees.manifests.name(), staged.manifests.inner()),
+        ])
+    }
+}
@@ -0,0 +1,226 @@
+//! Where the engine keeps its state.
+//!
+//! Diffing, scanning and syncing only need named trees of ordered bytes, so they talk to
+//! a `MetadataStore` rather than to sled. `sled::Db` is the one the app runs on, and
+//! `MemoryStore` keeps everything in memory, for tests or running without a disk.
+//! Migrations and backups are about sled's files, so they stay sled only.
+
+use std::{sync::Arc, time::Duration};
+
+use sled::{transaction::ConflictableTransactionError, Transactional};
+
+use crate::error::Result;
+
+/// The store the app was set up with. Managed as Tauri state.
+pub type Store = Arc<dyn MetadataStore>;
+
+/// Entries in key order, from one end or the other
+pub type StoreIter = Box<dyn DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// A change to a tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeEvent<K, V> {
+    Insert { key: K, value: V },
+    Remove { key: K },
+}
+
+pub type StoreEvent = TreeEvent<Vec<u8>, Vec<u8>>;
+
+pub trait MetadataStore: Send + Sync {
+    /// The tree called `name`, made empty if it isn't there yet
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn StoreTree>>;
+    /// Returns whether there was such a tree
+    fn drop_tree(&self, name: &str) -> Result<bool>;
+    /// An ID this store has never handed out before
+    fn generate_id(&self) -> Result<u64>;
+    /// Apply each batch to the tree it's named with, all of them or none
+    fn apply_batches(&self, batches: &[(&str, &WriteBatch)]) -> Result<()>;
+    /// Make sure everything written so far survives a crash
+    fn flush(&self) -> Result<()>;
+}
+
+pub trait StoreTree: Send + Sync {
+    fn name(&self) -> String;
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Returns the value that was there before
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    /// Returns the value that was there, if there was one
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn clear(&self) -> Result<()>;
+    /// Everything whose key starts with `prefix`, which can be empty
+    fn scan_prefix(&self, prefix: &[u8]) -> StoreIter;
+    /// Changes to everything whose key starts with `prefix`, from now on
+    fn watch_prefix(&self, prefix: &[u8]) -> Box<dyn StoreWatch>;
+    fn apply_batch(&self, batch: &WriteBatch) -> Result<()>;
+    /// Set `key` to `new` if it's `old` right now, where `None` is no value at all.
+    /// Returns whether it was set.
+    fn compare_and_swap(&self, key: &[u8], old: Option<&[u8]>, new: Option<Vec<u8>>)
+        -> Result<bool>;
+}
+
+pub trait StoreWatch: Send {
+    /// Blocks until the next change. `None` once there can't be any more.
+    fn next_event(&mut self) -> Option<StoreEvent>;
+    /// The next change, or `None` if there wasn't one in time
+    fn next_event_timeout(&mut self, timeout: Duration) -> Option<StoreEvent>;
+}
+
+/// Writes to apply to one tree at once
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    // `None` removes the key
+    writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.push((key, Some(value)));
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.writes.push((key, None));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// In the order they were made, a later write to a key wins
+    pub fn writes(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.writes
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+
+    fn to_sled(&self) -> sled::Batch {
+        let mut batch = sled::Batch::default();
+        for (key, value) in self.writes() {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.remove(key),
+            }
+        }
+        batch
+    }
+}
+
+impl MetadataStore for sled::Db {
+    fn open_tree(&self, name: &str) -> Result<Arc<dyn StoreTree>> {
+        Ok(Arc::new(sled::Db::open_tree(self, name)?))
+    }
+
+    fn drop_tree(&self, name: &str) -> Result<bool> {
+        Ok(sled::Db::drop_tree(self, name)?)
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        Ok(sled::Db::generate_id(self)?)
+    }
+
+    fn apply_batches(&self, batches: &[(&str, &WriteBatch)]) -> Result<()> {
+        let trees = batches
+            .iter()
+            .map(|(name, _)| sled::Db::open_tree(self, name))
+            .collect::<sled::Result<Vec<_>>>()?;
+        let batches: Vec<sled::Batch> = batches.iter().map(|(_, batch)| batch.to_sled()).collect();
+
+        trees.as_slice().transaction(|trees| {
+            for (tree, batch) in trees.iter().zip(&batches) {
+                tree.apply_batch(batch)?;
+            }
+            Ok::<_, ConflictableTransactionError>(())
+        })?;
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        sled::Tree::flush(self)?;
+        Ok(())
+    }
+}
+
+impl StoreTree for sled::Tree {
+    fn name(&self) -> String {
+        String::from_utf8_lossy(&sled::Tree::name(self)).into_owned()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(sled::Tree::get(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(sled::Tree::insert(self, key, value)?.map(|value| value.to_vec()))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(sled::Tree::remove(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(sled::Tree::contains_key(self, key)?)
+    }
+
+    fn len(&self) -> usize {
+        sled::Tree::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        sled::Tree::is_empty(self)
+    }
+
+    fn clear(&self) -> Result<()> {
+        Ok(sled::Tree::clear(self)?)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> StoreIter {
+        Box::new(sled::Tree::scan_prefix(self, prefix).map(|item| {
+            let (key, value) = item?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    fn watch_prefix(&self, prefix: &[u8]) -> Box<dyn StoreWatch> {
+        Box::new(sled::Tree::watch_prefix(self, prefix))
+    }
+
+    fn apply_batch(&self, batch: &WriteBatch) -> Result<()> {
+        Ok(sled::Tree::apply_batch(self, batch.to_sled())?)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        Ok(sled::Tree::compare_and_swap(self, key, old, new)?.is_ok())
+    }
+}
+
+impl StoreWatch for sled::Subscriber {
+    fn next_event(&mut self) -> Option<StoreEvent> {
+        self.next().map(from_sled_event)
+    }
+
+    fn next_event_timeout(&mut self, timeout: Duration) -> Option<StoreEvent> {
+        self.next_timeout(timeout).ok().map(from_sled_event)
+    }
+}
+
+fn from_sled_event(event: sled::Event) -> StoreEvent {
+    match event {
+        sled::Event::Insert { key, value } => TreeEvent::Insert {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        },
+        sled::Event::Remove { key } => TreeEvent::Remove { key: key.to_vec() },
+    }
+}
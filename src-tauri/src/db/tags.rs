@@ -0,0 +1,338 @@
+//! Tags, named snapshots of HEAD.
+//!
+//! A tag keeps its own copy of the HEAD files it was made from, so it says exactly what
+//! was there even if history is rewritten or the objects are gone. Tags can't be moved
+//! or changed, a different state needs a different name. The copy is rebuilt from the
+//! HEAD commit rather than read off the HEAD tree, which a commit can change mid-copy.
+//!
+//! The files are kept one per key under the tag's ID, and only once they're all written
+//! does the name get claimed. Whichever of two tags with the same name claims it first
+//! wins, and the other's files are cleaned up.
+
+use std::{cmp::Reverse, fs, path::Path};
+
+use chrono::Utc;
+
+use crate::{
+    db::{
+        commits::{files_at, head_commit},
+        compare::{DiffIter, NoBase},
+        project_path::ProjectPath,
+        renames::detect_renames,
+        store::MetadataStore,
+        typed_tree::{Key, TypedBatch, TypedIter, TypedTree},
+        types::{
+            CommitId, FileDiff, LocalFileData, ManifestEntry, Project, Tag, TagFileKey, TagId,
+            TagManifest, TreeNames,
+        },
+    },
+    error::Result,
+};
+
+// Files written to a new tag at once
+const FILES_BATCH_SIZE: usize = 1_000;
+
+fn tags(project: &Project, db: &dyn MetadataStore) -> Result<TypedTree<String, Tag>> {
+    TypedTree::open(db, project.tree_name(TreeNames::TAGS))
+}
+
+fn tag_files(
+    project: &Project,
+    db: &dyn MetadataStore,
+) -> Result<TypedTree<TagFileKey, LocalFileData>> {
+    TypedTree::open(db, project.tree_name(TreeNames::TAG_FILES))
+}
+
+/// Tag what HEAD is now as `name`
+pub fn create_tag(
+    project: &Project,
+    name: String,
+    description: String,
+    creator: String,
+    db: &dyn MetadataStore,
+) -> Result<Tag> {
+    let name = name.trim().to_owned();
+    if name.is_empty() {
+        return Err("A tag needs a name".to_owned().into());
+    }
+    let tags = tags(project, db)?;
+    // Saves copying HEAD for nothing, the name is only really claimed below
+    if tags.contains_key(&name)? {
+        return Err(format!("There's already a tag called {}", name).into());
+    }
+
+    let id = db.generate_id()?;
+    let commit = head_commit(project, db)?.map(|commit| commit.id);
+    let files = tag_files(project, db)?;
+    let file_count = match copy_files(project, id, commit, &files, db) {
+        Ok(count) => count,
+        Err(err) => {
+            let _ = remove_files(id, &files);
+            return Err(err);
+        }
+    };
+
+    let tag = Tag {
+        id,
+        name,
+        project_id: project.id,
+        commit,
+        description,
+        creator,
+        created: Utc::now(),
+        file_count,
+    };
+    if !tags.insert_new(&tag.name, &tag)? {
+        remove_files(id, &files)?;
+        return Err(format!("There's already a tag called {}", tag.name).into());
+    }
+
+    Ok(tag)
+}
+
+/// Copy every file as of `commit` in under tag `id`, returning how many there were.
+/// Without any commits the HEAD tree is all there is.
+fn copy_files(
+    project: &Project,
+    id: TagId,
+    commit: Option<CommitId>,
+    files: &TypedTree<TagFileKey, LocalFileData>,
+    db: &dyn MetadataStore,
+) -> Result<usize> {
+    let head: TypedTree<ProjectPath, LocalFileData> =
+        TypedTree::open(db, project.tree_name(TreeNames::HASH_HEAD_METDATA))?;
+    let snapshot: Box<dyn Iterator<Item = Result<(ProjectPath, LocalFileData)>>> = match commit {
+        Some(commit) => Box::new(files_at(project, commit, db)?.into_iter().map(Ok)),
+        None => Box::new(head.iter()),
+    };
+
+    let mut count = 0;
+    let mut batch = TypedBatch::default();
+    for item in snapshot {
+        let (path, data) = item?;
+        batch.insert(&TagFileKey { tag: id, path }, &data)?;
+        count += 1;
+
+        if count % FILES_BATCH_SIZE == 0 {
+            files.apply_batch(&std::mem::take(&mut batch))?;
+        }
+    }
+    files.apply_batch(&batch)?;
+
+    // Nothing pins the HEAD tree, so it's only good if no first commit landed meanwhile
+    if commit.is_none() && head_commit(project, db)?.is_some() {
+        return Err(format!(
+            "Project {} was committed to meanwhile, tag again",
+            project.id
+        )
+        .into());
+    }
+
+    Ok(count)
+}
+
+fn remove_files(id: TagId, files: &TypedTree<TagFileKey, LocalFileData>) -> Result<()> {
+    let mut batch = TypedBatch::default();
+    for key in files.keys_with_prefix(id.to_key()) {
+        batch.remove(&key?);
+    }
+    files.apply_batch(&batch)
+}
+
+/// The project's tags, newest first
+pub fn list_tags(project: &Project, db: &dyn MetadataStore) -> Result<Vec<Tag>> {
+    let mut tags = tags(project, db)?
+        .iter()
+        .map(|item| Ok(item?.1))
+        .collect::<Result<Vec<_>>>()?;
+    tags.sort_by_key(|tag| Reverse(tag.created));
+    Ok(tags)
+}
+
+pub fn get_tag(project: &Project, name: &str, db: &dyn MetadataStore) -> Result<Tag> {
+    tags(project, db)?
+        .get(&name.to_owned())?
+        .ok_or_else(|| format!("No tag called {} in project {}", name, project.id).into())
+}
+
+/// The files of `tag`, in path order
+pub fn files_of(
+    project: &Project,
+    tag: &Tag,
+    db: &dyn MetadataStore,
+) -> Result<TypedIter<TagFileKey, LocalFileData>> {
+    Ok(tag_files(project, db)?.scan_prefix(tag.id.to_key()))
+}
+
+/// What changed from tag `from`, LEFT, to tag `to`, RIGHT
+pub fn diff_tags(
+    project: &Project,
+    from: &str,
+    to: &str,
+    db: &dyn MetadataStore,
+) -> Result<Vec<FileDiff>> {
+    let items = |name: &str| -> Result<_> {
+        let files = files_of(project, &get_tag(project, name, db)?, db)?;
+        Ok(files.map(|item| item.map(|(key, data)| (key.path, data))))
+    };
+
+    // Same as `find_diffs`, without reading either tag into memory first
+    let diffs = DiffIter::new(items(from)?, items(to)?, NoBase).collect::<Result<Vec<_>>>()?;
+    Ok(detect_renames(diffs))
+}
+
+/// The tag and every file in it
+pub fn tag_manifest(project: &Project, name: &str, db: &dyn MetadataStore) -> Result<TagManifest> {
+    let tag = get_tag(project, name, db)?;
+    let files = files_of(project, &tag, db)?
+        .map(|item| {
+            let (_, data) = item?;
+            Ok(ManifestEntry {
+                path: data.metadata.path,
+                size: data.metadata.size,
+                modified: data.metadata.modified,
+                hash: data.hash,
+                strong_hash: data.strong_hash,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(TagManifest { tag, files })
+}
+
+/// Write the tag's manifest to `destination` as JSON
+pub fn export_tag_manifest(
+    project: &Project,
+    name: &str,
+    destination: &Path,
+    db: &dyn MetadataStore,
+) -> Result<TagManifest> {
+    let manifest = tag_manifest(project, name, db)?;
+    let json = serde_json::to_vec_pretty(&manifest)
+        .map_err(|err| format!("Couldn't write the manifest of {}: {}", name, err))?;
+    fs::write(destination, json)?;
+
+    Ok(manifest)
+}
+
+/// Drop every tag of a project
+pub fn remove_tags(project: &Project, db: &dyn MetadataStore) -> Result<()> {
+    db.drop_tree(&project.tree_name(TreeNames::TAGS))?;
+    db.drop_tree(&project.tree_name(TreeNames::TAG_FILES))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{create_tag, diff_tags, export_tag_manifest, files_of, list_tags};
+    use crate::db::{
+        commits::commit_project,
+        memory_store::MemoryStore,
+        objects::ObjectStore,
+        project_path::ProjectPath,
+        refresh_state::refresh_paths,
+        test_support::{path, project_at},
+        typed_tree::TypedTree,
+        types::{DiffTypes, LocalFileData, TagManifest, TreeNames},
+    };
+
+    #[tokio::test]
+    async fn test_tags() {
+        let db = MemoryStore::default();
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        let root = dir.path().join("robot");
+        let project = project_at(&root);
+        let everything = [ProjectPath::default()];
+
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("bracket.sldprt"), b"bracket").unwrap();
+        fs::write(root.join("plate.sldprt"), b"plate").unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        commit_project(&project, "ana".into(), "first".into(), &objects, &db)
+            .await
+            .unwrap();
+        let rev_a = create_tag(
+            &project,
+            "rev-a".into(),
+            "To the shop".into(),
+            "ana".into(),
+            &db,
+        )
+        .unwrap();
+        assert_eq!(2, rev_a.file_count);
+        assert!(create_tag(&project, "rev-a".into(), String::new(), "ben".into(), &db).is_err());
+        assert_eq!(2, files_of(&project, &rev_a, &db).unwrap().count());
+
+        fs::write(root.join("bracket.sldprt"), b"thicker bracket").unwrap();
+        fs::remove_file(root.join("plate.sldprt")).unwrap();
+        refresh_paths(&project, &everything, &db).await.unwrap();
+        commit_project(&project, "ben".into(), "second".into(), &objects, &db)
+            .await
+            .unwrap();
+        create_tag(&project, "rev-b".into(), String::new(), "ben".into(), &db).unwrap();
+
+        let names: Vec<_> = list_tags(&project, &db)
+            .unwrap()
+            .into_iter()
+            .map(|tag| tag.name)
+            .collect();
+        assert_eq!(vec!["rev-b".to_owned(), "rev-a".to_owned()], names);
+
+        // The plate only being in rev-a shows up as a create on its side
+        let diffs = diff_tags(&project, "rev-a", "rev-b", &db).unwrap();
+        let kinds: Vec<_> = diffs
+            .iter()
+            .map(|diff| (diff.path.clone(), diff.diff_type.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (path("bracket.sldprt"), DiffTypes::RightNewer),
+                (path("plate.sldprt"), DiffTypes::LeftCreate),
+            ],
+            kinds
+        );
+
+        let destination = dir.path().join("rev-a.json");
+        let manifest = export_tag_manifest(&project, "rev-a", &destination, &db).unwrap();
+        let exported: TagManifest =
+            serde_json::from_slice(&fs::read(&destination).unwrap()).unwrap();
+        assert_eq!(manifest, exported);
+        assert_eq!(rev_a, exported.tag);
+        assert_eq!(2, exported.files.len());
+    }
+
+    #[tokio::test]
+    async fn test_tags_come_from_the_head_commit() {
+        let db = MemoryStore::default();
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        let root = dir.path().join("robot");
+        let project = project_at(&root);
+
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("bracket.sldprt"), b"bracket").unwrap();
+        refresh_paths(&project, &[ProjectPath::default()], &db)
+            .await
+            .unwrap();
+        commit_project(&project, "ana".into(), "first".into(), &objects, &db)
+            .await
+            .unwrap();
+
+        // As if the next commit was halfway through writing the HEAD tree
+        let head: TypedTree<ProjectPath, LocalFileData> =
+            TypedTree::open(&db, project.tree_name(TreeNames::HASH_HEAD_METDATA)).unwrap();
+        let mut plate = head.get(&path("bracket.sldprt")).unwrap().unwrap();
+        plate.metadata.path = path("plate.sldprt");
+        head.insert(&path("plate.sldprt"), &plate).unwrap();
+
+        let rev_a = create_tag(&project, "rev-a".into(), String::new(), "ana".into(), &db).unwrap();
+        let paths: Vec<_> = files_of(&project, &rev_a, &db)
+            .unwrap()
+            .map(|item| item.unwrap().0.path)
+            .collect();
+        assert_eq!(vec![path("bracket.sldprt")], paths);
+    }
+}
@@ -0,0 +1,22 @@
+//! Fixtures the db tests share.
+
+use std::path::Path;
+
+use chrono::Utc;
+
+use crate::db::{project_path::ProjectPath, types::Project};
+
+pub fn path(path: &str) -> ProjectPath {
+    ProjectPath::parse(path).unwrap()
+}
+
+/// A fresh project with id 1, rooted at `root`
+pub fn project_at(root: &Path) -> Project {
+    Project {
+        id: 1,
+        root: root.to_path_buf(),
+        cloud_project_id: None,
+        created: Utc::now(),
+        last_scanned: None,
+    }
+}
@@ -0,0 +1,489 @@
+//! Sled trees that know what's in them.
+//!
+//! A `TypedTree<K, V>` turns keys into bytes with `Key`, and values with a `Codec`,
+//! which is `RecordCodec` (the versioned envelopes in `record`) unless told otherwise.
+//! Everything that reads a tree goes through here, so a value that won't decode always
+//! comes back as an `Error::CorruptRecord` saying which tree and key it was.
+
+use std::{fmt::Display, marker::PhantomData, sync::Arc, time::Duration};
+
+use crate::{
+    db::{
+        content_hash::ContentHash,
+        keys::{decode_path_key, encode_path_key, folder_prefix},
+        project_path::ProjectPath,
+        record::{self, Record},
+        store::{MetadataStore, StoreEvent, StoreIter, StoreTree, StoreWatch, TreeEvent, WriteBatch},
+        types::TagFileKey,
+    },
+    error::{Error, Result},
+};
+
+/// Something a tree is keyed by
+pub trait Key: Display + Sized {
+    fn to_key(&self) -> Vec<u8>;
+    fn from_key(bytes: &[u8]) -> Result<Self>;
+}
+
+impl Key for ProjectPath {
+    fn to_key(&self) -> Vec<u8> {
+        encode_path_key(self)
+    }
+
+    fn from_key(bytes: &[u8]) -> Result<Self> {
+        decode_path_key(bytes)
+    }
+}
+
+// Project and commit IDs, big endian so they sort in order
+impl Key for u64 {
+    fn to_key(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+
+    fn from_key(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; 8] = bytes
+            .try_into()
+            .map_err(|_| format!("ID key is {} bytes long, not 8", bytes.len()))?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+// Names, like tag names
+impl Key for String {
+    fn to_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_key(bytes: &[u8]) -> Result<Self> {
+        String::from_utf8(bytes.to_vec())
+            .map_err(|err| format!("Name key is not valid UTF-8: {}", err).into())
+    }
+}
+
+// The tag's ID and then the path, so each tag's files are together and in path order
+impl Key for TagFileKey {
+    fn to_key(&self) -> Vec<u8> {
+        let mut key = self.tag.to_key();
+        key.extend(encode_path_key(&self.path));
+        key
+    }
+
+    fn from_key(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 8 {
+            return Err(format!(
+                "Tag file key is {} bytes long, too short for an ID",
+                bytes.len()
+            )
+            .into());
+        }
+        let (tag, path) = bytes.split_at(8);
+
+        Ok(Self {
+            tag: u64::from_key(tag)?,
+            path: decode_path_key(path)?,
+        })
+    }
+}
+
+// Content hashes, as their `algorithm:hex` string
+impl Key for ContentHash {
+    fn to_key(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    fn from_key(bytes: &[u8]) -> Result<Self> {
+        std::str::from_utf8(bytes)
+            .map_err(|err| format!("Hash key is not valid UTF-8: {}", err))?
+            .parse()
+    }
+}
+
+/// How a tree's values are turned into bytes and back
+pub trait Codec<V> {
+    fn encode(value: &V) -> Result<Vec<u8>>;
+    fn decode(bytes: &[u8]) -> Result<V>;
+}
+
+/// Versioned CBOR envelopes, see `record`
+pub struct RecordCodec;
+
+impl<V: Record> Codec<V> for RecordCodec {
+    fn encode(value: &V) -> Result<Vec<u8>> {
+        record::encode(value)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<V> {
+        record::decode(bytes)
+    }
+}
+
+// Holds onto the types without owning any of them, so they needn't be Send or Sync
+type Types<K, V, C> = PhantomData<fn() -> (K, V, C)>;
+
+pub struct TypedTree<K, V, C = RecordCodec> {
+    tree: Arc<dyn StoreTree>,
+    types: Types<K, V, C>,
+}
+
+// Derive would want K, V and C to be Clone too
+impl<K, V, C> Clone for TypedTree<K, V, C> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            types: PhantomData,
+        }
+    }
+}
+
+impl<K: Key, V, C: Codec<V>> TypedTree<K, V, C> {
+    pub fn new(tree: Arc<dyn StoreTree>) -> Self {
+        Self {
+            tree,
+            types: PhantomData,
+        }
+    }
+
+    pub fn open(db: &dyn MetadataStore, name: impl AsRef<str>) -> Result<Self> {
+        Ok(Self::new(db.open_tree(name.as_ref())?))
+    }
+
+    pub fn name(&self) -> String {
+        self.tree.name()
+    }
+
+    /// The untyped tree underneath
+    pub fn inner(&self) -> &dyn StoreTree {
+        self.tree.as_ref()
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let key = key.to_key();
+        match self.tree.get(&key)? {
+            Some(value) => Ok(Some(decode_value::<K, V, C>(self.inner(), &key, &value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value that was there before
+    pub fn insert(&self, key: &K, value: &V) -> Result<Option<V>> {
+        let key = key.to_key();
+        match self.tree.insert(&key, C::encode(value)?)? {
+            Some(old) => Ok(Some(decode_value::<K, V, C>(self.inner(), &key, &old)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Insert `value` only if there's nothing at `key` yet. Returns whether it was inserted.
+    pub fn insert_new(&self, key: &K, value: &V) -> Result<bool> {
+        self.tree
+            .compare_and_swap(&key.to_key(), None, Some(C::encode(value)?))
+    }
+
+    /// Returns the value that was there, if there was one
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        let key = key.to_key();
+        match self.tree.remove(&key)? {
+            Some(old) => Ok(Some(decode_value::<K, V, C>(self.inner(), &key, &old)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        self.tree.contains_key(&key.to_key())
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.tree.clear()
+    }
+
+    /// Everything in key order
+    pub fn iter(&self) -> TypedIter<K, V, C> {
+        self.scan_prefix([])
+    }
+
+    /// Everything whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> TypedIter<K, V, C> {
+        TypedIter {
+            iter: self.tree.scan_prefix(prefix.as_ref()),
+            tree: self.tree.clone(),
+            types: PhantomData,
+        }
+    }
+
+    /// Just the keys, in order. Values aren't decoded, so a bad one doesn't stop this.
+    pub fn keys(&self) -> impl Iterator<Item = Result<K>> {
+        self.keys_with_prefix([])
+    }
+
+    /// Just the keys starting with `prefix`, in order
+    pub fn keys_with_prefix(&self, prefix: impl AsRef<[u8]>) -> impl Iterator<Item = Result<K>> {
+        let tree = self.tree.clone();
+        self.tree.scan_prefix(prefix.as_ref()).map(move |item| {
+            let (key, _) = item?;
+            K::from_key(&key).map_err(|err| corrupt::<K>(tree.as_ref(), &key, err))
+        })
+    }
+
+    /// Changes to everything under `prefix`, as they happen
+    pub fn watch_prefix(&self, prefix: impl AsRef<[u8]>) -> TypedWatch<K, V, C> {
+        TypedWatch {
+            watch: self.tree.watch_prefix(prefix.as_ref()),
+            tree: self.tree.clone(),
+            types: PhantomData,
+        }
+    }
+
+    /// Changes to the whole tree
+    pub fn watch(&self) -> TypedWatch<K, V, C> {
+        self.watch_prefix([])
+    }
+
+    pub fn apply_batch(&self, batch: &TypedBatch<K, V, C>) -> Result<()> {
+        self.tree.apply_batch(&batch.batch)
+    }
+}
+
+impl<V, C: Codec<V>> TypedTree<ProjectPath, V, C> {
+    /// Everything inside `folder`, but not `folder` itself
+    pub fn under(&self, folder: &ProjectPath) -> TypedIter<ProjectPath, V, C> {
+        self.scan_prefix(folder_prefix(folder))
+    }
+
+    /// The paths inside `folder`
+    pub fn keys_under(&self, folder: &ProjectPath) -> impl Iterator<Item = Result<ProjectPath>> {
+        self.keys_with_prefix(folder_prefix(folder))
+    }
+
+    /// Everything, or only what's inside `folder` if there is one
+    pub fn maybe_under(&self, folder: Option<&ProjectPath>) -> TypedIter<ProjectPath, V, C> {
+        match folder {
+            Some(folder) => self.under(folder),
+            None => self.iter(),
+        }
+    }
+}
+
+/// A tree's entries in key order, decoded as they're reached
+pub struct TypedIter<K, V, C = RecordCodec> {
+    iter: StoreIter,
+    tree: Arc<dyn StoreTree>,
+    types: Types<K, V, C>,
+}
+
+impl<K: Key, V, C: Codec<V>> Iterator for TypedIter<K, V, C> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(match self.iter.next()? {
+            Ok((key, value)) => decode_entry::<K, V, C>(self.tree.as_ref(), &key, &value),
+            Err(err) => Err(err),
+        })
+    }
+}
+
+impl<K: Key, V, C: Codec<V>> DoubleEndedIterator for TypedIter<K, V, C> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        Some(match self.iter.next_back()? {
+            Ok((key, value)) => decode_entry::<K, V, C>(self.tree.as_ref(), &key, &value),
+            Err(err) => Err(err),
+        })
+    }
+}
+
+/// Changes to a tree. Iterating blocks until the next one.
+pub struct TypedWatch<K, V, C = RecordCodec> {
+    watch: Box<dyn StoreWatch>,
+    tree: Arc<dyn StoreTree>,
+    types: Types<K, V, C>,
+}
+
+impl<K: Key, V, C: Codec<V>> TypedWatch<K, V, C> {
+    /// The next change, or `None` if there wasn't one in time
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Result<TreeEvent<K, V>>> {
+        let event = self.watch.next_event_timeout(timeout)?;
+        Some(self.decode_event(event))
+    }
+
+    fn decode_event(&self, event: StoreEvent) -> Result<TreeEvent<K, V>> {
+        let tree = self.tree.as_ref();
+        match event {
+            TreeEvent::Insert { key, value } => {
+                let (key, value) = decode_entry::<K, V, C>(tree, &key, &value)?;
+                Ok(TreeEvent::Insert { key, value })
+            }
+            TreeEvent::Remove { key } => Ok(TreeEvent::Remove {
+                key: K::from_key(&key).map_err(|err| corrupt::<K>(tree, &key, err))?,
+            }),
+        }
+    }
+}
+
+impl<K: Key, V, C: Codec<V>> Iterator for TypedWatch<K, V, C> {
+    type Item = Result<TreeEvent<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = self.watch.next_event()?;
+        Some(self.decode_event(event))
+    }
+}
+
+/// Writes to apply to a `TypedTree` all at once
+pub struct TypedBatch<K, V, C = RecordCodec> {
+    batch: WriteBatch,
+    types: Types<K, V, C>,
+}
+
+impl<K, V, C> Default for TypedBatch<K, V, C> {
+    fn default() -> Self {
+        Self {
+            batch: WriteBatch::default(),
+            types: PhantomData,
+        }
+    }
+}
+
+impl<K: Key, V, C: Codec<V>> TypedBatch<K, V, C> {
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        self.batch.insert(key.to_key(), C::encode(value)?);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        self.batch.remove(key.to_key());
+    }
+
+    /// The untyped batch, for `MetadataStore::apply_batches`
+    pub fn inner(&self) -> &WriteBatch {
+        &self.batch
+    }
+}
+
+fn decode_entry<K: Key, V, C: Codec<V>>(
+    tree: &dyn StoreTree,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(K, V)> {
+    let decoded_key = K::from_key(key).map_err(|err| corrupt::<K>(tree, key, err))?;
+    let value = C::decode(value).map_err(|err| corrupt::<K>(tree, key, err))?;
+    Ok((decoded_key, value))
+}
+
+fn decode_value<K: Key, V, C: Codec<V>>(tree: &dyn StoreTree, key: &[u8], value: &[u8]) -> Result<V> {
+    C::decode(value).map_err(|err| corrupt::<K>(tree, key, err))
+}
+
+fn corrupt<K: Key>(tree: &dyn StoreTree, key: &[u8], error: Error) -> Error {
+    Error::CorruptRecord {
+        tree: tree.name(),
+        // The key as its type shows it if it decodes, its bytes if it doesn't
+        key: match K::from_key(key) {
+            Ok(key) => key.to_string(),
+            Err(_) => format!("{:?}", key),
+        },
+        error: Box::new(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::TypedTree;
+    use crate::{
+        db::{
+            memory_store::MemoryStore, project_path::ProjectPath, store::TreeEvent,
+            test_support::path, types::LocalFileMetadata,
+        },
+        error::Error,
+    };
+
+    fn metadata(file_path: &str, size: u64) -> LocalFileMetadata {
+        LocalFileMetadata {
+            path: path(file_path),
+            size,
+            modified: Utc.timestamp(100, 0),
+            update_time: Utc.timestamp(100, 0),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_and_iterate() {
+        let db = MemoryStore::default();
+        let tree: TypedTree<ProjectPath, LocalFileMetadata> = TypedTree::open(&db, "files").unwrap();
+
+        for file in ["a/b", "a.txt", "a/c/d"] {
+            tree.insert(&path(file), &metadata(file, 1)).unwrap();
+        }
+
+        assert_eq!(Some(metadata("a/b", 1)), tree.get(&path("a/b")).unwrap());
+        assert_eq!(
+            Some(metadata("a/b", 1)),
+            tree.insert(&path("a/b"), &metadata("a/b", 2)).unwrap()
+        );
+
+        let paths: Vec<ProjectPath> = tree.iter().map(|item| item.unwrap().0).collect();
+        assert_eq!(vec![path("a/b"), path("a/c/d"), path("a.txt")], paths);
+
+        let under: Vec<ProjectPath> = tree.under(&path("a")).map(|item| item.unwrap().0).collect();
+        assert_eq!(vec![path("a/b"), path("a/c/d")], under);
+
+        assert_eq!(Some(metadata("a.txt", 1)), tree.remove(&path("a.txt")).unwrap());
+        assert!(!tree.contains_key(&path("a.txt")).unwrap());
+
+        assert!(!tree.insert_new(&path("a/b"), &metadata("a/b", 3)).unwrap());
+        assert!(tree.insert_new(&path("a.txt"), &metadata("a.txt", 3)).unwrap());
+        assert_eq!(Some(metadata("a/b", 2)), tree.get(&path("a/b")).unwrap());
+        assert_eq!(Some(metadata("a.txt", 3)), tree.get(&path("a.txt")).unwrap());
+    }
+
+    #[test]
+    fn test_bad_records_say_where_they_are() {
+        let db = MemoryStore::default();
+        let tree: TypedTree<ProjectPath, LocalFileMetadata> = TypedTree::open(&db, "files").unwrap();
+
+        tree.inner().insert(b"part.sldprt", b"junk".to_vec()).unwrap();
+
+        match tree.get(&path("part.sldprt")) {
+            Err(Error::CorruptRecord { tree, key, .. }) => {
+                assert_eq!("files", tree);
+                assert_eq!("part.sldprt", key);
+            }
+            other => panic!("Expected a corrupt record, got {:?}", other),
+        }
+        assert!(tree.iter().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_watch() {
+        let db = MemoryStore::default();
+        let tree: TypedTree<ProjectPath, LocalFileMetadata> = TypedTree::open(&db, "files").unwrap();
+        let mut events = tree.watch();
+
+        tree.insert(&path("a"), &metadata("a", 1)).unwrap();
+        tree.remove(&path("a")).unwrap();
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            TreeEvent::Insert {
+                key: path("a"),
+                value: metadata("a", 1)
+            },
+            events.next_timeout(timeout).unwrap().unwrap()
+        );
+        assert_eq!(
+            TreeEvent::Remove { key: path("a") },
+            events.next_timeout(timeout).unwrap().unwrap()
+        );
+    }
+}
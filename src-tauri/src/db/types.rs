@@ -1,12 +1,17 @@
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf};
 
 use chrono::{DateTime, Utc};
 use derivative::Derivative;
 use serde::{Serialize, Deserialize};
 
+use crate::db::{content_hash::ContentHash, project_path::ProjectPath};
+
 pub struct TreeNames;
 
 impl TreeNames {
+  // Registry of local projects, keyed by `ProjectId`
+  pub const PROJECTS: &'static str = "projects";
+
   // Pre-hash local metadata (versions and stuff)
   pub const BASIC_LOCAL_METADATA: &'static str = "basicMetadataLocal::>>";
   // Post-hash local metadata (the git local tree)
@@ -15,12 +20,62 @@ impl TreeNames {
   pub const HASH_HEAD_METDATA: &'static str = "metaHashHead::>>";
   // The latest remote metadata, as known locally (origin/* in git terms)
   pub const HASH_REMOTE_METDATA: &'static str = "metaHashRemote::>>";
+  // Last known data for files deleted locally, so they aren't mistaken for remote creates
+  pub const LOCAL_TOMBSTONES: &'static str = "tombstonesLocal::>>";
+  // How each local file splits into chunks, for sending only what the remote lacks
+  pub const CHUNK_MANIFESTS: &'static str = "chunkManifestsLocal::>>";
+  // Every version the remote has been seen with, for file history
+  pub const REMOTE_HISTORY: &'static str = "historyRemote::>>";
+  // The last `ScanReport` of each project, keyed by `ProjectId`
+  pub const SCAN_REPORTS: &'static str = "scanReports";
+  // Every commit of a project, keyed by `CommitId`. Not in `PROJECT_TREES`, it isn't path keyed.
+  pub const COMMITS: &'static str = "commits::>>";
+  // A project's tags, keyed by name. Like `COMMITS`, not path keyed.
+  pub const TAGS: &'static str = "tags::>>";
+  // The files of each of a project's tags, keyed by `TagFileKey`
+  pub const TAG_FILES: &'static str = "tagFiles::>>";
+  // The `CommitId` HEAD is at for each project, keyed by `ProjectId`
+  pub const HEADS: &'static str = "heads";
+  // What's in the object store and how many commit changes use it, keyed by `ContentHash`
+  pub const OBJECTS: &'static str = "objects";
+  // Files that restores moved out of the way, keyed by ID
+  pub const TRASH: &'static str = "trash";
+
+  // Every tree that is namespaced per project and keyed by `encode_path_key`
+  pub const PROJECT_TREES: [&'static str; 7] = [
+    Self::BASIC_LOCAL_METADATA,
+    Self::HASH_LOCAL_METDATA,
+    Self::HASH_HEAD_METDATA,
+    Self::HASH_REMOTE_METDATA,
+    Self::LOCAL_TOMBSTONES,
+    Self::CHUNK_MANIFESTS,
+    Self::REMOTE_HISTORY,
+  ];
+}
+
+pub type ProjectId = u64;
+
+/// A local project folder that's being tracked
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Project {
+    pub id: ProjectId,
+    pub root: PathBuf,
+    pub cloud_project_id: Option<u64>,
+    pub created: DateTime<Utc>,
+    pub last_scanned: Option<DateTime<Utc>>,
+}
+
+impl Project {
+    /// Name of this project's copy of one of the `TreeNames::PROJECT_TREES`
+    pub fn tree_name(&self, tree: &str) -> String {
+        tree.to_owned() + &self.id.to_string()
+    }
 }
 
 #[derive(Derivative, Debug, Serialize, Deserialize, Clone)]
 #[derivative(PartialEq, Eq, PartialOrd, Ord)]
 pub struct LocalFileMetadata {
-    pub path: PathBuf,
+    pub path: ProjectPath,
     pub size: u64,
     pub modified: DateTime<Utc>,
     #[derivative(PartialEq = "ignore")]
@@ -31,34 +86,79 @@ pub struct LocalFileMetadata {
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct LocalFileData {
     pub name: String,
-    pub hash: u128,
+    // Fast hash for telling whether the contents changed
+    pub hash: ContentHash,
+    // Only there when a cryptographic hash is turned on in the preferences
+    #[serde(default)]
+    pub strong_hash: Option<ContentHash>,
     pub metadata: LocalFileMetadata,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+impl LocalFileData {
+    /// Whether both have the same contents, `None` if there's no telling. Hashes from
+    /// different algorithms can't be compared, which happens to records kept from before
+    /// v4 until they're hashed again.
+    pub fn same_contents(&self, other: &LocalFileData) -> Option<bool> {
+        if self.hash.algorithm() == other.hash.algorithm() {
+            Some(self.hash == other.hash)
+        } else {
+            None
+        }
+    }
+}
+
+/// Why two versions of a file couldn't be ordered
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConflictReason {
+    // Same key, different hash, same modified time
+    SameModifiedTime,
+    // Left changed the file since HEAD, right deleted it
+    LeftModifiedRightDeleted,
+    // Left deleted the file, right changed it since
+    LeftDeletedRightModified,
+    // The hashes are from different algorithms, so there's no telling if it changed
+    HashesNotComparable,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DiffTypes {
     RightCreate,
     LeftCreate,
     RightNewer,
     LeftNewer,
+    // Deleted on the left, still exists on the right
+    LeftDelete,
+    // Deleted on the right, still exists on the left
+    RightDelete,
+    // Moved without changing content, the side is the same as the diff metadata
+    Renamed {
+        from: ProjectPath,
+        to: ProjectPath,
+    },
+    // Boxed so the whole enum isn't the size of two file records
+    Conflict {
+        left: Box<LocalFileData>,
+        right: Box<LocalFileData>,
+        reason: ConflictReason,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum FileDiffData {
     Left(LocalFileData),
     Right(LocalFileData),
     Both(LocalFileData, LocalFileData),
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct FileDiff {
-    path: PathBuf,
-    diff_metadata: FileDiffData,
-    diff_type: DiffTypes,
+    pub path: ProjectPath,
+    pub diff_metadata: FileDiffData,
+    pub diff_type: DiffTypes,
 }
 
 impl FileDiff {
-    pub fn right_create(path: PathBuf, right: LocalFileData) -> Self {
+    pub fn right_create(path: ProjectPath, right: LocalFileData) -> Self {
         Self {
             path,
             diff_metadata: FileDiffData::Right(right),
@@ -66,7 +166,7 @@ impl FileDiff {
         }
     }
 
-    pub fn right_newer(path: PathBuf, left: LocalFileData, right: LocalFileData) -> Self {
+    pub fn right_newer(path: ProjectPath, left: LocalFileData, right: LocalFileData) -> Self {
         Self {
             path,
             diff_metadata: FileDiffData::Both(left, right),
@@ -74,7 +174,7 @@ impl FileDiff {
         }
     }
 
-    pub fn left_create(path: PathBuf, left: LocalFileData) -> Self {
+    pub fn left_create(path: ProjectPath, left: LocalFileData) -> Self {
         Self {
             path,
             diff_metadata: FileDiffData::Left(left),
@@ -82,14 +182,413 @@ impl FileDiff {
         }
     }
 
-    pub fn left_newer(path: PathBuf, left: LocalFileData, right: LocalFileData) -> Self {
+    pub fn left_newer(path: ProjectPath, left: LocalFileData, right: LocalFileData) -> Self {
         Self {
             path,
             diff_metadata: FileDiffData::Both(left, right),
             diff_type: DiffTypes::LeftNewer,
         }
     }
+
+    pub fn left_delete(path: ProjectPath, right: LocalFileData) -> Self {
+        Self {
+            path,
+            diff_metadata: FileDiffData::Right(right),
+            diff_type: DiffTypes::LeftDelete,
+        }
+    }
+
+    pub fn right_delete(path: ProjectPath, left: LocalFileData) -> Self {
+        Self {
+            path,
+            diff_metadata: FileDiffData::Left(left),
+            diff_type: DiffTypes::RightDelete,
+        }
+    }
+
+    pub fn renamed(from: ProjectPath, to: ProjectPath, diff_metadata: FileDiffData) -> Self {
+        Self {
+            path: to.clone(),
+            diff_metadata,
+            diff_type: DiffTypes::Renamed { from, to },
+        }
+    }
+
+    pub fn conflict(
+        path: ProjectPath,
+        left: LocalFileData,
+        right: LocalFileData,
+        reason: ConflictReason,
+    ) -> Self {
+        Self {
+            path,
+            diff_metadata: FileDiffData::Both(left.clone(), right.clone()),
+            diff_type: DiffTypes::Conflict {
+                left: Box::new(left),
+                right: Box::new(right),
+                reason,
+            },
+        }
+    }
+}
+
+/// A chunk of diffs sent to the frontend while a comparison is still running
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FileDiffBatch {
+    pub diffs: Vec<FileDiff>,
+    // True on the last batch of a comparison
+    pub done: bool,
+}
+
+impl FileDiffBatch {
+    pub fn new(diffs: Vec<FileDiff>, done: bool) -> Self {
+        Self { diffs, done }
+    }
+}
+
+/// What a locally deleted file looked like right before it was pruned
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    pub data: LocalFileData,
+    pub deleted_time: DateTime<Utc>,
+}
+
+/// How a path changed relative to HEAD, the common ancestor of local and remote
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThreeWayDiffTypes {
+    LocalModified,
+    RemoteModified,
+    // Both sides changed the file differently, this is a conflict
+    BothModified,
+    LocalDeleted,
+    RemoteDeleted,
+    LocalAdded,
+    RemoteAdded,
+    Unchanged,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreeWayDiff {
+    pub path: ProjectPath,
+    pub diff_type: ThreeWayDiffTypes,
+    pub local: Option<LocalFileData>,
+    pub head: Option<LocalFileData>,
+    pub remote: Option<LocalFileData>,
+}
+
+impl ThreeWayDiff {
+    pub fn new(
+        path: ProjectPath,
+        local: Option<LocalFileData>,
+        head: Option<LocalFileData>,
+        remote: Option<LocalFileData>,
+        diff_type: ThreeWayDiffTypes,
+    ) -> Self {
+        Self {
+            path,
+            diff_type,
+            local,
+            head,
+            remote,
+        }
+    }
+}
+
+pub type TreeItem = (ProjectPath, LocalFileData);
+
+
+/// Where the rule that ignored a path came from
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum IgnoreSource {
+    // The built-in list of CAD temp files and OS junk
+    Default,
+    // A `.splatcadignore` file, at this path in the project
+    File(ProjectPath),
+}
+
+/// Why a path is ignored by the scanner
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct IgnoreReason {
+    // The path the rule matched, either the path itself or a folder containing it
+    pub matched: ProjectPath,
+    pub pattern: String,
+    pub source: IgnoreSource,
+}
+
+/// Sent to the frontend whenever the watcher picks up local changes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LocalChanges {
+    pub project_id: ProjectId,
+    // Every path whose local state changed, the diffs replace any older ones for these
+    pub changed: Vec<ProjectPath>,
+    pub diffs: Vec<FileDiff>,
+}
+
+/// One content-defined chunk of a file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub hash: ContentHash,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// How a file splits into chunks
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ChunkManifest {
+    // xxh3-128 of the whole file when it was chunked, to tell if the manifest is stale
+    pub file_hash: ContentHash,
+    pub size: u64,
+    pub chunks: Vec<Chunk>,
+}
+
+/// What `verify_project` should do
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct VerifyOptions {
+    // Only re-hash this many randomly picked files, instead of all of them
+    pub sample: Option<usize>,
+    // Fix what can be fixed from the files on disk
+    pub repair: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum VerifyIssueKind {
+    // A key or record in `tree` that can't be decoded
+    Corrupt { tree: String, error: String },
+    // A record in `tree` whose own path isn't the one it's stored under
+    KeyMismatch { tree: String },
+    // In the metadata tree, but never hashed
+    MissingHash,
+    // Hashed, but not in the metadata tree
+    OrphanHash,
+    // The hash record's metadata isn't what the metadata tree has
+    RecordsDisagree,
+    // Tracked, but gone from disk
+    MissingFile,
+    Unreadable { error: String },
+    // Size or modified time changed since the last scan, a rescan will catch it
+    Stale,
+    // Hashed with older hash settings
+    OutdatedHash,
+    // Same size and modified time as when it was hashed, but different contents
+    HashMismatch { stored: ContentHash, actual: ContentHash },
+    // Same size and modified time as HEAD, but a different hash
+    HeadHashMismatch { head: ContentHash, local: ContentHash },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct VerifyIssue {
+    // Unknown when the key itself is corrupt
+    pub path: Option<ProjectPath>,
+    pub kind: VerifyIssueKind,
+    pub repaired: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VerifyReport {
+    pub project_id: ProjectId,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub files_checked: usize,
+    pub bytes_checked: u64,
+    // Whether only a sample of the files was re-hashed
+    pub sampled: bool,
+    pub issues: Vec<VerifyIssue>,
+}
+
+pub type CommitId = u64;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum CommitChangeKind {
+    Added,
+    Modified,
+    Deleted,
+    // Moved here from `from` without changing content
+    Renamed { from: ProjectPath },
+}
+
+/// How one path changed in a commit
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CommitChange {
+    pub path: ProjectPath,
+    pub kind: CommitChangeKind,
+    // The file as it was committed, `None` when it was deleted
+    pub data: Option<LocalFileData>,
+}
+
+/// A checkpoint of the local files. Never changed once it's written.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub id: CommitId,
+    pub project_id: ProjectId,
+    // `None` for the project's first commit
+    pub parent: Option<CommitId>,
+    pub author: String,
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+    // Only what changed since the parent, in path order
+    pub changes: Vec<CommitChange>,
+}
+
+pub type TagId = u64;
+
+/// A name for the state of a project at one point, like a revision sent out to be made.
+/// Never changed once it's made.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Tag {
+    // What its files are kept under in `TAG_FILES`
+    pub id: TagId,
+    pub name: String,
+    pub project_id: ProjectId,
+    // The HEAD commit when it was made, if there was one
+    pub commit: Option<CommitId>,
+    pub description: String,
+    pub creator: String,
+    pub created: DateTime<Utc>,
+    pub file_count: usize,
+}
+
+/// One of the HEAD files a tag was made from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagFileKey {
+    pub tag: TagId,
+    pub path: ProjectPath,
+}
+
+impl fmt::Display for TagFileKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.tag, self.path)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: ProjectPath,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    pub hash: ContentHash,
+    pub strong_hash: Option<ContentHash>,
+}
+
+/// Everything in a tag, for handing to someone outside the app
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TagManifest {
+    pub tag: Tag,
+    pub files: Vec<ManifestEntry>,
+}
+
+/// A file a restore put aside instead of overwriting or deleting
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    pub id: u64,
+    pub project_id: ProjectId,
+    // Where it was in the project
+    pub path: ProjectPath,
+    // Where it is now, under the app dir
+    pub location: PathBuf,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    pub trashed: DateTime<Utc>,
+}
+
+/// One version of a file the remote state had
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RemoteVersion {
+    pub data: LocalFileData,
+    // When the frontend told us about it
+    pub seen: DateTime<Utc>,
+    // The remote moved it here from this path without changing it
+    pub renamed_from: Option<ProjectPath>,
+}
+
+/// Every version of one path the remote has been seen with, oldest first
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct RemoteHistory {
+    pub versions: Vec<RemoteVersion>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum VersionSource {
+    Commit { id: CommitId },
+    Remote,
+}
+
+/// One distinct version of a file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FileVersion {
+    // Where the file was in this version, it may have moved since
+    pub path: ProjectPath,
+    pub hash: ContentHash,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    // Remote versions don't say who made them
+    pub author: Option<String>,
+    pub message: Option<String>,
+    // When it was committed, or first seen on the remote
+    pub timestamp: DateTime<Utc>,
+    pub source: VersionSource,
+    // Whether its bytes can be had without the remote
+    pub available_locally: bool,
+}
+
+/// Why the scanner couldn't take a path into account
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    PermissionDenied,
+    // Held open by another program, like a part that's open in a CAD program
+    Locked,
+    // Deleted between being listed and being read
+    Vanished,
+    // Symlinks aren't followed, this one doesn't even point anywhere
+    BrokenSymlink,
+    Symlink,
+    // Can't be a `ProjectPath`, e.g. a name that isn't UTF-8
+    InvalidPath { error: String },
+    Io { error: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SkippedPath {
+    // Relative to the project root when it can be
+    pub path: PathBuf,
+    pub reason: SkipReason,
 }
 
-pub type TreeItem = (PathBuf, LocalFileData);
+/// What a full scan of a project did, and what it had to leave out
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScanReport {
+    pub project_id: ProjectId,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub files_seen: usize,
+    pub bytes_seen: u64,
+    pub files_hashed: usize,
+    pub bytes_hashed: u64,
+    // Files that were tracked and aren't on disk anymore
+    pub files_removed: usize,
+    pub skipped: Vec<SkippedPath>,
+    // Stopped early by `cancel_scan`. Whatever was hashed by then is kept.
+    #[serde(default)]
+    pub cancelled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ScanPhase {
+    // Listing files and finding the ones that changed
+    Walking,
+    Hashing,
+    // Recording the files that are gone
+    Pruning,
+}
 
+/// How far along a full scan is. Totals aren't known while walking.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ScanProgress {
+    pub project_id: ProjectId,
+    pub phase: ScanPhase,
+    pub files_done: usize,
+    pub files_total: Option<usize>,
+    pub bytes_done: u64,
+    pub bytes_total: Option<u64>,
+    pub current_file: Option<ProjectPath>,
+}
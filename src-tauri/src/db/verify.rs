@@ -0,0 +1,539 @@
+//! Checking that the local trees still agree with each other and with the disk.
+//!
+//! The structural checks only read sled, so they always cover the whole project.
+//! Re-hashing is the expensive part and can be limited to a random sample.
+
+use std::{collections::BTreeMap, io::ErrorKind};
+
+use chrono::{DateTime, Utc};
+use futures::prelude::*;
+use rand::seq::IteratorRandom;
+
+use crate::{
+    db::{
+        commits::{files_at, head_commit},
+        hashing::{hash_file, hash_settings, HashSettings},
+        keys::{decode_path_key, encode_path_key},
+        project_path::ProjectPath,
+        record::{decode, encode, Record},
+        refresh_state::hash_and_finalize,
+        staging::{LocalTrees, StagedChanges},
+        store::{MetadataStore, StoreTree},
+        types::{
+            LocalFileData, LocalFileMetadata, Project, TreeNames, VerifyIssue,
+            VerifyIssueKind, VerifyOptions, VerifyReport,
+        },
+    },
+    error::{Error, Result},
+};
+
+// Files re-hashed at once, the memory budget still applies on top
+const CONCURRENT_CHECKS: usize = 16;
+
+/// Check a project's local state, and repair it if asked to
+pub async fn verify_project(
+    project: &Project,
+    options: &VerifyOptions,
+    db: &dyn MetadataStore,
+) -> Result<VerifyReport> {
+    let started = Utc::now();
+    let settings = hash_settings(db)?;
+    let metadata_tree = db.open_tree(&project.tree_name(TreeNames::BASIC_LOCAL_METADATA))?;
+    let meta_hash_tree = db.open_tree(&project.tree_name(TreeNames::HASH_LOCAL_METDATA))?;
+    let head_tree = db.open_tree(&project.tree_name(TreeNames::HASH_HEAD_METDATA))?;
+
+    let mut issues = Vec::new();
+
+    let metadatas = read_tree(
+        metadata_tree.as_ref(),
+        TreeNames::BASIC_LOCAL_METADATA,
+        |metadata: &LocalFileMetadata| &metadata.path,
+        &mut issues,
+    )?;
+    let hashes = read_tree(
+        meta_hash_tree.as_ref(),
+        TreeNames::HASH_LOCAL_METDATA,
+        |data: &LocalFileData| &data.metadata.path,
+        &mut issues,
+    )?;
+    let heads = read_tree(
+        head_tree.as_ref(),
+        TreeNames::HASH_HEAD_METDATA,
+        |data: &LocalFileData| &data.metadata.path,
+        &mut issues,
+    )?;
+
+    // The two local trees should have the same paths, with the same metadata
+    for (path, metadata) in &metadatas {
+        match hashes.get(path) {
+            None => issues.push(issue(path, VerifyIssueKind::MissingHash)),
+            Some(data) if &data.metadata != metadata => {
+                issues.push(issue(path, VerifyIssueKind::RecordsDisagree))
+            }
+            _ => {}
+        }
+    }
+    for path in hashes.keys() {
+        if !metadatas.contains_key(path) {
+            issues.push(issue(path, VerifyIssueKind::OrphanHash));
+        }
+    }
+
+    // A file that looks exactly like it did at HEAD should hash like it too
+    for (path, head) in &heads {
+        if let Some(local) = hashes.get(path) {
+            if local.metadata.size == head.metadata.size
+                && local.metadata.modified == head.metadata.modified
+                && local.hash.algorithm() == head.hash.algorithm()
+                && local.hash != head.hash
+            {
+                issues.push(issue(
+                    path,
+                    VerifyIssueKind::HeadHashMismatch {
+                        head: head.hash.clone(),
+                        local: local.hash.clone(),
+                    },
+                ));
+            }
+        }
+    }
+
+    // Then the disk
+    let sampled = matches!(options.sample, Some(sample) if sample < metadatas.len());
+    let to_check: Vec<&ProjectPath> = match options.sample {
+        Some(sample) if sampled => metadatas
+            .keys()
+            .choose_multiple(&mut rand::thread_rng(), sample),
+        _ => metadatas.keys().collect(),
+    };
+
+    let checks = stream::iter(to_check)
+        .map(|path| check_file(project, path, &metadatas[path], hashes.get(path), settings))
+        .buffer_unordered(CONCURRENT_CHECKS)
+        .collect::<Vec<_>>()
+        .await;
+
+    let files_checked = checks.len();
+    let mut bytes_checked = 0;
+    for (bytes, found) in checks {
+        bytes_checked += bytes;
+        issues.extend(found);
+    }
+
+    if options.repair {
+        for issue in issues.iter_mut() {
+            issue.repaired = repair(project, issue, settings, db).await?;
+        }
+        db.flush()?;
+    }
+
+    Ok(VerifyReport {
+        project_id: project.id,
+        started,
+        finished: Utc::now(),
+        files_checked,
+        bytes_checked,
+        sampled,
+        issues,
+    })
+}
+
+fn issue(path: &ProjectPath, kind: VerifyIssueKind) -> VerifyIssue {
+    VerifyIssue {
+        path: Some(path.clone()),
+        kind,
+        repaired: false,
+    }
+}
+
+/// Decode a whole tree, noting anything that doesn't decode or is stored under the wrong key
+fn read_tree<T: Record>(
+    tree: &dyn StoreTree,
+    tree_name: &str,
+    path_of: impl Fn(&T) -> &ProjectPath,
+    issues: &mut Vec<VerifyIssue>,
+) -> Result<BTreeMap<ProjectPath, T>> {
+    let mut records = BTreeMap::new();
+
+    for item in tree.scan_prefix(&[]) {
+        let (key, value) = item?;
+
+        let corrupt = |error: String| VerifyIssueKind::Corrupt {
+            tree: tree_name.to_owned(),
+            error,
+        };
+        let path = match decode_path_key(&key) {
+            Ok(path) => path,
+            Err(err) => {
+                issues.push(VerifyIssue {
+                    path: None,
+                    kind: corrupt(err.to_string()),
+                    repaired: false,
+                });
+                continue;
+            }
+        };
+        let record: T = match decode(&value) {
+            Ok(record) => record,
+            Err(err) => {
+                issues.push(issue(&path, corrupt(err.to_string())));
+                continue;
+            }
+        };
+
+        if path_of(&record) != &path {
+            issues.push(issue(
+                &path,
+                VerifyIssueKind::KeyMismatch {
+                    tree: tree_name.to_owned(),
+                },
+            ));
+            continue;
+        }
+
+        records.insert(path, record);
+    }
+
+    Ok(records)
+}
+
+/// Compare one file on disk with its records. Returns the bytes hashed and what was wrong.
+async fn check_file(
+    project: &Project,
+    path: &ProjectPath,
+    metadata: &LocalFileMetadata,
+    data: Option<&LocalFileData>,
+    settings: HashSettings,
+) -> (u64, Vec<VerifyIssue>) {
+    let absolute = path.to_absolute(&project.root);
+
+    let on_disk = match tokio::fs::metadata(&absolute).await {
+        Ok(on_disk) => on_disk,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return (0, vec![issue(path, VerifyIssueKind::MissingFile)])
+        }
+        Err(err) => {
+            let kind = VerifyIssueKind::Unreadable {
+                error: err.to_string(),
+            };
+            return (0, vec![issue(path, kind)]);
+        }
+    };
+    let modified = on_disk.modified().ok().map(DateTime::<Utc>::from);
+    if on_disk.len() != metadata.size || modified != Some(metadata.modified) {
+        return (0, vec![issue(path, VerifyIssueKind::Stale)]);
+    }
+
+    // Already reported by the cross-checks, and there's nothing to compare with
+    let data = match data {
+        Some(data) => data,
+        None => return (0, Vec::new()),
+    };
+
+    let actual = match hash_file(absolute, settings).await {
+        Ok(actual) => actual,
+        Err(err) => {
+            let kind = VerifyIssueKind::Unreadable {
+                error: err.to_string(),
+            };
+            return (0, vec![issue(path, kind)]);
+        }
+    };
+
+    let kind = if data.hash.algorithm() != actual.hash.algorithm()
+        || data.strong_hash.as_ref().map(|hash| hash.algorithm()) != settings.strong
+    {
+        Some(VerifyIssueKind::OutdatedHash)
+    } else if data.hash != actual.hash || data.strong_hash != actual.strong_hash {
+        Some(VerifyIssueKind::HashMismatch {
+            stored: data.hash.clone(),
+            actual: actual.hash,
+        })
+    } else {
+        None
+    };
+
+    (on_disk.len(), kind.map(|kind| issue(path, kind)).into_iter().collect())
+}
+
+/// Fix one issue if it can be fixed. Returns whether it was.
+async fn repair(
+    project: &Project,
+    issue: &VerifyIssue,
+    settings: HashSettings,
+    db: &dyn MetadataStore,
+) -> Result<bool> {
+    let trees = LocalTrees::open(project, db)?;
+
+    let path = match &issue.path {
+        Some(path) => path,
+        // Can't even tell which record it is, the next scan will write a good one
+        None => return Ok(false),
+    };
+    let key = encode_path_key(path);
+
+    match &issue.kind {
+        // Scans never write HEAD, so it comes back from the commits instead
+        VerifyIssueKind::Corrupt { tree, .. } | VerifyIssueKind::KeyMismatch { tree }
+            if tree == TreeNames::HASH_HEAD_METDATA =>
+        {
+            rebuild_head(project, path, db)
+        }
+        // Drop the bad record, the next scan puts a good one back
+        VerifyIssueKind::Corrupt { tree, .. } | VerifyIssueKind::KeyMismatch { tree } => {
+            db.open_tree(&project.tree_name(tree))?.remove(&key)?;
+            Ok(true)
+        }
+        // The file's gone, so this is a deletion the scan never recorded
+        VerifyIssueKind::MissingFile => {
+            record_deletion(path, &trees)?;
+            Ok(true)
+        }
+        // Only the metadata record is missing, which says nothing about the file itself
+        VerifyIssueKind::OrphanHash => {
+            match tokio::fs::metadata(path.to_absolute(&project.root)).await {
+                Ok(_) => rehash(project, path, settings, &trees).await?,
+                Err(err) if err.kind() == ErrorKind::NotFound => record_deletion(path, &trees)?,
+                Err(err) => return Err(err.into()),
+            }
+            Ok(true)
+        }
+        // Anything else wrong with the records is fixed by hashing the file again
+        VerifyIssueKind::MissingHash
+        | VerifyIssueKind::RecordsDisagree
+        | VerifyIssueKind::Stale
+        | VerifyIssueKind::OutdatedHash
+        | VerifyIssueKind::HashMismatch { .. } => {
+            rehash(project, path, settings, &trees).await?;
+            Ok(true)
+        }
+        // HEAD is history, and unreadable files need a person to look at them
+        VerifyIssueKind::HeadHashMismatch { .. } | VerifyIssueKind::Unreadable { .. } => Ok(false),
+    }
+}
+
+/// Write `path`'s HEAD record again from the commits. Returns false if there aren't any
+/// to rebuild it from.
+fn rebuild_head(project: &Project, path: &ProjectPath, db: &dyn MetadataStore) -> Result<bool> {
+    let head = match head_commit(project, db)? {
+        Some(head) => head,
+        None => return Ok(false),
+    };
+    // Not a typed tree, since that would decode the corrupt record it replaces
+    let head_tree = db.open_tree(&project.tree_name(TreeNames::HASH_HEAD_METDATA))?;
+    let key = encode_path_key(path);
+
+    match files_at(project, head.id, db)?.remove(path) {
+        Some(data) => head_tree.insert(&key, encode(&data)?)?,
+        None => head_tree.remove(&key)?,
+    };
+    Ok(true)
+}
+
+/// Tombstone `path`, with what's known about it
+fn record_deletion(path: &ProjectPath, trees: &LocalTrees) -> Result<()> {
+    // An orphan's record is all there is, and a corrupt one is better left out
+    let data = match trees.hashes.get(path) {
+        Ok(data) => data,
+        Err(Error::CorruptRecord { .. }) => None,
+        Err(err) => return Err(err),
+    };
+
+    let mut staged = StagedChanges::default();
+    staged.deleted(path, data, Utc::now())?;
+    staged.apply(trees)
+}
+
+/// Hash the file at `path` again and write both of its records
+async fn rehash(
+    project: &Project,
+    path: &ProjectPath,
+    settings: HashSettings,
+    trees: &LocalTrees<'_>,
+) -> Result<()> {
+    let on_disk = tokio::fs::metadata(path.to_absolute(&project.root)).await?;
+    let metadata = LocalFileMetadata {
+        path: path.clone(),
+        size: on_disk.len(),
+        modified: DateTime::from(on_disk.modified()?),
+        update_time: Utc::now(),
+    };
+    let data = hash_and_finalize(project.root.clone(), metadata, settings).await?;
+
+    let mut staged = StagedChanges::default();
+    staged.hashed(&data)?;
+    staged.apply(trees)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{remove_file, write};
+
+    use super::verify_project;
+    use crate::db::{
+        commits::commit_project,
+        content_hash::ContentHash,
+        keys::encode_path_key,
+        memory_store::MemoryStore,
+        objects::ObjectStore,
+        project_path::ProjectPath,
+        refresh_state::refresh_paths,
+        staging::LocalTrees,
+        store::MetadataStore,
+        test_support::{path, project_at},
+        types::{TreeNames, VerifyIssue, VerifyIssueKind, VerifyOptions},
+    };
+
+    #[tokio::test]
+    async fn test_verify_and_repair() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path().join("good.sldprt"), "good").unwrap();
+        write(root.path().join("tampered.sldprt"), "original").unwrap();
+        write(root.path().join("deleted.sldprt"), "deleted").unwrap();
+
+        let db = sled::Config::default().temporary(true).open().unwrap();
+        let project = project_at(root.path());
+        refresh_paths(&project, &[ProjectPath::default()], &db)
+            .await
+            .unwrap();
+
+        // Pretend the stored hash went bad, and delete a file behind the scanner's back
+        let trees = LocalTrees::open(&project, &db).unwrap();
+        let mut data = trees.hashes.get(&path("tampered.sldprt")).unwrap().unwrap();
+        data.hash = ContentHash::xxh3_128(1);
+        trees.hashes.insert(&path("tampered.sldprt"), &data).unwrap();
+        remove_file(root.path().join("deleted.sldprt")).unwrap();
+
+        let report = verify_project(&project, &VerifyOptions::default(), &db)
+            .await
+            .unwrap();
+
+        assert_eq!(3, report.files_checked);
+        let mut found: Vec<(String, bool)> = report
+            .issues
+            .iter()
+            .map(|issue| {
+                let kind = match issue.kind {
+                    VerifyIssueKind::HashMismatch { .. } => "mismatch",
+                    VerifyIssueKind::MissingFile => "missing",
+                    _ => "other",
+                };
+                (format!("{} {}", issue.path.as_ref().unwrap(), kind), issue.repaired)
+            })
+            .collect();
+        found.sort();
+        assert_eq!(
+            vec![
+                ("deleted.sldprt missing".to_owned(), false),
+                ("tampered.sldprt mismatch".to_owned(), false),
+            ],
+            found
+        );
+
+        let repair = VerifyOptions {
+            sample: None,
+            repair: true,
+        };
+        let report = verify_project(&project, &repair, &db).await.unwrap();
+        assert!(report.issues.iter().all(|issue| issue.repaired));
+
+        let report = verify_project(&project, &VerifyOptions::default(), &db)
+            .await
+            .unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(2, report.files_checked);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_metadata_is_not_a_deletion() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path().join("bolt.sldprt"), "bolt").unwrap();
+
+        let db = MemoryStore::default();
+        let project = project_at(root.path());
+        refresh_paths(&project, &[ProjectPath::default()], &db)
+            .await
+            .unwrap();
+
+        // The metadata record goes bad, the file itself is fine
+        db.open_tree(&project.tree_name(TreeNames::BASIC_LOCAL_METADATA))
+            .unwrap()
+            .insert(&encode_path_key(&path("bolt.sldprt")), b"junk".to_vec())
+            .unwrap();
+
+        let repair = VerifyOptions {
+            sample: None,
+            repair: true,
+        };
+        let report = verify_project(&project, &repair, &db).await.unwrap();
+        let mut kinds: Vec<&str> = report
+            .issues
+            .iter()
+            .map(|issue| match issue.kind {
+                VerifyIssueKind::Corrupt { .. } => "corrupt",
+                VerifyIssueKind::OrphanHash => "orphan",
+                _ => "other",
+            })
+            .collect();
+        kinds.sort();
+        assert_eq!(vec!["corrupt", "orphan"], kinds);
+        assert!(report.issues.iter().all(|issue| issue.repaired));
+
+        let trees = LocalTrees::open(&project, &db).unwrap();
+        assert!(trees.tombstones.get(&path("bolt.sldprt")).unwrap().is_none());
+        assert!(trees.metadata.get(&path("bolt.sldprt")).unwrap().is_some());
+
+        let report = verify_project(&project, &VerifyOptions::default(), &db)
+            .await
+            .unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(1, report.files_checked);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_head_is_rebuilt_from_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("robot");
+        std::fs::create_dir(&root).unwrap();
+        write(root.join("bolt.sldprt"), "bolt").unwrap();
+
+        let db = MemoryStore::default();
+        let project = project_at(&root);
+        refresh_paths(&project, &[ProjectPath::default()], &db)
+            .await
+            .unwrap();
+        let head_tree = db
+            .open_tree(&project.tree_name(TreeNames::HASH_HEAD_METDATA))
+            .unwrap();
+        let key = encode_path_key(&path("bolt.sldprt"));
+        let repair = VerifyOptions {
+            sample: None,
+            repair: true,
+        };
+
+        // Nothing to rebuild it from yet
+        head_tree.insert(&key, b"junk".to_vec()).unwrap();
+        let report = verify_project(&project, &repair, &db).await.unwrap();
+        assert_eq!(1, report.issues.len());
+        assert!(!report.issues[0].repaired);
+        head_tree.remove(&key).unwrap();
+
+        let objects = ObjectStore::new(dir.path().join("objects"));
+        commit_project(&project, "ana".into(), "first".into(), &objects, &db)
+            .await
+            .unwrap();
+        let committed = head_tree.get(&key).unwrap();
+
+        head_tree.insert(&key, b"junk".to_vec()).unwrap();
+        let report = verify_project(&project, &repair, &db).await.unwrap();
+        assert!(matches!(
+            report.issues[..],
+            [VerifyIssue {
+                kind: VerifyIssueKind::Corrupt { .. },
+                repaired: true,
+                ..
+            }]
+        ));
+        assert_eq!(committed, head_tree.get(&key).unwrap());
+    }
+}
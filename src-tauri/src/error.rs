@@ -9,11 +9,20 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     Generic(GenericError),
     TauriError(tauri::api::Error),
+    TauriRuntimeError(tauri::Error),
     IoError(std::io::Error),
     WalkDirError(walkdir::Error),
     StringError(String),
     SledError(sled::Error),
     SerdeCborError(serde_cbor::Error),
+    IgnoreError(ignore::Error),
+    NotifyError(notify::Error),
+    // A record that's in the DB but can't be decoded
+    CorruptRecord {
+        tree: String,
+        key: String,
+        error: Box<Error>,
+    },
 }
 
 impl From<GenericError> for Error {
@@ -28,6 +37,12 @@ impl From<tauri::api::Error> for Error {
     }
 }
 
+impl From<tauri::Error> for Error {
+    fn from(error: tauri::Error) -> Self {
+        Error::TauriRuntimeError(error)
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
         Error::IoError(error)
@@ -52,11 +67,34 @@ impl From<sled::Error> for Error {
     }
 }
 
+// Transactions only ever abort with sled errors here
+impl From<sled::transaction::TransactionError> for Error {
+    fn from(error: sled::transaction::TransactionError) -> Self {
+        match error {
+            sled::transaction::TransactionError::Abort(error)
+            | sled::transaction::TransactionError::Storage(error) => Error::SledError(error),
+        }
+    }
+}
+
 impl From<serde_cbor::Error> for Error {
     fn from(error: serde_cbor::Error) -> Self {
         Error::SerdeCborError(error)
     }
 }
+
+impl From<ignore::Error> for Error {
+    fn from(error: ignore::Error) -> Self {
+        Error::IgnoreError(error)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(error: notify::Error) -> Self {
+        Error::NotifyError(error)
+    }
+}
+
 impl std::error::Error for Error {}
 
 impl Display for Error {
@@ -64,17 +102,23 @@ impl Display for Error {
         match self {
             Error::Generic(error) => write!(f, "{}", error),
             Error::TauriError(error) => write!(f, "{}", error),
+            Error::TauriRuntimeError(error) => write!(f, "{}", error),
             Error::IoError(error) => write!(f, "{}", error),
             Error::WalkDirError(error) => write!(f, "{}", error),
             Error::StringError(error) => write!(f, "{}", error),
             Error::SledError(error) => write!(f, "{}", error),
             Error::SerdeCborError(error) => write!(f, "{}", error),
+            Error::IgnoreError(error) => write!(f, "{}", error),
+            Error::NotifyError(error) => write!(f, "{}", error),
+            Error::CorruptRecord { tree, key, error } => {
+                write!(f, "Bad record {} in {}: {}", key, tree, error)
+            }
         }
     }
 }
 
-impl Into<tauri::InvokeError> for Error {
-    fn into(self) -> tauri::InvokeError {
-        tauri::InvokeError::from(Value::String(self.to_string()))
+impl From<Error> for tauri::InvokeError {
+    fn from(error: Error) -> Self {
+        tauri::InvokeError::from(Value::String(error.to_string()))
     }
 }
@@ -1,3 +1,5 @@
 pub mod commands;
 pub mod error;
-pub mod db;
\ No newline at end of file
+pub mod db;
+pub mod scans;
+pub mod watcher;
@@ -6,17 +6,76 @@
 mod commands;
 mod db;
 mod error;
+mod scans;
+mod watcher;
 
-use crate::commands::local_files::{get_file_diff, update_remote_state, update_local_state};
+use tauri::Manager;
+
+use crate::{
+    commands::{
+        chunks::{get_chunk_manifest, get_missing_chunks},
+        history::{
+            commit_project, file_history, list_trash, log, recover_trashed, restore_path,
+            revert_to_head, set_object_compression,
+        },
+        local_files::{
+            cancel_scan, explain_ignored, get_file_diff, get_scan_report, get_three_way_diff,
+            set_hash_mode, set_strong_hash, stream_file_diff, update_local_state,
+            update_remote_state, verify_project,
+        },
+        projects::{list_projects, register_project, relink_project, remove_project},
+        tags::{create_tag, diff_tags, export_tag_manifest, list_tags},
+    },
+    scans::Scans,
+    watcher::Watchers,
+};
 
 fn main() {
     tauri::Builder::default()
         .setup(|app| {
-            db::make_db(app.config());
+            let db = db::setup::make_db(app.config());
+            app.manage(db.clone());
+            app.manage(db::setup::make_object_store(&app.config()));
+            app.manage(db::setup::make_trash(&app.config()));
+
+            // The watchers wait on running scans, so this goes first
+            app.manage(Scans::default());
+            let watchers = Watchers::default();
+            watchers.watch_all(db, &app.handle())?;
+            app.manage(watchers);
             Ok(())
         })
-        .manage(db::get_db())
-        .invoke_handler(tauri::generate_handler![get_file_diff, update_local_state, update_remote_state])
+        .invoke_handler(tauri::generate_handler![
+            get_file_diff,
+            get_three_way_diff,
+            stream_file_diff,
+            update_local_state,
+            cancel_scan,
+            get_scan_report,
+            update_remote_state,
+            explain_ignored,
+            set_hash_mode,
+            set_strong_hash,
+            verify_project,
+            register_project,
+            relink_project,
+            list_projects,
+            remove_project,
+            get_chunk_manifest,
+            get_missing_chunks,
+            commit_project,
+            log,
+            file_history,
+            set_object_compression,
+            restore_path,
+            revert_to_head,
+            list_trash,
+            recover_trashed,
+            create_tag,
+            list_tags,
+            diff_tags,
+            export_tag_manifest
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,134 @@
+//! Keeps track of running scans, so `cancel_scan` can reach the one it's after, and so
+//! the watcher's refreshes wait for them instead of racing them.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use crate::{db::types::ProjectId, error::Result};
+
+pub const SCAN_PROGRESS_EVENT: &str = "scan-progress";
+
+/// The cancel flags of the scans that are running, one per project. Managed as Tauri state.
+#[derive(Default)]
+pub struct Scans {
+    running: Mutex<HashMap<ProjectId, Arc<AtomicBool>>>,
+    // Held by whatever is writing a project's local state: a scan, or a watcher refresh
+    busy: Mutex<HashMap<ProjectId, Arc<AsyncMutex<()>>>>,
+}
+
+impl Scans {
+    /// Note that `project_id` is being scanned, until the returned guard is dropped.
+    /// Only one scan of a project runs at a time, and it waits for a watcher refresh
+    /// that's already going.
+    pub async fn start(&self, project_id: ProjectId) -> Result<RunningScan<'_>> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        {
+            let mut running = self.lock()?;
+            if running.contains_key(&project_id) {
+                return Err(format!("Project {} is already being scanned", project_id).into());
+            }
+            running.insert(project_id, cancelled.clone());
+        }
+
+        // Built before waiting, so it's cleaned up if the wait is dropped
+        let mut scan = RunningScan {
+            scans: self,
+            project_id,
+            cancelled,
+            _busy: None,
+        };
+        scan._busy = Some(self.wait_idle(project_id).await?);
+        Ok(scan)
+    }
+
+    /// Wait until nothing else is writing `project_id`'s local state, and keep it that
+    /// way until the guard is dropped. Watcher batches queue up behind a running scan.
+    pub async fn wait_idle(&self, project_id: ProjectId) -> Result<OwnedMutexGuard<()>> {
+        let busy = self
+            .busy
+            .lock()
+            .map_err(|_| "Scan lock was poisoned".to_string())?
+            .entry(project_id)
+            .or_default()
+            .clone();
+
+        Ok(busy.lock_owned().await)
+    }
+
+    /// Ask the scan of `project_id` to stop. Returns whether there was one.
+    pub fn cancel(&self, project_id: ProjectId) -> Result<bool> {
+        Ok(match self.lock()?.get(&project_id) {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<ProjectId, Arc<AtomicBool>>>> {
+        self.running
+            .lock()
+            .map_err(|_| "Scan lock was poisoned".to_string().into())
+    }
+}
+
+pub struct RunningScan<'a> {
+    scans: &'a Scans,
+    project_id: ProjectId,
+    cancelled: Arc<AtomicBool>,
+    _busy: Option<OwnedMutexGuard<()>>,
+}
+
+impl RunningScan<'_> {
+    pub fn cancelled(&self) -> &AtomicBool {
+        &self.cancelled
+    }
+}
+
+impl Drop for RunningScan<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut running) = self.scans.lock() {
+            running.remove(&self.project_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_refreshes_wait_for_scans() {
+        let scans = Scans::default();
+        let project_id: ProjectId = 1;
+
+        let scan = scans.start(project_id).await.unwrap();
+        assert!(scans.start(project_id).await.is_err());
+        assert!(
+            timeout(Duration::from_millis(50), scans.wait_idle(project_id))
+                .await
+                .is_err()
+        );
+
+        drop(scan);
+        let refresh = scans.wait_idle(project_id).await.unwrap();
+        assert!(timeout(Duration::from_millis(50), scans.start(project_id))
+            .await
+            .is_err());
+
+        drop(refresh);
+        scans.start(project_id).await.unwrap();
+    }
+}
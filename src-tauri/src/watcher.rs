@@ -0,0 +1,145 @@
+//! Keeps local state current between scans by watching each project's folder.
+//!
+//! Events are debounced, then only the touched paths are refreshed and diffed against
+//! the remote. The result goes to the frontend as a `local-changes` event. While a scan
+//! of the project runs, batches wait for it to finish.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use tauri::{AppHandle, Manager};
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::{
+    db::{
+        compare::{compare_paths, TreeDiffBase},
+        project_path::ProjectPath,
+        projects::{get_project, list_projects},
+        refresh_state::refresh_paths,
+        store::{MetadataStore, Store},
+        types::{LocalChanges, Project, ProjectId, TreeNames},
+    },
+    error::Result,
+    scans::Scans,
+};
+
+pub const LOCAL_CHANGES_EVENT: &str = "local-changes";
+
+// CAD programs tend to write a file in several goes, wait for them to settle
+const DEBOUNCE_TIME: Duration = Duration::from_millis(500);
+
+/// The running watchers, one per registered project. Managed as Tauri state.
+#[derive(Default)]
+pub struct Watchers {
+    watchers: Mutex<HashMap<ProjectId, Debouncer<RecommendedWatcher>>>,
+}
+
+impl Watchers {
+    /// Watch every registered project. Ones that can't be watched are skipped.
+    pub fn watch_all(&self, db: &Store, app: &AppHandle) -> Result<()> {
+        for project in list_projects(db.as_ref())? {
+            if let Err(err) = self.watch(&project, db, app) {
+                println!("Not watching {}: {}", project.root.display(), err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start watching a project, replacing the watcher it already had
+    pub fn watch(&self, project: &Project, db: &Store, app: &AppHandle) -> Result<()> {
+        let project_id = project.id;
+        let root = project.root.clone();
+        let (sender, mut receiver) = unbounded_channel::<Vec<PathBuf>>();
+
+        let mut debouncer = new_debouncer(DEBOUNCE_TIME, move |result: DebounceEventResult| {
+            match result {
+                Ok(events) => {
+                    let _ = sender.send(events.into_iter().map(|event| event.path).collect());
+                }
+                Err(err) => println!("Watch error in {}: {}", root.display(), err),
+            }
+        })?;
+        debouncer
+            .watcher()
+            .watch(&project.root, RecursiveMode::Recursive)?;
+
+        // Batches are handled one at a time. This ends once the debouncer is dropped,
+        // since it owns the sender.
+        let db = db.clone();
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            while let Some(paths) = receiver.recv().await {
+                if let Err(err) = apply_changes(project_id, paths, db.as_ref(), &app).await {
+                    println!("Failed to apply changes to project {}: {}", project_id, err);
+                }
+            }
+        });
+
+        self.lock()?.insert(project_id, debouncer);
+        Ok(())
+    }
+
+    pub fn unwatch(&self, project_id: ProjectId) -> Result<()> {
+        self.lock()?.remove(&project_id);
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<ProjectId, Debouncer<RecommendedWatcher>>>> {
+        self.watchers
+            .lock()
+            .map_err(|_| "Watcher lock was poisoned".to_string().into())
+    }
+}
+
+/// Refresh the touched paths and tell the frontend how their diffs look now
+async fn apply_changes(
+    project_id: ProjectId,
+    paths: Vec<PathBuf>,
+    db: &dyn MetadataStore,
+    app: &AppHandle,
+) -> Result<()> {
+    let scans = app.state::<Scans>();
+    let _idle = scans.wait_idle(project_id).await?;
+    let project = get_project(db, project_id)?;
+
+    let paths: Vec<ProjectPath> = paths
+        .iter()
+        .filter_map(|path| ProjectPath::from_absolute(&project.root, path).ok())
+        .collect();
+
+    let changed = refresh_paths(&project, &paths, db).await?;
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let base = TreeDiffBase::open(
+        project.tree_name(TreeNames::HASH_HEAD_METDATA),
+        project.tree_name(TreeNames::LOCAL_TOMBSTONES),
+        db,
+    )?;
+    let diffs = compare_paths(
+        project.tree_name(TreeNames::HASH_LOCAL_METDATA),
+        project.tree_name(TreeNames::HASH_REMOTE_METDATA),
+        &changed,
+        &base,
+        db,
+    )?;
+
+    app.emit_all(
+        LOCAL_CHANGES_EVENT,
+        LocalChanges {
+            project_id,
+            changed,
+            diffs,
+        },
+    )?;
+
+    Ok(())
+}